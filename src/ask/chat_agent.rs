@@ -8,10 +8,11 @@ use async_openai::types::chat::{
 use async_openai::{Client, types::chat::CreateChatCompletionRequestArgs};
 use model2vec_rs::model::StaticModel;
 
-use crate::ask::system_prompt::{STDIN_SYSTEM_PROMPT, SYSTEM_PROMPT};
+use crate::ask::system_prompt::{SYSTEM_PROMPT, stdin_message, stdin_system_prompt};
 use crate::ask::tool_calling::{call_tool, print_tool_summary};
-use crate::ask::tools::{AgentTool, GrepTool, ReadTool, SearchTool};
-use crate::json_mode::AskOutput;
+use crate::ask::tools::{AgentTool, GrepTool, ReadTool, SearchTool, SimilarTool};
+use crate::json_mode::{AskOutput, SCHEMA_VERSION};
+use crate::search::records::StdinRecord;
 
 /// Run an agent loop with the search and read tools
 ///
@@ -36,6 +37,7 @@ pub async fn ask_agent(
 ) -> Result<AskOutput> {
     let max_iterations = max_iterations.unwrap_or(20);
     let mut result = AskOutput {
+        schema_version: SCHEMA_VERSION,
         query: user_message.to_string(),
         response: String::new(),
         files_searched: vec![],
@@ -45,6 +47,7 @@ pub async fn ask_agent(
     let tools: Vec<ChatCompletionTools> = vec![
         GrepTool::chat_definition()?,
         SearchTool::chat_definition()?,
+        SimilarTool::chat_definition()?,
         ReadTool::chat_definition()?,
     ];
 
@@ -139,7 +142,7 @@ pub async fn ask_agent(
 /// Run an agent with stdin content injected directly (no tools available)
 ///
 /// # Arguments
-/// * `stdin_content` - The content from stdin to include in the prompt
+/// * `records` - The documents read from stdin to include in the prompt
 /// * `user_message` - The user's query/message
 /// * `client` - OpenAI API client
 /// * `api_model` - The LLM model to use (e.g., "gpt-4o-mini")
@@ -147,26 +150,24 @@ pub async fn ask_agent(
 /// # Returns
 /// The response from the agent as a String
 pub async fn ask_agent_with_stdin(
-    stdin_content: &str,
+    records: &[StdinRecord],
     user_message: &str,
     client: &Client<OpenAIConfig>,
     api_model: &str,
 ) -> Result<AskOutput> {
     // Construct the user message with stdin content
-    let full_message = format!(
-        "<stdin_content>\n{}\n</stdin_content>\n\n{}",
-        stdin_content, user_message
-    );
+    let full_message = stdin_message(records, user_message);
     let mut result = AskOutput {
+        schema_version: SCHEMA_VERSION,
         query: user_message.to_string(),
         response: String::new(),
-        files_searched: vec!["<stdin>".to_string()],
+        files_searched: records.iter().map(|record| record.id.clone()).collect(),
     };
 
     // Initialize messages with system prompt and user message (no tools)
     let messages: Vec<ChatCompletionRequestMessage> = vec![
         ChatCompletionRequestSystemMessageArgs::default()
-            .content(STDIN_SYSTEM_PROMPT)
+            .content(stdin_system_prompt(records))
             .build()?
             .into(),
         ChatCompletionRequestUserMessage::from(full_message.as_str()).into(),
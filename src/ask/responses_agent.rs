@@ -8,10 +8,11 @@ use async_openai::types::responses::{
 };
 use model2vec_rs::model::StaticModel;
 
-use crate::ask::system_prompt::{STDIN_SYSTEM_PROMPT, SYSTEM_PROMPT};
+use crate::ask::system_prompt::{SYSTEM_PROMPT, stdin_message, stdin_system_prompt};
 use crate::ask::tool_calling::{call_tool, print_tool_summary};
-use crate::ask::tools::{AgentTool, GrepTool, ReadTool, SearchTool};
-use crate::json_mode::AskOutput;
+use crate::ask::tools::{AgentTool, GrepTool, ReadTool, SearchTool, SimilarTool};
+use crate::json_mode::{AskOutput, SCHEMA_VERSION};
+use crate::search::records::StdinRecord;
 
 /// Run an agent loop with the search and read tools using the Responses API
 ///
@@ -36,6 +37,7 @@ pub async fn ask_agent_responses(
 ) -> Result<AskOutput> {
     let max_iterations = max_iterations.unwrap_or(20);
     let mut result = AskOutput {
+        schema_version: SCHEMA_VERSION,
         query: user_message.to_string(),
         response: String::new(),
         files_searched: vec![],
@@ -45,6 +47,7 @@ pub async fn ask_agent_responses(
     let tools: Vec<Tool> = vec![
         GrepTool::responses_definition()?,
         SearchTool::responses_definition()?,
+        SimilarTool::responses_definition()?,
         ReadTool::responses_definition()?,
     ];
 
@@ -120,6 +123,7 @@ pub async fn ask_agent_responses(
                 .unwrap_or("<No response>".to_string());
 
             return Ok(AskOutput {
+                schema_version: SCHEMA_VERSION,
                 query: user_message.to_string(),
                 response: response_text,
                 files_searched: result.files_searched,
@@ -129,6 +133,7 @@ pub async fn ask_agent_responses(
 
     // If we reach here, max iterations was hit
     Ok(AskOutput {
+        schema_version: SCHEMA_VERSION,
         query: user_message.to_string(),
         response: format!(
             "Max iterations ({}) reached without final response",
@@ -168,24 +173,21 @@ fn output_item_to_item(output_item: &OutputItem) -> Result<Item> {
 /// Run an agent with stdin content injected directly using Responses API (no tools available)
 ///
 /// # Arguments
-/// * `stdin_content` - The content from stdin to include in the prompt
+/// * `records` - The documents read from stdin to include in the prompt
 /// * `user_message` - The user's query/message
 /// * `client` - OpenAI API client
 /// * `api_model` - The LLM model to use (e.g., "gpt-4.1")
 ///
 /// # Returns
-/// AskOutput containing the query, response, and "<stdin>" as the file searched
+/// AskOutput containing the query, response, and the ids of the stdin documents as the files searched
 pub async fn ask_agent_responses_with_stdin(
-    stdin_content: &str,
+    records: &[StdinRecord],
     user_message: &str,
     client: &Client<OpenAIConfig>,
     api_model: &str,
 ) -> Result<AskOutput> {
     // Construct the user message with stdin content
-    let full_message = format!(
-        "<stdin_content>\n{}\n</stdin_content>\n\n{}",
-        stdin_content, user_message
-    );
+    let full_message = stdin_message(records, user_message);
 
     // Initialize input items with user message (no tools)
     let input_items: Vec<InputItem> = vec![InputItem::EasyMessage(EasyInputMessage {
@@ -199,21 +201,22 @@ pub async fn ask_agent_responses_with_stdin(
         .max_output_tokens(4096u32)
         .model(api_model)
         .input(InputParam::Items(input_items))
-        .instructions(STDIN_SYSTEM_PROMPT)
+        .instructions(stdin_system_prompt(records))
         .store(false)
         .build()?;
 
     // Get response from LLM
     let response = client.responses().create(request).await?;
 
-    // Return AskOutput with stdin as the file searched
+    // Return AskOutput with the stdin documents as the files searched
     let response_text = response
         .output_text()
         .unwrap_or("<No response>".to_string());
 
     Ok(AskOutput {
+        schema_version: SCHEMA_VERSION,
         query: user_message.to_string(),
         response: response_text,
-        files_searched: vec!["<stdin>".to_string()],
+        files_searched: records.iter().map(|record| record.id.clone()).collect(),
     })
 }
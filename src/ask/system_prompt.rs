@@ -1,4 +1,6 @@
-pub const SYSTEM_PROMPT: &str = "You are a helpful search assistant with access to grep, search, and read tools for exploring corpus' of documents.
+use crate::search::records::{STDIN_NAME, StdinRecord};
+
+pub const SYSTEM_PROMPT: &str = "You are a helpful search assistant with access to grep, search, similar, and read tools for exploring corpus' of documents.
 
 TOOL SELECTION GUIDELINES:
 1. Use 'grep' when you know the exact string, function name, class name, or regex pattern to find
@@ -8,14 +10,16 @@ TOOL SELECTION GUIDELINES:
 2. Use 'search' for semantic/fuzzy keyword searches and conceptual queries
    - Example: Finding documentation related to \"authentication\" or \"database connection\"
    - Example: Discovering relevant sections when you don't know exact names
-3. Use 'read' to get the full context from specific file ranges after finding relevant locations
+3. Use 'similar' to find passages related to a chunk you already found (\"more like this\")
+   - Example: After finding one definition of a concept, locating other places that discuss it
+4. Use 'read' to get the full context from specific file ranges after finding relevant locations
 
 CITATION REQUIREMENTS:
 1. Use numbered citations [1], [2], [3] etc. throughout your response for ALL factual claims
 2. At the end of your response, include a '## References' section listing each citation
 3. Place citations immediately after the specific claim they support, not bundled together
 4. Each distinct source or set of sources gets its own reference number
-5. The chunks returned by search and read tools include file paths and line numbers - use these for your citations
+5. The chunks returned by search, similar and read tools include file paths and 1-based line numbers (start and end are inclusive, match is the best matching line) - use these for your citations
 
 REFERENCE FORMAT RULES:
 - Single location: [1] file_path:line_number
@@ -35,3 +39,51 @@ Graph Convolutional Networks are powerful for node classification [1]. The archi
 Remember: Every factual claim needs a citation with a specific file path and line number.";
 
 pub const STDIN_SYSTEM_PROMPT: &str = "You are a helpful assistant. The user has provided you with content via stdin, which will be included in their message. Please analyze and respond to their query based on this content.";
+
+pub const STDIN_DOCUMENTS_SYSTEM_PROMPT: &str = "You are a helpful assistant. The user has provided you with several documents via stdin, each in a <document> element with an id and optional metadata, which will be included in their message. Please analyze and respond to their query based on these documents, citing the id of every document a claim comes from, e.g. [ticket-12].";
+
+/// Whether stdin held a single plain text rather than separate documents
+fn is_plain_stdin(records: &[StdinRecord]) -> bool {
+    matches!(records, [record] if record.id == STDIN_NAME)
+}
+
+pub fn stdin_system_prompt(records: &[StdinRecord]) -> &'static str {
+    if is_plain_stdin(records) {
+        STDIN_SYSTEM_PROMPT
+    } else {
+        STDIN_DOCUMENTS_SYSTEM_PROMPT
+    }
+}
+
+/// The user message with the content read from stdin, each document tagged with its id
+pub fn stdin_message(records: &[StdinRecord], user_message: &str) -> String {
+    if is_plain_stdin(records) {
+        return format!(
+            "<stdin_content>\n{}\n</stdin_content>\n\n{}",
+            records[0].text, user_message
+        );
+    }
+
+    let documents: Vec<String> = records
+        .iter()
+        .map(|record| {
+            let metadata = if record.metadata.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    " metadata='{}'",
+                    serde_json::Value::Object(record.metadata.clone())
+                )
+            };
+            format!(
+                "<document id=\"{}\"{}>\n{}\n</document>",
+                record.id, metadata, record.text
+            )
+        })
+        .collect();
+    format!(
+        "<stdin_content>\n{}\n</stdin_content>\n\n{}",
+        documents.join("\n"),
+        user_message
+    )
+}
@@ -2,9 +2,12 @@ use anyhow::Result;
 use model2vec_rs::model::StaticModel;
 use serde_json::Value;
 
-use crate::ask::tools::{GrepTool, ReadTool, SearchTool};
+use crate::ask::tools::{GrepTool, ReadTool, SearchTool, SimilarTool};
 use crate::json_mode::AskOutput;
-use crate::search::SearchConfig;
+use crate::search::cutoff::Cutoff;
+use crate::search::filter::SearchFilter;
+use crate::search::text::DEFAULT_MAX_FILE_SIZE;
+use crate::search::{LineSpan, SearchConfig};
 
 /// Call a tool by name with the given arguments
 pub async fn call_tool(
@@ -74,13 +77,32 @@ pub async fn call_tool(
             let n_lines = config_json["n_lines"].as_u64().unwrap_or(5) as usize;
             let ignore_case = config_json["ignore_case"].as_bool().unwrap_or(false);
             let max_distance = config_json["max_distance"].as_f64();
+            let within = config_json["within"].as_f64();
             let top_k = config_json["top_k"].as_u64().unwrap_or(3) as usize;
+            let mmr_lambda = config_json["mmr_lambda"].as_f64();
+            let filter_regex = config_json["filter_regex"].as_str();
+            let globs = string_array(&config_json["globs"]);
+            let exclude = string_array(&config_json["exclude"]);
+            let types = string_array(&config_json["types"]);
+            // Let the model correct a bad pattern rather than ending the session
+            let filter =
+                match SearchFilter::new(filter_regex, &globs, &exclude, &types, ignore_case) {
+                    Ok(filter) => filter,
+                    Err(e) => return Ok(format!("Error: {}", e)),
+                };
 
             let config = SearchConfig {
                 n_lines,
                 ignore_case,
                 max_distance,
                 top_k,
+                mmr_lambda,
+                exclude_spans: vec![],
+                negative_embeddings: vec![],
+                max_file_size: Some(DEFAULT_MAX_FILE_SIZE),
+                filter,
+                cutoff: within.map(Cutoff::Within),
+                explain: false,
             };
 
             // Log the tool call with formatted parameters
@@ -90,12 +112,27 @@ pub async fn call_tool(
             println!("    n_lines: {}", n_lines);
             println!("    ignore_case: {}", ignore_case);
 
-            // Max distance and top_k are mutually exclusive
+            // Thresholds and top_k are mutually exclusive
             if let Some(md) = max_distance {
                 println!("    max_distance: {:?}", md);
-            } else {
+            }
+            if let Some(delta) = within {
+                println!("    within: {:?}", delta);
+            }
+            if max_distance.is_none() && within.is_none() {
                 println!("    top_k: {}", top_k);
             }
+            if let Some(lambda) = mmr_lambda {
+                println!("    mmr_lambda: {}", lambda);
+            }
+            if let Some(pattern) = filter_regex {
+                println!("    filter_regex: \"{}\"", pattern);
+            }
+            for (name, values) in [("globs", &globs), ("exclude", &exclude), ("types", &types)] {
+                if !values.is_empty() {
+                    println!("    {}: {:?}", name, values);
+                }
+            }
 
             SearchTool::search(
                 files,
@@ -107,6 +144,52 @@ pub async fn call_tool(
             )
             .await
         }
+        "similar" => {
+            let path = function_args["path"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Missing 'path' parameter"))?;
+            let start_line = function_args["start_line"]
+                .as_u64()
+                .ok_or_else(|| anyhow::anyhow!("Missing 'start_line' parameter"))?
+                as usize;
+            let end_line = function_args["end_line"]
+                .as_u64()
+                .ok_or_else(|| anyhow::anyhow!("Missing 'end_line' parameter"))?
+                as usize;
+            let n_lines = function_args["n_lines"].as_u64().unwrap_or(5) as usize;
+            let top_k = function_args["top_k"].as_u64().unwrap_or(3) as usize;
+
+            // Log the tool call with formatted parameters
+            println!("\n[Tool Call: similar]");
+            println!("  path: {}", path);
+            println!("  start_line: {}", start_line);
+            println!("  end_line: {}", end_line);
+            println!("  n_lines: {}", n_lines);
+            println!("  top_k: {}", top_k);
+
+            let config = SearchConfig {
+                n_lines,
+                top_k,
+                max_file_size: Some(DEFAULT_MAX_FILE_SIZE),
+                ..Default::default()
+            };
+            // Chunk line numbers are 1-based and inclusive
+            let span = LineSpan {
+                path: path.to_string(),
+                start: start_line.saturating_sub(1),
+                end: end_line,
+            };
+
+            SimilarTool::similar(
+                files,
+                span,
+                model,
+                config,
+                &mut cur_output.files_searched,
+                workspace_name,
+            )
+            .await
+        }
         "read" => {
             let path = function_args["path"]
                 .as_str()
@@ -137,6 +220,18 @@ pub async fn call_tool(
     }
 }
 
+/// Strings of a JSON array argument, empty when it is missing
+fn string_array(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// Print a summary of the tool response
 pub fn print_tool_summary(response: &str) {
     // Count the number of <chunk> tags
@@ -4,13 +4,16 @@ use async_openai::types::responses::{FunctionTool, Tool};
 use model2vec_rs::model::StaticModel;
 use serde_json::json;
 
-use crate::search::{SearchConfig, SearchResult, search_files};
+use crate::search::text::SkippedFile;
+use crate::search::{
+    LineSpan, SearchConfig, SearchResult, embed_span, parsed_source_document, search_files,
+};
 
 #[cfg(feature = "workspace")]
 use crate::workspace::{Workspace, store::RankedLine};
 
 #[cfg(feature = "workspace")]
-use crate::search::search_with_workspace;
+use crate::search::{ranked_line_to_search_result, search_with_workspace};
 
 /// Trait for tools that can work with both Chat Completions and Responses API
 pub trait AgentTool {
@@ -31,17 +34,29 @@ fn create_function_tool(name: &str, description: &str, parameters: serde_json::V
     })
 }
 
+/// Format results as chunks, numbered like the JSON output: 1-based, inclusive line ranges
 fn format_search_results(results: &[SearchResult]) -> String {
     let mut response = String::new();
 
     for search_result in results {
         let filename = search_result.filename.to_string();
         let distance = search_result.distance;
-        let start = search_result.start;
+        let score = search_result.score();
+        let start = search_result.start + 1;
         let end = search_result.end;
+        let match_line = search_result.match_line + 1;
+        let source = parsed_source_document(&search_result.filename)
+            .map(|source| format!(" source={source}"))
+            .unwrap_or_default();
+        // The file changed after it was indexed, so the lines are as they were then
+        let stale = if search_result.stale {
+            " stale=true"
+        } else {
+            ""
+        };
 
         response.push_str(&format!(
-            "<chunk file={filename} start={start} end={end} distance={distance}>\n"
+            "<chunk file={filename}{source} start={start} end={end} match={match_line} score={score} distance={distance}{stale}>\n"
         ));
 
         for line in search_result.lines.iter() {
@@ -56,41 +71,13 @@ fn format_search_results(results: &[SearchResult]) -> String {
 
 #[cfg(feature = "workspace")]
 fn format_ranked_lines(ranked_lines: &[RankedLine], n_lines: usize) -> String {
-    let mut response = String::new();
-
-    for ranked_line in ranked_lines {
-        let filename = &ranked_line.path;
-        let distance = ranked_line.distance;
-        // ranked_line.line_number is 0-based from database
-        let match_line_number = ranked_line.line_number as usize;
-
-        // Calculate context range (working with 0-based indices)
-        let start = match_line_number.saturating_sub(n_lines);
-        let end = match_line_number + n_lines + 1;
-
-        response.push_str(&format!(
-            "<chunk file={filename} start={start} end={end} distance={distance}>\n"
-        ));
-
-        // For workspace results, we need to read the file to get context lines
-        // This is acceptable since we're only doing this for the final results
-        if let Ok(content) = std::fs::read_to_string(filename) {
-            let lines: Vec<&str> = content.lines().collect();
-            let actual_start = start;
-            let actual_end = end.min(lines.len());
-
-            for line in lines[actual_start..actual_end].iter() {
-                response.push_str(&format!("{line}\n"));
-            }
-        } else {
-            // Fallback: indicate that the file couldn't be read
-            response.push_str("[Error: Could not read file content]");
-        }
-
-        response.push_str("</chunk>\n");
-    }
-
-    response
+    // Context lines are read from the files, or taken from the workspace when
+    // the files changed since they were indexed
+    let search_results: Vec<SearchResult> = ranked_lines
+        .iter()
+        .map(|ranked_line| ranked_line_to_search_result(ranked_line, n_lines))
+        .collect();
+    format_search_results(&search_results)
 }
 
 pub struct SearchTool;
@@ -100,7 +87,7 @@ impl AgentTool for SearchTool {
         Ok(ChatCompletionTools::Function(ChatCompletionTool {
             function: FunctionObjectArgs::default()
                 .name("search")
-                .description("Search through files using semantic keyword search. Returns relevant document chunks with their file paths and line numbers. If top-k is not specified, returns all relevant results within the max distance threshold.")
+                .description("Search through files using semantic keyword search. Returns relevant document chunks with their file paths and line numbers. If max_distance or within is set, returns every result under that threshold instead of the top-k.")
                 .parameters(json!({
                     "$defs": {
                         "Config": {
@@ -118,13 +105,39 @@ impl AgentTool for SearchTool {
                                 },
                                 "max_distance": {
                                     "type": "number",
-                                    "description": "Maximum semantic distance for matches (lower is more similar)",
-                                    "default": 0.5
+                                    "description": "Maximum semantic distance for matches (lower is more similar). Good values vary between queries, so prefer 'within'"
+                                },
+                                "within": {
+                                    "type": "number",
+                                    "description": "Return all matches within this distance of the best match, e.g. 0.1. Adapts to how well the query matches at all"
                                 },
                                 "top_k": {
                                     "type": "integer",
                                     "description": "Number of top results to return",
                                     "default": 3
+                                },
+                                "mmr_lambda": {
+                                    "type": "number",
+                                    "description": "Re-rank results for diversity (0.0 = most diverse, 1.0 = pure relevance). Use around 0.5 when results repeat the same text. Omit to disable."
+                                },
+                                "filter_regex": {
+                                    "type": "string",
+                                    "description": "Only return lines matching this regular expression, e.g. 'timeout' to find lines about retries that mention timeouts"
+                                },
+                                "globs": {
+                                    "type": "array",
+                                    "items": { "type": "string" },
+                                    "description": "Only search files matching one of these globs, e.g. 'docs/**/*.md'. A glob without '/' matches file names in any directory"
+                                },
+                                "exclude": {
+                                    "type": "array",
+                                    "items": { "type": "string" },
+                                    "description": "Skip files matching any of these globs"
+                                },
+                                "types": {
+                                    "type": "array",
+                                    "items": { "type": "string" },
+                                    "description": "Only search files of these types, e.g. 'md', 'py', 'rust', 'txt'"
                                 }
                             },
                             "required": [],
@@ -168,13 +181,39 @@ impl AgentTool for SearchTool {
                         },
                         "max_distance": {
                             "type": "number",
-                            "description": "Maximum semantic distance for matches (lower is more similar)",
-                            "default": 0.5
+                            "description": "Maximum semantic distance for matches (lower is more similar). Good values vary between queries, so prefer 'within'"
+                        },
+                        "within": {
+                            "type": "number",
+                            "description": "Return all matches within this distance of the best match, e.g. 0.1. Adapts to how well the query matches at all"
                         },
                         "top_k": {
                             "type": "integer",
                             "description": "Number of top results to return",
                             "default": 3
+                        },
+                        "mmr_lambda": {
+                            "type": "number",
+                            "description": "Re-rank results for diversity (0.0 = most diverse, 1.0 = pure relevance). Use around 0.5 when results repeat the same text. Omit to disable."
+                        },
+                        "filter_regex": {
+                            "type": "string",
+                            "description": "Only return lines matching this regular expression, e.g. 'timeout' to find lines about retries that mention timeouts"
+                        },
+                        "globs": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Only search files matching one of these globs, e.g. 'docs/**/*.md'. A glob without '/' matches file names in any directory"
+                        },
+                        "exclude": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Skip files matching any of these globs"
+                        },
+                        "types": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Only search files of these types, e.g. 'md', 'py', 'rust', 'txt'"
                         }
                     },
                     "required": [],
@@ -198,7 +237,7 @@ impl AgentTool for SearchTool {
 
         Ok(create_function_tool(
             "search",
-            "Search through files using semantic keyword search. Returns relevant document chunks with their file paths and line numbers. If top-k is not specified, returns all relevant results within the max distance threshold.",
+            "Search through files using semantic keyword search. Returns relevant document chunks with their file paths and line numbers. If max_distance or within is set, returns every result under that threshold instead of the top-k.",
             parameters,
         ))
     }
@@ -219,43 +258,190 @@ impl SearchTool {
             query.to_string()
         };
 
-        if files.is_empty() {
-            return Err(anyhow::anyhow!(
-                "Error: No input provided. Either specify files as arguments or pipe input to stdin."
-            ));
-        }
-
-        // Handle file input with optional workspace integration
-        #[cfg(feature = "workspace")]
-        if Workspace::active(workspace_name).is_ok() {
-            // Workspace mode: use persisted line embeddings for speed
-            let ranked_lines =
-                search_with_workspace(files, &query, model, &config, workspace_name).await?;
-
-            // Track files that were searched (have results)
-            for ranked_line in &ranked_lines {
-                if !files_searched.contains(&ranked_line.path) {
-                    files_searched.push(ranked_line.path.clone());
-                }
-            }
+        let query_embedding = model.encode_single(&query);
+
+        run_search(
+            files,
+            &query_embedding,
+            model,
+            config,
+            files_searched,
+            workspace_name,
+        )
+        .await
+    }
+}
 
-            // Convert results to SearchResult format and format
-            let formatted = format_ranked_lines(&ranked_lines, config.n_lines);
-            return Ok(formatted);
-        }
+/// Run a semantic search with a precomputed query vector and format the results as chunks
+async fn run_search(
+    files: &[String],
+    query_embedding: &[f32],
+    model: &StaticModel,
+    config: SearchConfig,
+    files_searched: &mut Vec<String>,
+    workspace_name: Option<&str>,
+) -> Result<String> {
+    if files.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Error: No input provided. Either specify files as arguments or pipe input to stdin."
+        ));
+    }
 
-        let search_results = search_files(files, &query, model, &config)?;
+    // Handle file input with optional workspace integration
+    #[cfg(feature = "workspace")]
+    if Workspace::active(workspace_name).is_ok() {
+        // Workspace mode: use persisted line embeddings for speed
+        let outcome =
+            search_with_workspace(files, query_embedding, model, &config, workspace_name).await?;
 
         // Track files that were searched (have results)
-        for result in &search_results {
-            if !files_searched.contains(&result.filename) {
-                files_searched.push(result.filename.clone());
+        for ranked_line in &outcome.results {
+            if !files_searched.contains(&ranked_line.path) {
+                files_searched.push(ranked_line.path.clone());
             }
         }
 
-        let formatted = format_search_results(&search_results);
+        // Convert results to SearchResult format and format
+        let mut formatted = format_ranked_lines(&outcome.results, config.n_lines);
+        formatted.push_str(&format_skipped_files(&outcome.skipped));
+        return Ok(formatted);
+    }
+
+    let outcome = search_files(files, query_embedding, model, &config)?;
+
+    // Track files that were searched (have results)
+    for result in &outcome.results {
+        if !files_searched.contains(&result.filename) {
+            files_searched.push(result.filename.clone());
+        }
+    }
+
+    let mut formatted = format_search_results(&outcome.results);
+    formatted.push_str(&format_skipped_files(&outcome.skipped));
+
+    Ok(formatted)
+}
+
+/// Note which files could not be searched, so the agent doesn't assume they had no matches
+fn format_skipped_files(skipped: &[SkippedFile]) -> String {
+    if skipped.is_empty() {
+        return String::new();
+    }
+    let mut note = format!(
+        "\nSkipped {} file(s) that could not be searched:\n",
+        skipped.len()
+    );
+    for skipped_file in skipped {
+        note.push_str(&format!(
+            "  {}: {}\n",
+            skipped_file.path, skipped_file.reason
+        ));
+    }
+    note
+}
+
+pub struct SimilarTool;
+
+impl AgentTool for SimilarTool {
+    fn chat_definition() -> Result<ChatCompletionTools> {
+        Ok(ChatCompletionTools::Function(ChatCompletionTool {
+            function: FunctionObjectArgs::default()
+                .name("similar")
+                .description("Find passages similar to a given range of lines (\"more like this\"). The range itself is excluded from the results. Use this after finding a relevant chunk to discover related passages elsewhere in the files.")
+                .parameters(json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "The file containing the example passage"
+                        },
+                        "start_line": {
+                            "type": "integer",
+                            "description": "The first line of the example passage (1-based, as in chunk start)"
+                        },
+                        "end_line": {
+                            "type": "integer",
+                            "description": "The last line of the example passage (1-based, inclusive, as in chunk end)"
+                        },
+                        "n_lines": {
+                            "type": "integer",
+                            "description": "Number of context lines to include before and after each match",
+                            "default": 5
+                        },
+                        "top_k": {
+                            "type": "integer",
+                            "description": "Number of top results to return",
+                            "default": 3
+                        }
+                    },
+                    "required": ["path", "start_line", "end_line"],
+                    "additionalProperties": false
+                }))
+                .strict(false)
+                .build()?,
+        }))
+    }
+
+    fn responses_definition() -> Result<Tool> {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "The file containing the example passage"
+                },
+                "start_line": {
+                    "type": "integer",
+                    "description": "The first line of the example passage (1-based, as in chunk start)"
+                },
+                "end_line": {
+                    "type": "integer",
+                    "description": "The last line of the example passage (1-based, inclusive, as in chunk end)"
+                },
+                "n_lines": {
+                    "type": "integer",
+                    "description": "Number of context lines to include before and after each match",
+                    "default": 5
+                },
+                "top_k": {
+                    "type": "integer",
+                    "description": "Number of top results to return",
+                    "default": 3
+                }
+            },
+            "required": ["path", "start_line", "end_line"],
+            "additionalProperties": false
+        });
+
+        Ok(create_function_tool(
+            "similar",
+            "Find passages similar to a given range of lines (\"more like this\"). The range itself is excluded from the results. Use this after finding a relevant chunk to discover related passages elsewhere in the files.",
+            parameters,
+        ))
+    }
+}
 
-        Ok(formatted)
+impl SimilarTool {
+    pub async fn similar(
+        files: &[String],
+        span: LineSpan,
+        model: &StaticModel,
+        mut config: SearchConfig,
+        files_searched: &mut Vec<String>,
+        workspace_name: Option<&str>,
+    ) -> Result<String> {
+        let query_embedding = embed_span(&span, model, config.ignore_case)?;
+        config.exclude_spans.push(span);
+
+        run_search(
+            files,
+            &query_embedding,
+            model,
+            config,
+            files_searched,
+            workspace_name,
+        )
+        .await
     }
 }
 
@@ -276,11 +462,11 @@ impl AgentTool for ReadTool {
                         },
                         "start_line": {
                             "type": "integer",
-                            "description": "The starting line number (0-based)"
+                            "description": "The first line to read (1-based)"
                         },
                         "end_line": {
                             "type": "integer",
-                            "description": "The ending line number (exclusive, 0-based)"
+                            "description": "The last line to read (1-based, inclusive)"
                         }
                     },
                     "required": ["path", "start_line", "end_line"],
@@ -301,11 +487,11 @@ impl AgentTool for ReadTool {
                 },
                 "start_line": {
                     "type": "integer",
-                    "description": "The starting line number (0-based)"
+                    "description": "The first line to read (1-based)"
                 },
                 "end_line": {
                     "type": "integer",
-                    "description": "The ending line number (exclusive, 0-based)"
+                    "description": "The last line to read (1-based, inclusive)"
                 }
             },
             "required": ["path", "start_line", "end_line"],
@@ -321,17 +507,19 @@ impl AgentTool for ReadTool {
 }
 
 impl ReadTool {
+    /// Read lines `start_line` to `end_line` (1-based, inclusive)
     pub async fn read(path: &str, start_line: usize, end_line: usize) -> Result<String> {
         let content = std::fs::read_to_string(path)?;
         let lines: Vec<&str> = content.lines().collect();
         let actual_end = end_line.min(lines.len());
-        let selected_lines = &lines[start_line..actual_end];
+        let actual_start = start_line.max(1).min(actual_end + 1);
+        let selected_lines = &lines[actual_start - 1..actual_end];
 
         // Build the response with the `<chunk>` tags
         let mut response = String::new();
         response.push_str(&format!(
             "<chunk file={} start={} end={}>\n",
-            path, start_line, actual_end
+            path, actual_start, actual_end
         ));
         response.push_str(&selected_lines.join("\n"));
         response.push_str("</chunk>\n");
@@ -537,7 +725,9 @@ impl GrepTool {
 
                 response.push_str(&format!(
                     "<chunk file={} start={} end={}>\n",
-                    file_path, start, end
+                    file_path,
+                    start + 1,
+                    end
                 ));
 
                 for line in &lines[start..end] {
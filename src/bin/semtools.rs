@@ -1,8 +1,22 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use semtools::cmds::ask::ask_cmd;
 use semtools::cmds::parse::parse_cmd;
-use semtools::cmds::search::search_cmd;
-use semtools::cmds::workspace::{workspace_prune_cmd, workspace_status_cmd, workspace_use_cmd};
+use semtools::cmds::search::{ColorChoice, OutputFormat, batch_search_cmd, follow_cmd, search_cmd};
+use semtools::cmds::workspace::{
+    workspace_configure_cmd, workspace_copy_cmd, workspace_delete_cmd, workspace_index_cmd,
+    workspace_list_cmd, workspace_prune_cmd, workspace_rename_cmd, workspace_status_cmd,
+    workspace_use_cmd,
+};
+use semtools::search::cutoff::Cutoff;
+use semtools::search::filter::SearchFilter;
+use semtools::search::query::{
+    CompositeQuery, DEFAULT_NEGATIVE_WEIGHT, NegativeMode, WeightedQuery, parse_batch_queries,
+};
+use semtools::search::records::StdinFormat;
+use semtools::search::text::parse_file_size;
+use semtools::search::walk::WalkOptions;
+use semtools::search::{DocumentAggregation, LineSpan, SearchConfig};
+use std::ffi::OsString;
 
 #[derive(Parser, Debug)]
 struct SemtoolsArgs {
@@ -15,15 +29,97 @@ enum WorkspaceCommands {
     /// Use or create a workspace (prints export command to run)
     Use { name: String },
     /// Show active workspace and basic stats
+    #[command(alias = "info")]
     Status {
         #[clap(default_value = None)]
         name: Option<String>,
     },
+    /// List workspaces with their document and line counts and disk usage
+    #[command(alias = "ls")]
+    List,
+    /// Delete a workspace and everything indexed in it
+    Delete {
+        name: String,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Give a workspace a new name
+    Rename { from: String, to: String },
+    /// Copy a workspace, with its settings and embeddings, to a new one
+    #[command(alias = "fork")]
+    Copy { from: String, to: String },
     /// Remove stale or missing files from store
     Prune {
         #[clap(default_value = None)]
         name: Option<String>,
     },
+    /// Change how line embeddings are stored, indexed and searched
+    ///
+    /// Settings that aren't given keep their value
+    Configure {
+        /// Compress vectors for searching: 'none', 'scalar' (4x smaller), 'binary' (32x) or 'product' (16x)
+        #[arg(long, value_name = "KIND")]
+        quantization: Option<String>,
+
+        /// Keep full vectors on 'disk' (memory-mapped) or in 'memory'
+        #[arg(long, value_name = "WHERE")]
+        storage: Option<String>,
+
+        /// Links per vector in the HNSW graph (default 16, at least 2)
+        #[arg(long = "hnsw-m", value_name = "N")]
+        hnsw_m: Option<usize>,
+
+        /// Candidates considered while building the HNSW graph (default 100, at least 4)
+        #[arg(long = "hnsw-ef-construct", value_name = "N")]
+        hnsw_ef_construct: Option<usize>,
+
+        /// Candidates considered while searching the HNSW graph, 0 for Qdrant's default
+        #[arg(long = "hnsw-ef", value_name = "N")]
+        hnsw_ef: Option<usize>,
+
+        /// With quantization, fetch this many candidates per result and rescore them with the full vectors (default 3)
+        #[arg(long, value_name = "FACTOR")]
+        oversample: Option<usize>,
+
+        /// Use a specific workspace
+        #[arg(short, long, default_value = None)]
+        workspace: Option<String>,
+    },
+    /// Embed every file under directories into the workspace ahead of searching
+    Index {
+        /// Directories (or files) to walk. Defaults to the ones indexed before
+        roots: Vec<String>,
+
+        /// Only index files matching this glob (repeatable), relative to each directory, e.g. 'docs/**/*.md'
+        #[arg(short = 'g', long = "glob", value_name = "GLOB")]
+        glob: Vec<String>,
+
+        /// Skip files matching this glob (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Only index files of this type (repeatable), e.g. md, py, rust or txt
+        #[arg(short = 't', long = "type", value_name = "TYPE")]
+        file_type: Vec<String>,
+
+        /// Skip files larger than this, e.g. 512K, 10M or 1G. Binary files are always skipped
+        #[arg(long = "max-filesize", value_name = "SIZE", default_value = "100M")]
+        max_filesize: String,
+
+        /// Don't respect .gitignore, .ignore and .semtoolsignore files
+        #[arg(long)]
+        no_ignore: bool,
+
+        /// Include hidden files and directories
+        #[arg(long)]
+        hidden: bool,
+
+        /// Use a specific workspace
+        #[arg(short, long, default_value = None)]
+        workspace: Option<String>,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -50,11 +146,17 @@ enum Commands {
     #[cfg(feature = "search")]
     /// A CLI tool for fast semantic keyword search
     Search {
-        /// Query to search for (positional argument)
-        query: String,
+        /// Query to search for (positional argument). Not given with --like or --queries
+        #[arg(
+            required_unless_present_any = ["like", "queries"],
+            conflicts_with_all = ["like", "queries"]
+        )]
+        query: Option<String>,
 
-        /// Files to search (positional arguments, optional if using stdin)
-        #[arg(help = "Files to search, optional if using stdin")]
+        /// Files to search (positional arguments, optional if using stdin or a workspace)
+        #[arg(
+            help = "Files to search, optional if using stdin. With a workspace, defaults to every indexed file, and a directory stands for the indexed files under it"
+        )]
         files: Vec<String>,
 
         /// How many lines before/after to return as context
@@ -69,17 +171,124 @@ enum Commands {
         #[arg(short = 'm', long = "max-distance", alias = "threshold")]
         max_distance: Option<f64>,
 
+        /// Return all results within this distance of the best result, e.g. 0.1
+        #[arg(long, value_name = "DELTA", conflicts_with_all = ["percentile", "auto_cutoff"])]
+        within: Option<f64>,
+
+        /// Return all results scoring at or above this percentile of everything searched, e.g. 99 for the best 1%
+        #[arg(long, value_name = "P", conflicts_with = "auto_cutoff")]
+        percentile: Option<f64>,
+
+        /// Work out a cutoff from the scores: 'elbow' (before the largest jump in distance) or 'zscore'
+        #[arg(long = "auto-cutoff", value_name = "METHOD")]
+        auto_cutoff: Option<String>,
+
+        /// Standard deviations closer than the mean distance a result must be with `--auto-cutoff zscore`
+        #[arg(long = "z-score", value_name = "Z", default_value_t = 2.0)]
+        z_score: f64,
+
+        /// Print the distribution of distances and suggested thresholds on stderr
+        #[arg(long, conflicts_with = "queries")]
+        explain: bool,
+
         /// Perform case-insensitive search (default is false)
         #[arg(short, long, default_value_t = false)]
         ignore_case: bool,
 
-        /// Output results in JSON format
-        #[clap(short, long)]
+        /// Re-rank results for diversity with maximal marginal relevance (0.0 = most diverse, 1.0 = pure relevance)
+        #[arg(long = "mmr-lambda", alias = "diversity")]
+        mmr_lambda: Option<f64>,
+
+        /// Skip files larger than this, e.g. 512K, 10M or 1G. Binary files are always skipped
+        #[arg(long = "max-filesize", value_name = "SIZE", default_value = "100M")]
+        max_filesize: String,
+
+        /// Only return lines matching this regular expression (case-insensitive with -i)
+        #[arg(long = "filter-regex", value_name = "REGEX")]
+        filter_regex: Option<String>,
+
+        /// Only search files matching this glob (repeatable), e.g. 'docs/**/*.md'. A glob without '/' matches file names in any directory
+        #[arg(short = 'g', long = "glob", value_name = "GLOB")]
+        glob: Vec<String>,
+
+        /// Skip files matching this glob (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Only search files of this type (repeatable), e.g. md, py, rust or txt
+        #[arg(short = 't', long = "type", value_name = "TYPE")]
+        file_type: Vec<String>,
+
+        /// How stdin is split into documents: 'text' (one document), 'jsonl' (one {"id", "text", ...} record per line) or 'nul' (NUL-separated)
+        #[arg(long = "stdin-format", value_name = "FORMAT", default_value = "text")]
+        stdin_format: String,
+
+        /// Only search stdin records whose metadata has this value (repeatable), e.g. --where team=auth
+        #[arg(long = "where", value_name = "KEY=VALUE")]
+        where_metadata: Vec<String>,
+
+        /// Output results in JSON format (same as --format json)
+        #[clap(short, long, conflicts_with_all = ["format", "vimgrep"])]
         json: bool,
 
+        /// Output format: 'text', 'json', 'jsonl' (one result per line), 'vimgrep' or 'quickfix'
+        #[arg(long, default_value = "text")]
+        format: String,
+
+        /// Print one `file:line:column:text` line per result (same as --format vimgrep)
+        #[arg(long, conflicts_with = "format")]
+        vimgrep: bool,
+
+        /// When to colour text output: 'always', 'never' or 'auto'
+        #[arg(long, value_name = "WHEN", default_value = "auto")]
+        color: String,
+
         /// Use a specific workspace
         #[arg(short, long, default_value = None)]
         workspace: Option<String>,
+
+        /// Find passages similar to a file range instead of a query, e.g. notes.md:40-55 (1-based, inclusive)
+        #[arg(long, value_name = "PATH:START-END")]
+        like: Option<String>,
+
+        /// Additional query to combine with the main one (repeatable). Append ^WEIGHT to weight any query, e.g. "login^2"
+        #[arg(long = "and", value_name = "QUERY", conflicts_with = "like")]
+        and: Vec<String>,
+
+        /// Query to steer away from (repeatable), e.g. --not oauth. Defaults to weight 0.5, override with ^WEIGHT
+        #[arg(long = "not", value_name = "QUERY", conflicts_with = "like")]
+        not: Vec<String>,
+
+        /// How --not queries are applied: 'subtract' them from the query vector, or 'filter' out results closer to them than to the query
+        #[arg(long, default_value = "subtract")]
+        negative_mode: String,
+
+        /// Run every query in a file (one per line, or JSON Lines with "id", "query" and per-query options), printing one JSON object per query
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["like", "and", "not"])]
+        queries: Option<String>,
+
+        /// Keep reading stdin and print lines within --max-distance of the query (or of every query in --queries) as they arrive, like a semantic `tail -f`
+        #[arg(
+            long,
+            conflicts_with_all = ["like", "explain", "rank_documents", "within", "percentile", "auto_cutoff", "json", "stdin_format"]
+        )]
+        follow: bool,
+
+        /// Rank whole files instead of lines, returning each file's best snippet
+        #[arg(
+            long = "rank-documents",
+            alias = "files-with-matches",
+            default_value_t = false
+        )]
+        rank_documents: bool,
+
+        /// How line scores are combined per file with --rank-documents: 'max', 'mean' or 'embedding'
+        #[arg(long, default_value = "max")]
+        aggregate: String,
+
+        /// Number of best lines averaged per file with `--aggregate mean`
+        #[arg(long, default_value_t = 3)]
+        aggregate_top_n: usize,
     },
     #[cfg(feature = "ask")]
     /// A CLI tool for document-based question-answering
@@ -87,8 +296,10 @@ enum Commands {
         /// Query to prompt the agent with
         query: String,
 
-        /// Files to search (positional arguments, optional if using stdin)
-        #[arg(help = "Files to search, optional if using stdin")]
+        /// Files to search (positional arguments, optional if using stdin or a workspace)
+        #[arg(
+            help = "Files to search, optional if using stdin. With a workspace, defaults to every indexed file, and a directory stands for the indexed files under it"
+        )]
         files: Vec<String>,
 
         /// Path to the config file. Defaults to ~/.semtools_config.json
@@ -118,6 +329,10 @@ enum Commands {
         /// Use a specific workspace
         #[arg(short, long, default_value = None)]
         workspace: Option<String>,
+
+        /// How stdin is split into documents: 'text' (one document), 'jsonl' (one {"id", "text", ...} record per line) or 'nul' (NUL-separated)
+        #[arg(long = "stdin-format", value_name = "FORMAT", default_value = "text")]
+        stdin_format: String,
     },
     #[cfg(feature = "workspace")]
     /// Manage semtools workspaces
@@ -131,9 +346,36 @@ enum Commands {
     },
 }
 
+/// Parse the command line. `search --like` and `search --queries` take no query,
+/// so there every positional argument is a file: the query is only accepted
+/// by name, and conflicts with them, rather than taking the first file.
+fn parse_args() -> SemtoolsArgs {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let searches_without_query = args.get(1).is_some_and(|cmd| cmd == "search")
+        && args
+            .iter()
+            .skip(2)
+            .take_while(|arg| *arg != "--")
+            .any(|arg| {
+                let arg = arg.to_string_lossy();
+                ["--like", "--queries"]
+                    .iter()
+                    .any(|flag| arg == *flag || arg.starts_with(&format!("{flag}=")))
+            });
+
+    let mut command = SemtoolsArgs::command();
+    if searches_without_query {
+        command = command.mut_subcommand("search", |search| {
+            search.mut_arg("query", |query| query.long("query").hide(true))
+        });
+    }
+    let matches = command.get_matches_from(args);
+    SemtoolsArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = SemtoolsArgs::parse();
+    let args = parse_args();
     match args.cmd {
         Commands::Ask {
             query,
@@ -145,6 +387,7 @@ async fn main() -> anyhow::Result<()> {
             api_mode,
             json,
             workspace,
+            stdin_format,
         } => {
             ask_cmd(
                 query,
@@ -156,6 +399,7 @@ async fn main() -> anyhow::Result<()> {
                 api_mode,
                 json,
                 workspace.as_deref(),
+                StdinFormat::parse(&stdin_format)?,
             )
             .await?;
         }
@@ -173,19 +417,162 @@ async fn main() -> anyhow::Result<()> {
             n_lines,
             top_k,
             max_distance,
+            within,
+            percentile,
+            auto_cutoff,
+            z_score,
+            explain,
             ignore_case,
+            mmr_lambda,
+            max_filesize,
+            filter_regex,
+            glob,
+            exclude,
+            file_type,
+            stdin_format,
+            where_metadata,
             json,
+            format,
+            vimgrep,
+            color,
             workspace,
+            rank_documents,
+            aggregate,
+            aggregate_top_n,
+            like,
+            and,
+            not,
+            negative_mode,
+            queries,
+            follow,
         } => {
-            search_cmd(
-                query,
-                files,
+            let cutoff = match (within, percentile, auto_cutoff) {
+                (Some(delta), _, _) => Some(Cutoff::Within(delta)),
+                (_, Some(percentile), _) => Some(Cutoff::percentile(percentile)?),
+                (_, _, Some(method)) => Some(Cutoff::parse(&method, z_score)?),
+                (None, None, None) => None,
+            };
+            let config = SearchConfig {
                 n_lines,
                 top_k,
                 max_distance,
                 ignore_case,
-                json,
+                mmr_lambda,
+                exclude_spans: vec![],
+                negative_embeddings: vec![],
+                max_file_size: Some(parse_file_size(&max_filesize)?),
+                filter: SearchFilter::new(
+                    filter_regex.as_deref(),
+                    &glob,
+                    &exclude,
+                    &file_type,
+                    ignore_case,
+                )?
+                .with_metadata(&where_metadata)?,
+                cutoff,
+                explain,
+            };
+            let rank_by_document = if rank_documents {
+                Some(DocumentAggregation::parse(&aggregate, aggregate_top_n)?)
+            } else {
+                None
+            };
+            let stdin_format = StdinFormat::parse(&stdin_format)?;
+            let format = if json {
+                OutputFormat::Json
+            } else if vimgrep {
+                OutputFormat::Vimgrep
+            } else {
+                OutputFormat::parse(&format)?
+            };
+            if follow {
+                let negative_mode = NegativeMode::parse(&negative_mode)?;
+                let standing_queries = match &queries {
+                    Some(queries_path) => {
+                        parse_batch_queries(&std::fs::read_to_string(queries_path)?)?
+                            .into_iter()
+                            .map(|batch_query| {
+                                let composite = batch_query.composite(negative_mode)?;
+                                Ok((
+                                    batch_query.id.clone(),
+                                    composite,
+                                    batch_query.config(&config),
+                                ))
+                            })
+                            .collect::<anyhow::Result<Vec<_>>>()?
+                    }
+                    None => vec![(
+                        None,
+                        CompositeQuery {
+                            positive: query
+                                .iter()
+                                .chain(&and)
+                                .map(|part| WeightedQuery::parse(part, 1.0))
+                                .collect(),
+                            negative: not
+                                .iter()
+                                .map(|part| WeightedQuery::parse(part, DEFAULT_NEGATIVE_WEIGHT))
+                                .collect(),
+                            negative_mode,
+                        },
+                        config,
+                    )],
+                };
+                if !files.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "--follow reads from stdin; pipe the stream in instead of passing files"
+                    ));
+                }
+                follow_cmd(standing_queries, format, ColorChoice::parse(&color)?)?;
+                return Ok(());
+            }
+            if let Some(queries_path) = queries {
+                if !matches!(format, OutputFormat::Text | OutputFormat::Json) {
+                    return Err(anyhow::anyhow!(
+                        "--queries always prints one JSON object per query; --format only applies with --follow"
+                    ));
+                }
+                let batch_queries = parse_batch_queries(&std::fs::read_to_string(&queries_path)?)?;
+                batch_search_cmd(
+                    batch_queries,
+                    files,
+                    config,
+                    NegativeMode::parse(&negative_mode)?,
+                    workspace.as_deref(),
+                    rank_by_document,
+                    stdin_format,
+                )
+                .await?;
+                return Ok(());
+            }
+            let (query, files, like) = match like {
+                Some(spec) => (None, files, Some(LineSpan::parse(&spec)?)),
+                None => {
+                    let negative_mode = NegativeMode::parse(&negative_mode)?;
+                    let query = query.map(|query| CompositeQuery {
+                        positive: std::iter::once(&query)
+                            .chain(&and)
+                            .map(|part| WeightedQuery::parse(part, 1.0))
+                            .collect(),
+                        negative: not
+                            .iter()
+                            .map(|part| WeightedQuery::parse(part, DEFAULT_NEGATIVE_WEIGHT))
+                            .collect(),
+                        negative_mode,
+                    });
+                    (query, files, None)
+                }
+            };
+            search_cmd(
+                query,
+                files,
+                config,
+                format,
+                ColorChoice::parse(&color)?,
                 workspace.as_deref(),
+                rank_by_document,
+                like,
+                stdin_format,
             )
             .await?;
         }
@@ -199,6 +586,62 @@ async fn main() -> anyhow::Result<()> {
             WorkspaceCommands::Status { name } => {
                 workspace_status_cmd(json, name.as_deref()).await?;
             }
+            WorkspaceCommands::List => {
+                workspace_list_cmd(json).await?;
+            }
+            WorkspaceCommands::Delete { name, yes } => {
+                workspace_delete_cmd(name, yes, json).await?;
+            }
+            WorkspaceCommands::Rename { from, to } => {
+                workspace_rename_cmd(from, to, json).await?;
+            }
+            WorkspaceCommands::Copy { from, to } => {
+                workspace_copy_cmd(from, to, json).await?;
+            }
+            WorkspaceCommands::Configure {
+                quantization,
+                storage,
+                hnsw_m,
+                hnsw_ef_construct,
+                hnsw_ef,
+                oversample,
+                workspace,
+            } => {
+                workspace_configure_cmd(
+                    quantization,
+                    storage,
+                    hnsw_m,
+                    hnsw_ef_construct,
+                    hnsw_ef,
+                    oversample,
+                    json,
+                    workspace.as_deref(),
+                )
+                .await?;
+            }
+            WorkspaceCommands::Index {
+                roots,
+                glob,
+                exclude,
+                file_type,
+                max_filesize,
+                no_ignore,
+                hidden,
+                workspace,
+            } => {
+                workspace_index_cmd(
+                    roots,
+                    SearchFilter::new(None, &glob, &exclude, &file_type, false)?,
+                    Some(parse_file_size(&max_filesize)?),
+                    WalkOptions {
+                        respect_ignore_files: !no_ignore,
+                        hidden,
+                    },
+                    json,
+                    workspace.as_deref(),
+                )
+                .await?;
+            }
         },
     }
 
@@ -2,7 +2,7 @@ use anyhow::Result;
 use async_openai::Client;
 use async_openai::config::OpenAIConfig;
 use model2vec_rs::model::StaticModel;
-use std::io::{self, BufRead, IsTerminal};
+use std::io::{self, IsTerminal, Read};
 
 use crate::SemtoolsConfig;
 use crate::ask::chat_agent::{ask_agent, ask_agent_with_stdin};
@@ -10,11 +10,21 @@ use crate::ask::responses_agent::{ask_agent_responses, ask_agent_responses_with_
 use crate::config::ApiMode;
 use crate::json_mode::ErrorOutput;
 use crate::search::MODEL_NAME;
-
-fn read_from_stdin() -> Result<Vec<String>> {
-    let stdin = io::stdin();
-    let lines: Result<Vec<String>, _> = stdin.lock().lines().collect();
-    Ok(lines?)
+use crate::search::cache::EmbeddingCache;
+use crate::search::records::{StdinFormat, StdinRecord, parse_records};
+
+#[cfg(feature = "workspace")]
+use crate::search::workspace_files;
+#[cfg(feature = "workspace")]
+use crate::workspace::Workspace;
+
+// Read piped stdin as documents, leaving out empty ones
+fn read_from_stdin(stdin_format: StdinFormat) -> Result<Vec<StdinRecord>> {
+    let mut bytes = Vec::new();
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    let mut records = parse_records(&bytes, stdin_format)?;
+    records.retain(|record| !record.text.trim().is_empty());
+    Ok(records)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -28,6 +38,7 @@ pub async fn ask_cmd(
     api_mode: Option<String>,
     json: bool,
     workspace_name: Option<&str>,
+    stdin_format: StdinFormat,
 ) -> Result<()> {
     // Load configuration
     let config_path = config.unwrap_or_else(SemtoolsConfig::default_config_path);
@@ -80,17 +91,15 @@ pub async fn ask_cmd(
 
     // Check if we have stdin input (no files and stdin is not a terminal)
     if files.is_empty() && !io::stdin().is_terminal() {
-        let stdin_lines = read_from_stdin()?;
-        if !stdin_lines.is_empty() {
-            let stdin_content = stdin_lines.join("\n");
-
+        let stdin_records = read_from_stdin(stdin_format)?;
+        if !stdin_records.is_empty() {
             // Run the appropriate agent with stdin content (no tools)
             let output = match api_mode {
                 ApiMode::Chat => {
-                    ask_agent_with_stdin(&stdin_content, &query, &client, &model_name).await?
+                    ask_agent_with_stdin(&stdin_records, &query, &client, &model_name).await?
                 }
                 ApiMode::Responses => {
-                    ask_agent_responses_with_stdin(&stdin_content, &query, &client, &model_name)
+                    ask_agent_responses_with_stdin(&stdin_records, &query, &client, &model_name)
                         .await?
                 }
             };
@@ -106,6 +115,14 @@ pub async fn ask_cmd(
         }
     }
 
+    // Without files, the agent searches everything indexed in the workspace
+    #[cfg(feature = "workspace")]
+    let files = if Workspace::active(workspace_name).is_ok() {
+        workspace_files(&files, workspace_name)?
+    } else {
+        files
+    };
+
     // If no stdin, we need files to search through
     if files.is_empty() {
         let error_msg =
@@ -132,6 +149,9 @@ pub async fn ask_cmd(
         None, // Optional: subfolder if model files are not at the root of the repo/path
     )?;
 
+    // The agent may search the same files many times, keep their embeddings around
+    EmbeddingCache::global().retain_in_memory(true);
+
     // Run the appropriate agent based on API mode
     let output = match api_mode {
         ApiMode::Chat => {
@@ -1,124 +1,440 @@
 use anyhow::Result;
 use model2vec_rs::model::StaticModel;
-use std::io::{self, BufRead, IsTerminal};
+use std::io::{self, BufRead, IsTerminal, Read};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
 
 #[cfg(feature = "workspace")]
-use crate::workspace::{Workspace, store::RankedLine};
+use crate::workspace::{Workspace, store::RankedDocument};
 
 #[cfg(feature = "workspace")]
-use crate::search::search_with_workspace;
+use crate::search::{
+    WorkspaceSearch, rank_documents_with_workspace, ranked_line_to_search_result,
+    search_with_workspace, workspace_files,
+};
 
-use crate::json_mode::{ErrorOutput, SearchOutput, SearchResultJSON};
+use crate::json_mode::{
+    ContextJSON, DocumentResultJSON, DocumentSearchOutput, ErrorOutput, MatchJSON, SCHEMA_VERSION,
+    SearchOutput, SearchResultJSON, SkippedFileJSON, StreamedResultJSON,
+};
+use crate::search::cutoff::{ScoreDistribution, explain};
+use crate::search::follow::{FollowMatch, StandingQuery, StreamMatcher};
+use crate::search::query::{BatchQuery, CompositeQuery, NegativeMode};
+use crate::search::records::{StdinFormat, parse_records};
+use crate::search::text::SkippedFile;
 use crate::search::{
-    Document, MODEL_NAME, SearchConfig, SearchResult, search_documents, search_files,
+    Document, DocumentAggregation, DocumentResult, LineSpan, MODEL_NAME, SearchConfig,
+    SearchResult, create_document_from_content, document_distribution, embed_span,
+    line_distribution, load_documents, parsed_source_document, rank_documents, rank_files,
+    search_documents, search_files,
 };
 
-fn read_from_stdin() -> Result<Vec<String>> {
-    let stdin = io::stdin();
-    let lines: Result<Vec<String>, _> = stdin.lock().lines().collect();
-    Ok(lines?)
+fn read_from_stdin() -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Read piped stdin as documents, if there is any. Records that don't pass the
+// filters are left out; path filters apply to record ids, which plain text lacks.
+fn read_stdin_documents(
+    model: &StaticModel,
+    config: &SearchConfig,
+    stdin_format: StdinFormat,
+) -> Result<Option<Vec<Document>>> {
+    if io::stdin().is_terminal() {
+        return Ok(None);
+    }
+
+    let records = parse_records(&read_from_stdin()?, stdin_format)?;
+    let documents: Vec<Document> = records
+        .into_iter()
+        .filter(|record| {
+            stdin_format == StdinFormat::Text || config.filter.matches_path(&record.id)
+        })
+        .filter(|record| config.filter.matches_metadata(&record.metadata))
+        .filter_map(|record| {
+            create_document_from_content(record.id, &record.text, model, config.ignore_case)
+        })
+        .collect();
+
+    Ok((!documents.is_empty() || stdin_format != StdinFormat::Text).then_some(documents))
 }
 
-// Convert SearchResult to SearchResultJSON
+fn exit_with_no_input(json: bool) -> Result<()> {
+    let error_msg = "No input provided. Either specify files as arguments or pipe input to stdin.";
+    if json {
+        let error_output = ErrorOutput {
+            error: error_msg.to_string(),
+            error_type: "NoInput".to_string(),
+        };
+        let json_output = serde_json::to_string_pretty(&error_output)?;
+        eprintln!("{}", json_output);
+    } else {
+        eprintln!("Error: {}", error_msg);
+    }
+    std::process::exit(1);
+}
+
+// Convert SearchResult (0-based, end-exclusive) to SearchResultJSON (1-based, inclusive)
 fn search_result_to_json(result: &SearchResult) -> SearchResultJSON {
+    let (start_column, end_column) = match result.match_columns {
+        Some(columns) => (columns.start + 1, columns.end),
+        None => (1, result.match_text.chars().count()),
+    };
+
     SearchResultJSON {
-        filename: result.filename.clone(),
-        start_line_number: result.start,
-        end_line_number: result.end,
-        match_line_number: result.match_line,
+        path: result.filename.clone(),
+        source: parsed_source_document(&result.filename),
         distance: result.distance,
-        content: result.lines.join("\n"),
+        score: result.score(),
+        matched: MatchJSON {
+            line: result.match_line + 1,
+            start_column,
+            end_column,
+            byte_start: result.match_bytes.start,
+            byte_end: result.match_bytes.end,
+            text: result.match_text.clone(),
+        },
+        context: ContextJSON {
+            start_line: result.start + 1,
+            end_line: result.end,
+            text: result.lines.join("\n"),
+        },
+        stale: result.stale,
+    }
+}
+
+// Convert DocumentResult to DocumentResultJSON
+fn document_result_to_json(result: &DocumentResult) -> DocumentResultJSON {
+    DocumentResultJSON {
+        path: result.filename.clone(),
+        source: parsed_source_document(&result.filename),
+        distance: result.distance,
+        score: 1.0 - result.distance,
+        best_match: search_result_to_json(&result.best_match),
+    }
+}
+
+fn skipped_files_to_json(skipped: &[SkippedFile]) -> Vec<SkippedFileJSON> {
+    skipped
+        .iter()
+        .map(|skipped_file| SkippedFileJSON {
+            path: skipped_file.path.clone(),
+            reason: skipped_file.reason.to_string(),
+        })
+        .collect()
+}
+
+// Summarize files left out of the search on stderr, keeping stdout for results
+fn report_skipped_files(skipped: &[SkippedFile]) {
+    if skipped.is_empty() {
+        return;
+    }
+    eprintln!("Skipped {} file(s):", skipped.len());
+    for skipped_file in skipped {
+        eprintln!("  {}: {}", skipped_file.path, skipped_file.reason);
+    }
+}
+
+// Describe the spread of candidate distances on stderr, for --explain
+fn report_distribution(
+    distribution: Option<&ScoreDistribution>,
+    unit: &str,
+    config: &SearchConfig,
+) {
+    if let Some(distribution) = distribution {
+        eprint!(
+            "{}",
+            explain(distribution, unit, config.max_distance, config.cutoff)
+        );
+    }
+}
+
+/// How search results are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Results with context lines, for reading
+    Text,
+    /// A single JSON object with all results
+    Json,
+    /// One JSON object per result
+    Jsonl,
+    /// `file:line:column:text`, as produced by `rg --vimgrep`
+    Vimgrep,
+    /// `file:line:column: note: text`, understood by default errorformats and problem matchers
+    Quickfix,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            "vimgrep" => Ok(Self::Vimgrep),
+            "quickfix" => Ok(Self::Quickfix),
+            _ => Err(anyhow::anyhow!(
+                "Invalid output format: '{}'. Must be 'text', 'json', 'jsonl', 'vimgrep' or 'quickfix'",
+                name
+            )),
+        }
+    }
+
+    fn is_json(self) -> bool {
+        matches!(self, Self::Json | Self::Jsonl)
+    }
+}
+
+/// Whether text output is coloured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Always,
+    Never,
+    /// Colour when stdout is a terminal and `NO_COLOR` is not set
+    Auto,
+}
+
+impl ColorChoice {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            "auto" => Ok(Self::Auto),
+            _ => Err(anyhow::anyhow!(
+                "Invalid color choice: '{}'. Must be 'always', 'never' or 'auto'",
+                name
+            )),
+        }
+    }
+
+    fn enabled(self) -> bool {
+        self.enabled_for(
+            io::stdout().is_terminal(),
+            std::env::var_os("NO_COLOR").is_some(),
+        )
+    }
+
+    fn enabled_for(self, is_terminal: bool, no_color: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => is_terminal && !no_color,
+        }
+    }
+}
+
+// The lines of a single result with their line numbers, highlighting the actual match
+fn format_result_lines(search_result: &SearchResult, color: bool) -> Vec<String> {
+    search_result
+        .lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let line_number = search_result.start + i;
+            if color && line_number == search_result.match_line {
+                // Highlight the matching line with yellow background and black text
+                format!("\x1b[43m\x1b[30m{:4}: {}\x1b[0m", line_number + 1, line)
+            } else {
+                format!("{:4}: {}", line_number + 1, line)
+            }
+        })
+        .collect()
+}
+
+fn print_result_lines(search_result: &SearchResult, color: bool) {
+    for line in format_result_lines(search_result, color) {
+        println!("{line}");
+    }
+}
+
+// Print a result with its header, prefixed with the query it matched when there are several
+fn print_search_result(search_result: &SearchResult, query_id: Option<&str>, color: bool) {
+    let filename = search_result.filename.to_string();
+    let distance = search_result.distance;
+    // 1-based and inclusive like the line prefixes, so it can be passed to --like
+    let start = search_result.start + 1;
+    let end = search_result.end;
+    let stale = stale_marker(search_result);
+
+    match query_id {
+        Some(query_id) => println!("[{query_id}] {filename}:{start}-{end} ({distance}){stale}"),
+        None => println!("{filename}:{start}-{end} ({distance}){stale}"),
+    }
+    print_result_lines(search_result, color);
+    println!(); // Empty line between results
+}
+
+// Shown after a result's header when its lines are from the workspace index
+// because the file has changed since it was indexed
+fn stale_marker(search_result: &SearchResult) -> &'static str {
+    if search_result.stale {
+        " [stale: file changed since indexing]"
+    } else {
+        ""
     }
 }
 
 // Extracted function to format and print results
-fn print_search_results(results: &[SearchResult]) {
-    let is_tty = io::stdout().is_terminal();
+fn print_search_results(results: &[SearchResult], color: bool) {
     for search_result in results {
-        let filename = search_result.filename.to_string();
-        let distance = search_result.distance;
-        let start = search_result.start;
-        let end = search_result.end;
-
-        println!("{filename}:{start}::{end} ({distance})");
-
-        // Print each line, highlighting the actual match
-        for (i, line) in search_result.lines.iter().enumerate() {
-            let line_number = start + i;
-
-            if line_number == search_result.match_line {
-                if is_tty {
-                    // Highlight the matching line with yellow background and black text
-                    println!("\x1b[43m\x1b[30m{:4}: {}\x1b[0m", line_number + 1, line);
-                } else {
-                    println!("{:4}: {}", line_number + 1, line);
-                }
-            } else {
-                // Regular context line
-                println!("{:4}: {}", line_number + 1, line);
+        print_search_result(search_result, None, color);
+    }
+}
+
+fn print_document_results(results: &[DocumentResult], color: bool) {
+    for document_result in results {
+        let filename = &document_result.filename;
+        let distance = document_result.distance;
+        let best_match = &document_result.best_match;
+
+        println!("{filename} ({distance})");
+        println!(
+            "  best match at {}-{} ({}){}",
+            best_match.start + 1,
+            best_match.end,
+            best_match.distance,
+            stale_marker(best_match)
+        );
+        print_result_lines(best_match, color);
+        println!(); // Empty line between results
+    }
+}
+
+// A result as a single line for --vimgrep or quickfix output, located at the
+// start of its match (1-based line and column)
+fn format_result_line(result: &SearchResult, format: OutputFormat) -> String {
+    let line = result.match_line + 1;
+    let column = result.match_columns.map_or(1, |columns| columns.start + 1);
+    // The match line as displayed, i.e. shortened when it is overly long
+    let text = result
+        .lines
+        .get(result.match_line.saturating_sub(result.start))
+        .map_or("", String::as_str);
+
+    match format {
+        OutputFormat::Quickfix => format!(
+            "{}:{line}:{column}: note: {text} (score {:.3})",
+            result.filename,
+            result.score()
+        ),
+        _ => format!("{}:{line}:{column}:{text}", result.filename),
+    }
+}
+
+// A result as one line of `--format jsonl` output
+fn jsonl_line<T: serde::Serialize>(result: T, query_id: Option<String>) -> Result<String> {
+    Ok(serde_json::to_string(&StreamedResultJSON {
+        schema_version: SCHEMA_VERSION,
+        query_id,
+        result,
+    })?)
+}
+
+fn output_search_results(
+    results: &[SearchResult],
+    skipped: &[SkippedFile],
+    format: OutputFormat,
+    color: ColorChoice,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            let output = SearchOutput {
+                schema_version: SCHEMA_VERSION,
+                query_id: None,
+                results: results.iter().map(search_result_to_json).collect(),
+                skipped: skipped_files_to_json(skipped),
+            };
+            let json_output = serde_json::to_string_pretty(&output)?;
+            println!("{}", json_output);
+            return Ok(());
+        }
+        OutputFormat::Jsonl => {
+            for result in results {
+                println!("{}", jsonl_line(search_result_to_json(result), None)?);
             }
         }
-        println!(); // Empty line between results
+        OutputFormat::Vimgrep | OutputFormat::Quickfix => {
+            for result in results {
+                println!("{}", format_result_line(result, format));
+            }
+        }
+        OutputFormat::Text => print_search_results(results, color.enabled()),
     }
+    report_skipped_files(skipped);
+    Ok(())
 }
 
-#[cfg(feature = "workspace")]
-fn print_workspace_search_results(ranked_lines: &[RankedLine], n_lines: usize) {
-    let is_tty = io::stdout().is_terminal();
-
-    for ranked_line in ranked_lines {
-        let filename = &ranked_line.path;
-        let distance = ranked_line.distance;
-        // ranked_line.line_number is 0-based from database
-        let match_line_number = ranked_line.line_number as usize;
-
-        // Calculate context range (working with 0-based indices)
-        let start = match_line_number.saturating_sub(n_lines);
-        let end = match_line_number + n_lines + 1;
-
-        println!("{filename}:{start}::{end} ({distance})");
-
-        // For workspace results, we need to read the file to get context lines
-        // This is acceptable since we're only doing this for the final results
-        if let Ok(content) = std::fs::read_to_string(filename) {
-            let lines: Vec<&str> = content.lines().collect();
-            let actual_start = start;
-            let actual_end = end.min(lines.len());
-
-            for (i, line) in lines[actual_start..actual_end].iter().enumerate() {
-                let line_number = actual_start + i;
-
-                if line_number == match_line_number {
-                    if is_tty {
-                        // Highlight the matching line with yellow background and black text
-                        println!("\x1b[43m\x1b[30m{:4}: {}\x1b[0m", line_number + 1, line);
-                    } else {
-                        println!("{:4}: {}", line_number + 1, line);
-                    }
-                } else {
-                    // Regular context line
-                    println!("{:4}: {}", line_number + 1, line);
-                }
+fn output_document_results(
+    results: &[DocumentResult],
+    skipped: &[SkippedFile],
+    format: OutputFormat,
+    color: ColorChoice,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            let output = DocumentSearchOutput {
+                schema_version: SCHEMA_VERSION,
+                query_id: None,
+                results: results.iter().map(document_result_to_json).collect(),
+                skipped: skipped_files_to_json(skipped),
+            };
+            let json_output = serde_json::to_string_pretty(&output)?;
+            println!("{}", json_output);
+            return Ok(());
+        }
+        OutputFormat::Jsonl => {
+            for result in results {
+                println!("{}", jsonl_line(document_result_to_json(result), None)?);
             }
-        } else {
-            // Fallback: indicate that the file couldn't be read
-            println!("    [Error: Could not read file content]");
         }
+        OutputFormat::Vimgrep | OutputFormat::Quickfix => {
+            for result in results {
+                println!("{}", format_result_line(&result.best_match, format));
+            }
+        }
+        OutputFormat::Text => print_document_results(results, color.enabled()),
+    }
+    report_skipped_files(skipped);
+    Ok(())
+}
 
-        println!(); // Empty line between results
+#[cfg(feature = "workspace")]
+fn ranked_document_to_result(ranked_doc: &RankedDocument, n_lines: usize) -> DocumentResult {
+    let best_match = match &ranked_doc.best_line {
+        Some(best_line) => ranked_line_to_search_result(best_line, n_lines),
+        None => SearchResult {
+            filename: ranked_doc.path.clone(),
+            lines: vec![],
+            start: 0,
+            end: 0,
+            match_line: 0,
+            match_columns: None,
+            match_text: String::new(),
+            match_bytes: 0..0,
+            distance: ranked_doc.distance as f64,
+            stale: false,
+        },
+    };
+
+    DocumentResult {
+        filename: ranked_doc.path.clone(),
+        distance: ranked_doc.distance as f64,
+        best_match,
     }
 }
 
 #[allow(clippy::too_many_arguments)]
 pub async fn search_cmd(
-    query: String,
+    query: Option<CompositeQuery>,
     files: Vec<String>,
-    n_lines: usize,
-    top_k: usize,
-    max_distance: Option<f64>,
-    ignore_case: bool,
-    json: bool,
+    mut config: SearchConfig,
+    format: OutputFormat,
+    color: ColorChoice,
     workspace_name: Option<&str>,
+    rank_by_document: Option<DocumentAggregation>,
+    like: Option<LineSpan>,
+    stdin_format: StdinFormat,
 ) -> Result<()> {
     let model = StaticModel::from_pretrained(
         MODEL_NAME, // "minishlab/potion-multilingual-128M",
@@ -127,150 +443,462 @@ pub async fn search_cmd(
         None, // Optional: subfolder if model files are not at the root of the repo/path
     )?;
 
-    let query = if ignore_case {
-        query.to_lowercase()
-    } else {
-        query.clone()
-    };
-
-    let query_embedding = model.encode_single(&query);
-    let config = SearchConfig {
-        n_lines,
-        top_k,
-        max_distance,
-        ignore_case,
+    let query_embedding = match like {
+        Some(span) => {
+            let embedding = embed_span(&span, &model, config.ignore_case)?;
+            // The example itself would always be the best match
+            config.exclude_spans.push(span);
+            embedding
+        }
+        None => query
+            .unwrap_or_else(|| CompositeQuery::single(""))
+            .embed(&model, &mut config)?,
     };
 
     // Handle stdin input (non-workspace mode)
-    if files.is_empty() && !io::stdin().is_terminal() {
-        let stdin_lines = read_from_stdin()?;
-        if !stdin_lines.is_empty() {
-            let lines_for_embedding = if ignore_case {
-                stdin_lines.iter().map(|s| s.to_lowercase()).collect()
-            } else {
-                stdin_lines.clone()
-            };
+    if files.is_empty()
+        && let Some(documents) = read_stdin_documents(&model, &config, stdin_format)?
+    {
+        if let Some(aggregation) = rank_by_document {
+            if config.explain {
+                let distribution =
+                    document_distribution(&documents, &query_embedding, &config, aggregation);
+                report_distribution(Some(&distribution), "documents", &config);
+            }
+            let document_results =
+                rank_documents(&documents, &query_embedding, &config, aggregation);
+            output_document_results(&document_results, &[], format, color)?;
+        } else {
+            if config.explain {
+                let distribution = line_distribution(&documents, &query_embedding, &config);
+                report_distribution(Some(&distribution), "lines", &config);
+            }
+            let search_results = search_documents(&documents, &query_embedding, &config);
+            output_search_results(&search_results, &[], format, color)?;
+        }
 
-            let embeddings = model.encode_with_args(&lines_for_embedding, Some(2048), 16384);
+        return Ok(());
+    }
 
-            let documents = vec![Document {
-                filename: "<stdin>".to_string(),
-                lines: stdin_lines,
-                embeddings,
-            }];
+    // Without files, a workspace search covers everything indexed in it
+    #[cfg(feature = "workspace")]
+    let workspace_active = Workspace::active(workspace_name).is_ok();
+    #[cfg(not(feature = "workspace"))]
+    let workspace_active = false;
 
-            let search_results = search_documents(&documents, &query_embedding, &config);
+    if files.is_empty() && !workspace_active {
+        exit_with_no_input(format.is_json())?;
+    }
 
-            if json {
-                let output = SearchOutput {
-                    results: search_results.iter().map(search_result_to_json).collect(),
-                };
-                let json_output = serde_json::to_string_pretty(&output)?;
-                println!("{}", json_output);
+    // Handle file input with optional workspace integration
+    #[cfg(feature = "workspace")]
+    {
+        let files = if workspace_active {
+            workspace_files(&files, workspace_name)?
+        } else {
+            files
+        };
+
+        if let Some(aggregation) = rank_by_document {
+            if workspace_active {
+                let ranked_docs = rank_documents_with_workspace(
+                    &files,
+                    &query_embedding,
+                    &model,
+                    &config,
+                    aggregation,
+                    workspace_name,
+                )
+                .await?;
+                report_distribution(ranked_docs.distribution.as_ref(), "documents", &config);
+                let document_results: Vec<DocumentResult> = ranked_docs
+                    .results
+                    .iter()
+                    .map(|ranked_doc| ranked_document_to_result(ranked_doc, config.n_lines))
+                    .collect();
+                output_document_results(&document_results, &ranked_docs.skipped, format, color)?;
             } else {
-                print_search_results(&search_results);
+                let outcome = rank_files(&files, &query_embedding, &model, &config, aggregation)?;
+                report_distribution(outcome.distribution.as_ref(), "documents", &config);
+                output_document_results(&outcome.results, &outcome.skipped, format, color)?;
             }
-
-            return Ok(());
+        } else if workspace_active {
+            // Workspace mode: use persisted line embeddings for speed
+            let outcome =
+                search_with_workspace(&files, &query_embedding, &model, &config, workspace_name)
+                    .await?;
+            report_distribution(outcome.distribution.as_ref(), "lines", &config);
+
+            let search_results: Vec<SearchResult> = outcome
+                .results
+                .iter()
+                .map(|ranked_line| ranked_line_to_search_result(ranked_line, config.n_lines))
+                .collect();
+            output_search_results(&search_results, &outcome.skipped, format, color)?;
+        } else {
+            let outcome = search_files(&files, &query_embedding, &model, &config)?;
+            report_distribution(outcome.distribution.as_ref(), "lines", &config);
+            output_search_results(&outcome.results, &outcome.skipped, format, color)?;
         }
     }
 
-    if files.is_empty() {
-        let error_msg =
-            "No input provided. Either specify files as arguments or pipe input to stdin.";
-        if json {
-            let error_output = ErrorOutput {
-                error: error_msg.to_string(),
-                error_type: "NoInput".to_string(),
-            };
-            let json_output = serde_json::to_string_pretty(&error_output)?;
-            eprintln!("{}", json_output);
+    #[cfg(not(feature = "workspace"))]
+    {
+        if let Some(aggregation) = rank_by_document {
+            let outcome = rank_files(&files, &query_embedding, &model, &config, aggregation)?;
+            report_distribution(outcome.distribution.as_ref(), "documents", &config);
+            output_document_results(&outcome.results, &outcome.skipped, format, color)?;
         } else {
-            eprintln!("Error: {}", error_msg);
+            let outcome = search_files(&files, &query_embedding, &model, &config)?;
+            report_distribution(outcome.distribution.as_ref(), "lines", &config);
+            output_search_results(&outcome.results, &outcome.skipped, format, color)?;
         }
-        std::process::exit(1);
     }
 
-    // Handle file input with optional workspace integration
+    Ok(())
+}
+
+/// Where the queries of a batch run are answered from
+enum BatchCorpus {
+    Documents(Vec<Document>),
     #[cfg(feature = "workspace")]
-    {
-        if Workspace::active(workspace_name).is_ok() {
-            // Workspace mode: use persisted line embeddings for speed
-            let config = SearchConfig {
-                n_lines,
-                top_k,
-                max_distance,
-                ignore_case,
-            };
-            let ranked_lines =
-                search_with_workspace(&files, &query, &model, &config, workspace_name).await?;
+    Workspace(WorkspaceSearch),
+}
 
-            if json {
-                // Convert workspace results to SearchResultJSON
-                let results: Vec<SearchResultJSON> = ranked_lines
-                    .iter()
-                    .map(|ranked_line| {
-                        let match_line_number = ranked_line.line_number as usize;
-                        let start = match_line_number.saturating_sub(n_lines);
-                        let end = match_line_number + n_lines + 1;
-
-                        // Read file content for the result
-                        let content =
-                            if let Ok(file_content) = std::fs::read_to_string(&ranked_line.path) {
-                                let lines: Vec<&str> = file_content.lines().collect();
-                                let actual_start = start;
-                                let actual_end = end.min(lines.len());
-                                lines[actual_start..actual_end].join("\n")
-                            } else {
-                                "[Error: Could not read file content]".to_string()
-                            };
-
-                        SearchResultJSON {
-                            filename: ranked_line.path.clone(),
-                            start_line_number: start,
-                            end_line_number: end,
-                            match_line_number,
-                            distance: ranked_line.distance as f64,
-                            content,
-                        }
-                    })
-                    .collect();
+/// Run every query of a `--queries` file, loading the model and embedding the
+/// corpus only once. Prints one JSON object per query (JSON Lines) as it goes.
+pub async fn batch_search_cmd(
+    queries: Vec<BatchQuery>,
+    files: Vec<String>,
+    config: SearchConfig,
+    negative_mode: NegativeMode,
+    workspace_name: Option<&str>,
+    rank_by_document: Option<DocumentAggregation>,
+    stdin_format: StdinFormat,
+) -> Result<()> {
+    // Validate every query up front rather than failing halfway through the batch
+    let queries = queries
+        .into_iter()
+        .map(|batch_query| {
+            let composite = batch_query.composite(negative_mode)?;
+            Ok((batch_query, composite))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let model = StaticModel::from_pretrained(MODEL_NAME, None, None, None)?;
+
+    let stdin_documents = if files.is_empty() {
+        read_stdin_documents(&model, &config, stdin_format)?
+    } else {
+        None
+    };
 
-                let output = SearchOutput { results };
-                let json_output = serde_json::to_string_pretty(&output)?;
-                println!("{}", json_output);
-            } else {
-                print_workspace_search_results(&ranked_lines, n_lines);
-            }
+    // Without files, a workspace search covers everything indexed in it
+    #[cfg(feature = "workspace")]
+    let workspace_active = Workspace::active(workspace_name).is_ok();
+    #[cfg(not(feature = "workspace"))]
+    let workspace_active = false;
+
+    #[cfg(feature = "workspace")]
+    let files = if workspace_active && stdin_documents.is_none() {
+        workspace_files(&files, workspace_name)?
+    } else {
+        files
+    };
+
+    let (corpus, skipped) = if let Some(documents) = stdin_documents {
+        (BatchCorpus::Documents(documents), Vec::new())
+    } else if files.is_empty() && !workspace_active {
+        return exit_with_no_input(true);
+    } else {
+        #[cfg(feature = "workspace")]
+        if workspace_active {
+            let mut workspace = WorkspaceSearch::open(&files, &model, &config, workspace_name)?;
+            let skipped = std::mem::take(&mut workspace.skipped);
+            (BatchCorpus::Workspace(workspace), skipped)
         } else {
-            let search_results = search_files(&files, &query, &model, &config)?;
-
-            if json {
-                let output = SearchOutput {
-                    results: search_results.iter().map(search_result_to_json).collect(),
-                };
-                let json_output = serde_json::to_string_pretty(&output)?;
-                println!("{}", json_output);
-            } else {
-                print_search_results(&search_results);
+            let loaded = load_documents(&files, &model, &config);
+            (BatchCorpus::Documents(loaded.results), loaded.skipped)
+        }
+
+        #[cfg(not(feature = "workspace"))]
+        {
+            let _ = workspace_name;
+            let loaded = load_documents(&files, &model, &config);
+            (BatchCorpus::Documents(loaded.results), loaded.skipped)
+        }
+    };
+
+    for (batch_query, composite) in queries {
+        let mut query_config = batch_query.config(&config);
+        let query_embedding = composite.embed(&model, &mut query_config)?;
+        let query_id = batch_query.id;
+
+        let json_output = match (&corpus, rank_by_document) {
+            (BatchCorpus::Documents(documents), Some(aggregation)) => {
+                let results =
+                    rank_documents(documents, &query_embedding, &query_config, aggregation);
+                serde_json::to_string(&DocumentSearchOutput {
+                    schema_version: SCHEMA_VERSION,
+                    query_id,
+                    results: results.iter().map(document_result_to_json).collect(),
+                    skipped: skipped_files_to_json(&skipped),
+                })?
+            }
+            (BatchCorpus::Documents(documents), None) => {
+                let results = search_documents(documents, &query_embedding, &query_config);
+                serde_json::to_string(&SearchOutput {
+                    schema_version: SCHEMA_VERSION,
+                    query_id,
+                    results: results.iter().map(search_result_to_json).collect(),
+                    skipped: skipped_files_to_json(&skipped),
+                })?
+            }
+            #[cfg(feature = "workspace")]
+            (BatchCorpus::Workspace(workspace), Some(aggregation)) => {
+                let ranked_docs = workspace.rank_documents(
+                    &files,
+                    &query_embedding,
+                    &query_config,
+                    aggregation,
+                )?;
+                serde_json::to_string(&DocumentSearchOutput {
+                    schema_version: SCHEMA_VERSION,
+                    query_id,
+                    results: ranked_docs
+                        .iter()
+                        .map(|ranked_doc| {
+                            document_result_to_json(&ranked_document_to_result(
+                                ranked_doc,
+                                query_config.n_lines,
+                            ))
+                        })
+                        .collect(),
+                    skipped: skipped_files_to_json(&skipped),
+                })?
+            }
+            #[cfg(feature = "workspace")]
+            (BatchCorpus::Workspace(workspace), None) => {
+                let ranked_lines = workspace.search(&files, &query_embedding, &query_config)?;
+                serde_json::to_string(&SearchOutput {
+                    schema_version: SCHEMA_VERSION,
+                    query_id,
+                    results: ranked_lines
+                        .iter()
+                        .map(|ranked_line| {
+                            search_result_to_json(&ranked_line_to_search_result(
+                                ranked_line,
+                                query_config.n_lines,
+                            ))
+                        })
+                        .collect(),
+                    skipped: skipped_files_to_json(&skipped),
+                })?
+            }
+        };
+
+        println!("{}", json_output);
+    }
+
+    Ok(())
+}
+
+/// How long `--follow` waits for more input before printing matches whose
+/// after-context is still incomplete
+const FOLLOW_IDLE_FLUSH: Duration = Duration::from_millis(500);
+
+/// Most lines embedded at once by `--follow` when input arrives faster than it is matched
+const FOLLOW_MAX_BATCH: usize = 1024;
+
+// Print matches of `--follow` as soon as they are complete
+fn output_follow_matches(matches: &[FollowMatch], format: OutputFormat, color: bool) -> Result<()> {
+    for follow_match in matches {
+        let result = &follow_match.result;
+        match format {
+            OutputFormat::Jsonl | OutputFormat::Json => {
+                let query_id = follow_match.query_id.clone();
+                println!("{}", jsonl_line(search_result_to_json(result), query_id)?);
+            }
+            OutputFormat::Vimgrep | OutputFormat::Quickfix => {
+                println!("{}", format_result_line(result, format));
+            }
+            OutputFormat::Text => {
+                print_search_result(result, follow_match.query_id.as_deref(), color)
             }
         }
     }
+    Ok(())
+}
 
-    #[cfg(not(feature = "workspace"))]
+/// Match lines of stdin against standing queries as they arrive, like a
+/// semantic `tail -f`. Every query needs a distance threshold, since there is
+/// no end of input to pick the top-k from. Each query comes with its id (for
+/// labelling its matches) and its own configuration.
+pub fn follow_cmd(
+    queries: Vec<(Option<String>, CompositeQuery, SearchConfig)>,
+    format: OutputFormat,
+    color: ColorChoice,
+) -> Result<()> {
+    if format == OutputFormat::Json {
+        return Err(anyhow::anyhow!(
+            "--follow prints matches as they arrive; use --format jsonl instead of json"
+        ));
+    }
+    if let Some((id, _, _)) = queries
+        .iter()
+        .find(|(_, _, config)| config.max_distance.is_none())
     {
-        let search_results = search_files(&files, &query, &model, &config)?;
+        return Err(anyhow::anyhow!(
+            "--follow needs a --max-distance{}",
+            id.as_ref()
+                .map(|id| format!(" (or max_distance for query '{id}')"))
+                .unwrap_or_default()
+        ));
+    }
+    if io::stdin().is_terminal() {
+        return exit_with_no_input(format.is_json());
+    }
 
-        if json {
-            let output = SearchOutput {
-                results: search_results.iter().map(search_result_to_json).collect(),
-            };
-            let json_output = serde_json::to_string_pretty(&output)?;
-            println!("{}", json_output);
-        } else {
-            print_search_results(&search_results);
+    let model = StaticModel::from_pretrained(MODEL_NAME, None, None, None)?;
+    let standing = queries
+        .into_iter()
+        .map(|(id, composite, mut config)| {
+            let embedding = composite.embed(&model, &mut config)?;
+            Ok(StandingQuery {
+                id,
+                embedding,
+                config,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut matcher = StreamMatcher::new("<stdin>", standing);
+
+    // Read on a separate thread so that a quiet stream doesn't hold back
+    // matches waiting for after-context. Reading stops while a batch is
+    // embedded, rather than buffering a fast stream without bound.
+    let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(FOLLOW_MAX_BATCH);
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buffer = Vec::new();
+        loop {
+            match stdin.read_until(b'\n', &mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if sender.send(std::mem::take(&mut buffer)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let color = color.enabled();
+    loop {
+        match receiver.recv_timeout(FOLLOW_IDLE_FLUSH) {
+            Ok(line) => {
+                let mut batch = vec![line];
+                batch.extend(receiver.try_iter().take(FOLLOW_MAX_BATCH - 1));
+                output_follow_matches(&matcher.push_lines(&batch, &model), format, color)?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                output_follow_matches(&matcher.flush(), format, color)?;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                output_follow_matches(&matcher.flush(), format, color)?;
+                break;
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::segment::ColumnRange;
+
+    fn result() -> SearchResult {
+        SearchResult {
+            filename: "src/db.rs".to_string(),
+            lines: vec![
+                "fn connect() {".to_string(),
+                "    pool.get()?".to_string(),
+                "}".to_string(),
+            ],
+            start: 9,
+            end: 12,
+            match_line: 10,
+            match_columns: Some(ColumnRange { start: 4, end: 15 }),
+            match_text: "pool.get()?".to_string(),
+            match_bytes: 19..30,
+            distance: 0.25,
+            stale: false,
+        }
+    }
+
+    #[test]
+    fn test_format_result_line() {
+        assert_eq!(
+            format_result_line(&result(), OutputFormat::Vimgrep),
+            "src/db.rs:11:5:    pool.get()?"
+        );
+        assert_eq!(
+            format_result_line(&result(), OutputFormat::Quickfix),
+            "src/db.rs:11:5: note:     pool.get()? (score 0.750)"
+        );
+
+        // A whole-line match starts at column 1
+        let whole_line = SearchResult {
+            match_columns: None,
+            ..result()
+        };
+        assert!(
+            format_result_line(&whole_line, OutputFormat::Vimgrep).starts_with("src/db.rs:11:1:")
+        );
+    }
+
+    #[test]
+    fn test_format_result_lines_highlights_the_match() {
+        assert_eq!(
+            format_result_lines(&result(), false),
+            vec!["  10: fn connect() {", "  11:     pool.get()?", "  12: }"]
+        );
+
+        let colored = format_result_lines(&result(), true);
+        assert_eq!(colored[0], "  10: fn connect() {");
+        assert_eq!(colored[1], "\x1b[43m\x1b[30m  11:     pool.get()?\x1b[0m");
+    }
+
+    #[test]
+    fn test_jsonl_line_is_one_flat_object() {
+        let line = jsonl_line(search_result_to_json(&result()), Some("db".to_string())).unwrap();
+        assert!(!line.contains('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(value["query_id"], "db");
+        assert_eq!(value["path"], "src/db.rs");
+        assert_eq!(value["match"]["line"], 11);
+        assert_eq!(value["match"]["start_column"], 5);
+        assert_eq!(value["context"]["start_line"], 10);
+        assert!(value.get("stale").is_none());
+
+        let line = jsonl_line(search_result_to_json(&result()), None).unwrap();
+        assert!(!line.contains("query_id"));
+    }
+
+    #[test]
+    fn test_color_choice() {
+        assert!(ColorChoice::Always.enabled_for(false, true));
+        assert!(!ColorChoice::Never.enabled_for(true, false));
+        assert!(ColorChoice::Auto.enabled_for(true, false));
+        assert!(!ColorChoice::Auto.enabled_for(false, false));
+        assert!(!ColorChoice::Auto.enabled_for(true, true));
+
+        assert_eq!(ColorChoice::parse("AUTO").unwrap(), ColorChoice::Auto);
+        assert!(ColorChoice::parse("sometimes").is_err());
+        assert_eq!(OutputFormat::parse("jsonl").unwrap(), OutputFormat::Jsonl);
+        assert!(OutputFormat::parse("xml").is_err());
+    }
+}
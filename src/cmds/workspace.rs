@@ -1,9 +1,27 @@
 use anyhow::{Context, Result};
 
 #[cfg(feature = "workspace")]
-use crate::workspace::{Workspace, WorkspaceConfig, store::Store};
+use crate::workspace::{
+    Workspace,
+    store::{IndexSettings, Quantization, VectorStorage},
+};
 
-use crate::json_mode::{PruneOutput, WorkspaceOutput};
+#[cfg(feature = "workspace")]
+use crate::search::{
+    MODEL_NAME, SearchConfig, index_workspace,
+    walk::{canonical_path, walk_files},
+};
+#[cfg(feature = "workspace")]
+use model2vec_rs::model::StaticModel;
+#[cfg(feature = "workspace")]
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::json_mode::{
+    ConfigureOutput, IndexOutput, MovedFileJSON, PruneOutput, SCHEMA_VERSION, SkippedFileJSON,
+    WorkspaceListOutput, WorkspaceOutput, WorkspaceSummaryJSON,
+};
+use crate::search::filter::SearchFilter;
+use crate::search::walk::WalkOptions;
 
 #[cfg(not(feature = "workspace"))]
 use crate::json_mode::ErrorOutput;
@@ -11,19 +29,13 @@ use crate::json_mode::ErrorOutput;
 pub async fn workspace_use_cmd(name: String, json: bool) -> Result<()> {
     #[cfg(feature = "workspace")]
     {
-        // Initialize new workspace configuration
-        let ws = Workspace {
-            config: WorkspaceConfig {
-                name: name.clone(),
-                root_dir: Workspace::root_path(&name)?,
-                ..Default::default()
-            },
-        };
+        // Initialize new workspace configuration, keeping the settings of an existing one
+        let ws = Workspace::open(Some(&name))?;
         ws.save()?;
 
         if json {
             // Try to get document count from store, or use 0 for new workspace
-            let total_documents = if let Ok(store) = Store::open(&ws.config.root_dir) {
+            let total_documents = if let Ok(store) = ws.open_store_shared() {
                 if let Ok(stats) = store.get_stats() {
                     stats.total_documents
                 } else {
@@ -34,6 +46,7 @@ pub async fn workspace_use_cmd(name: String, json: bool) -> Result<()> {
             };
 
             let output = WorkspaceOutput {
+                schema_version: SCHEMA_VERSION,
                 name: ws.config.name.clone(),
                 root_dir: ws.config.root_dir.clone(),
                 total_documents,
@@ -73,11 +86,12 @@ pub async fn workspace_status_cmd(json: bool, workspace_name: Option<&str>) -> R
         let ws = Workspace::open(workspace_name)?;
 
         // Open store and get stats
-        let store = Store::open(&ws.config.root_dir)?;
+        let store = ws.open_store_shared()?;
         let stats = store.get_stats()?;
 
         if json {
             let output = WorkspaceOutput {
+                schema_version: SCHEMA_VERSION,
                 name: ws.config.name.clone(),
                 root_dir: ws.config.root_dir.clone(),
                 total_documents: stats.total_documents,
@@ -88,12 +102,15 @@ pub async fn workspace_status_cmd(json: bool, workspace_name: Option<&str>) -> R
             println!("Active workspace: {}", ws.config.name);
             println!("Root: {}", ws.config.root_dir);
             println!("Documents: {}", stats.total_documents);
+            println!("Lines: {}", stats.total_lines);
+            println!("Disk: {}", format_size(ws.disk_usage()));
             if stats.has_index {
                 let index_info = stats.index_type.unwrap_or_else(|| "Unknown".to_string());
                 println!("Index: Yes ({index_info})");
             } else {
                 println!("Index: No");
             }
+            print_index_settings(&ws.config.index, ws.config.oversample_factor);
         }
     }
     #[cfg(not(feature = "workspace"))]
@@ -117,7 +134,7 @@ pub async fn workspace_prune_cmd(json: bool, workspace_name: Option<&str>) -> Re
     {
         let _name = Workspace::active(workspace_name).context("No active workspace")?;
         let ws = Workspace::open(workspace_name)?;
-        let store = Store::open(&ws.config.root_dir)?;
+        let store = ws.open_store()?;
 
         // Get all document paths from the workspace
         let all_paths = store.get_all_document_paths()?;
@@ -139,28 +156,501 @@ pub async fn workspace_prune_cmd(json: bool, workspace_name: Option<&str>) -> Re
             store.delete_documents(&missing_paths)?;
         }
 
+        // Remove line embeddings of lines that documents no longer have
+        let lines_removed = store.remove_orphaned_line_embeddings()?;
+
         if json {
             let output = PruneOutput {
+                schema_version: SCHEMA_VERSION,
                 files_removed,
                 files_remaining,
+                lines_removed,
             };
             let json_output = serde_json::to_string_pretty(&output)?;
             println!("{}", json_output);
-        } else if missing_paths.is_empty() {
+        } else if missing_paths.is_empty() && lines_removed == 0 {
             println!("No stale documents found. Workspace is clean.");
         } else {
-            println!("Found {} stale documents:", missing_paths.len());
-            for path in &missing_paths {
-                println!("  - {path}");
+            if !missing_paths.is_empty() {
+                println!("Found {} stale documents:", missing_paths.len());
+                for path in &missing_paths {
+                    println!("  - {path}");
+                }
+                println!(
+                    "Removed {} stale documents from workspace.",
+                    missing_paths.len()
+                );
+            }
+            if lines_removed > 0 {
+                println!("Removed {lines_removed} orphaned line embeddings from workspace.");
+            }
+        }
+    }
+    #[cfg(not(feature = "workspace"))]
+    {
+        if json {
+            let error_output = ErrorOutput {
+                error: "workspace feature not enabled".to_string(),
+                error_type: "FeatureNotEnabled".to_string(),
+            };
+            let json_output = serde_json::to_string_pretty(&error_output)?;
+            eprintln!("{}", json_output);
+        } else {
+            println!("workspace feature not enabled");
+        }
+    }
+    Ok(())
+}
+
+/// Change how a workspace stores, indexes and searches its line embeddings.
+/// Settings that aren't given keep their value; an `hnsw_ef` of 0 goes back to
+/// Qdrant's default. The line embeddings are rebuilt when storage, HNSW graph or
+/// quantization settings change.
+#[allow(clippy::too_many_arguments)]
+pub async fn workspace_configure_cmd(
+    quantization: Option<String>,
+    storage: Option<String>,
+    hnsw_m: Option<usize>,
+    hnsw_ef_construct: Option<usize>,
+    hnsw_ef: Option<usize>,
+    oversample: Option<usize>,
+    json: bool,
+    workspace_name: Option<&str>,
+) -> Result<()> {
+    #[cfg(feature = "workspace")]
+    {
+        let _name = Workspace::active(workspace_name).context("No active workspace")?;
+        let mut ws = Workspace::open(workspace_name)?;
+
+        let index = &mut ws.config.index;
+        if let Some(quantization) = quantization {
+            index.quantization = Quantization::parse(&quantization)?;
+        }
+        if let Some(storage) = storage {
+            index.storage = VectorStorage::parse(&storage)?;
+        }
+        if let Some(hnsw_m) = hnsw_m {
+            index.hnsw_m = hnsw_m;
+        }
+        if let Some(hnsw_ef_construct) = hnsw_ef_construct {
+            index.hnsw_ef_construct = hnsw_ef_construct;
+        }
+        if let Some(hnsw_ef) = hnsw_ef {
+            index.hnsw_ef = (hnsw_ef > 0).then_some(hnsw_ef);
+        }
+        if let Some(oversample) = oversample {
+            ws.config.oversample_factor = oversample.max(1);
+        }
+        ws.config.index.validate()?;
+        ws.save()?;
+
+        // Opening the store applies the settings, rebuilding the line embeddings if needed
+        if !json {
+            eprintln!("Applying settings to workspace '{}'...", ws.config.name);
+        }
+        drop(ws.open_store()?);
+
+        let index = ws.config.index;
+        if json {
+            let output = ConfigureOutput {
+                schema_version: SCHEMA_VERSION,
+                name: ws.config.name.clone(),
+                quantization: index.quantization.to_string(),
+                storage: index.storage.to_string(),
+                hnsw_m: index.hnsw_m,
+                hnsw_ef_construct: index.hnsw_ef_construct,
+                hnsw_ef: index.hnsw_ef,
+                oversample_factor: ws.config.oversample_factor,
+            };
+            let json_output = serde_json::to_string_pretty(&output)?;
+            println!("{}", json_output);
+        } else {
+            println!("Workspace '{}' configured.", ws.config.name);
+            print_index_settings(&index, ws.config.oversample_factor);
+        }
+    }
+    #[cfg(not(feature = "workspace"))]
+    {
+        let _ = (
+            quantization,
+            storage,
+            hnsw_m,
+            hnsw_ef_construct,
+            hnsw_ef,
+            oversample,
+            workspace_name,
+        );
+        if json {
+            let error_output = ErrorOutput {
+                error: "workspace feature not enabled".to_string(),
+                error_type: "FeatureNotEnabled".to_string(),
+            };
+            let json_output = serde_json::to_string_pretty(&error_output)?;
+            eprintln!("{}", json_output);
+        } else {
+            println!("workspace feature not enabled");
+        }
+    }
+    Ok(())
+}
+
+/// List all workspaces with what is indexed in them and the space they take
+pub async fn workspace_list_cmd(json: bool) -> Result<()> {
+    #[cfg(feature = "workspace")]
+    {
+        let active = Workspace::active(None).ok();
+        let mut workspaces = Vec::new();
+        for name in Workspace::list()? {
+            let ws = Workspace::open(Some(&name))?;
+            // Listing doesn't wait for other processes or write to the store: a
+            // workspace that is busy or broken is still listed, without counts
+            let stats = ws
+                .try_open_store_shared()
+                .ok()
+                .flatten()
+                .and_then(|store| store.get_stats().ok());
+            workspaces.push(WorkspaceSummaryJSON {
+                name: ws.config.name.clone(),
+                root_dir: ws.config.root_dir.clone(),
+                total_documents: stats.as_ref().map(|stats| stats.total_documents),
+                total_lines: stats.as_ref().map(|stats| stats.total_lines),
+                disk_bytes: ws.disk_usage(),
+            });
+        }
+
+        if json {
+            let output = WorkspaceListOutput {
+                schema_version: SCHEMA_VERSION,
+                active,
+                workspaces,
+            };
+            let json_output = serde_json::to_string_pretty(&output)?;
+            println!("{}", json_output);
+        } else if workspaces.is_empty() {
+            println!("No workspaces yet. Create one with: workspace use <name>");
+        } else {
+            let width = workspaces.iter().map(|ws| ws.name.len()).max().unwrap_or(0);
+            for ws in &workspaces {
+                let marker = if active.as_deref() == Some(ws.name.as_str()) {
+                    "*"
+                } else {
+                    " "
+                };
+                let counts = match (ws.total_documents, ws.total_lines) {
+                    (Some(documents), Some(lines)) => {
+                        format!("{documents} documents, {lines} lines")
+                    }
+                    _ => "busy".to_string(),
+                };
+                println!(
+                    "{marker} {:width$}  {counts}, {}",
+                    ws.name,
+                    format_size(ws.disk_bytes)
+                );
+            }
+        }
+    }
+    #[cfg(not(feature = "workspace"))]
+    {
+        if json {
+            let error_output = ErrorOutput {
+                error: "workspace feature not enabled".to_string(),
+                error_type: "FeatureNotEnabled".to_string(),
+            };
+            let json_output = serde_json::to_string_pretty(&error_output)?;
+            eprintln!("{}", json_output);
+        } else {
+            println!("workspace feature not enabled");
+        }
+    }
+    Ok(())
+}
+
+/// Delete a workspace and everything indexed in it. Asks first unless `yes`;
+/// without a terminal to ask on, `yes` is required.
+pub async fn workspace_delete_cmd(name: String, yes: bool, json: bool) -> Result<()> {
+    #[cfg(feature = "workspace")]
+    {
+        let ws = workspace_output(&Workspace::open_existing(&name)?);
+
+        if !yes {
+            if json || !io::stdin().is_terminal() {
+                anyhow::bail!("Not deleting workspace '{name}' without confirmation. Pass --yes");
+            }
+            eprint!(
+                "Delete workspace '{name}' and its {} indexed documents? [y/N] ",
+                ws.total_documents
+            );
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            io::stdin().lock().read_line(&mut answer)?;
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                println!("Workspace '{name}' kept.");
+                return Ok(());
+            }
+        }
+
+        Workspace::delete(&name)?;
+
+        if json {
+            let json_output = serde_json::to_string_pretty(&ws)?;
+            println!("{}", json_output);
+        } else {
+            println!("Workspace '{name}' deleted.");
+            if Workspace::active(None).ok().as_deref() == Some(name.as_str()) {
+                println!("It was the active workspace. To stop using it, run:");
+                println!("  unset SEMTOOLS_WORKSPACE");
+            }
+        }
+    }
+    #[cfg(not(feature = "workspace"))]
+    {
+        let _ = (name, yes);
+        if json {
+            let error_output = ErrorOutput {
+                error: "workspace feature not enabled".to_string(),
+                error_type: "FeatureNotEnabled".to_string(),
+            };
+            let json_output = serde_json::to_string_pretty(&error_output)?;
+            eprintln!("{}", json_output);
+        } else {
+            println!("workspace feature not enabled");
+        }
+    }
+    Ok(())
+}
+
+/// Give a workspace a new name, keeping its settings and everything indexed in it
+pub async fn workspace_rename_cmd(from: String, to: String, json: bool) -> Result<()> {
+    #[cfg(feature = "workspace")]
+    {
+        let ws = Workspace::rename(&from, &to)?;
+
+        if json {
+            let json_output = serde_json::to_string_pretty(&workspace_output(&ws))?;
+            println!("{}", json_output);
+        } else {
+            println!("Workspace '{from}' renamed to '{to}'.");
+            if Workspace::active(None).ok().as_deref() == Some(from.as_str()) {
+                println!("It was the active workspace. To keep using it, run:");
+                println!("  export SEMTOOLS_WORKSPACE={to}");
             }
+        }
+    }
+    #[cfg(not(feature = "workspace"))]
+    {
+        let _ = (from, to);
+        if json {
+            let error_output = ErrorOutput {
+                error: "workspace feature not enabled".to_string(),
+                error_type: "FeatureNotEnabled".to_string(),
+            };
+            let json_output = serde_json::to_string_pretty(&error_output)?;
+            eprintln!("{}", json_output);
+        } else {
+            println!("workspace feature not enabled");
+        }
+    }
+    Ok(())
+}
+
+/// Copy a workspace to a new one, e.g. to try other settings or index a branch
+/// without re-embedding everything
+pub async fn workspace_copy_cmd(from: String, to: String, json: bool) -> Result<()> {
+    #[cfg(feature = "workspace")]
+    {
+        if !json {
+            eprintln!("Copying workspace '{from}' to '{to}'...");
+        }
+        let ws = Workspace::copy(&from, &to)?;
+
+        if json {
+            let json_output = serde_json::to_string_pretty(&workspace_output(&ws))?;
+            println!("{}", json_output);
+        } else {
+            println!("Workspace '{from}' copied to '{to}'.");
+            println!("To activate it, run:");
+            println!("  export SEMTOOLS_WORKSPACE={to}");
+        }
+    }
+    #[cfg(not(feature = "workspace"))]
+    {
+        let _ = (from, to);
+        if json {
+            let error_output = ErrorOutput {
+                error: "workspace feature not enabled".to_string(),
+                error_type: "FeatureNotEnabled".to_string(),
+            };
+            let json_output = serde_json::to_string_pretty(&error_output)?;
+            eprintln!("{}", json_output);
+        } else {
+            println!("workspace feature not enabled");
+        }
+    }
+    Ok(())
+}
+
+/// The JSON output for a workspace, with 0 documents if its store can't be read
+#[cfg(feature = "workspace")]
+fn workspace_output(ws: &Workspace) -> WorkspaceOutput {
+    let total_documents = ws
+        .open_store_shared()
+        .and_then(|store| store.get_stats())
+        .map_or(0, |stats| stats.total_documents);
+
+    WorkspaceOutput {
+        schema_version: SCHEMA_VERSION,
+        name: ws.config.name.clone(),
+        root_dir: ws.config.root_dir.clone(),
+        total_documents,
+    }
+}
+
+/// A size in bytes in the binary units `parse_file_size` takes, e.g. `12.5M`
+#[cfg(feature = "workspace")]
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["K", "M", "G"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1}{}", UNITS[unit])
+}
+
+#[cfg(feature = "workspace")]
+fn print_index_settings(index: &IndexSettings, oversample_factor: usize) {
+    let hnsw_ef = index
+        .hnsw_ef
+        .map_or("default".to_string(), |ef| ef.to_string());
+    println!(
+        "Vectors: {} quantization, stored on {}",
+        index.quantization, index.storage
+    );
+    println!(
+        "HNSW: m={}, ef_construct={}, ef={hnsw_ef}; oversampling {oversample_factor}x",
+        index.hnsw_m, index.hnsw_ef_construct
+    );
+}
+
+/// Walk directories and embed every new or changed file into the workspace, so
+/// that later searches don't have to. Without roots, the roots indexed before
+/// are walked again.
+pub async fn workspace_index_cmd(
+    roots: Vec<String>,
+    filter: SearchFilter,
+    max_file_size: Option<u64>,
+    walk_options: WalkOptions,
+    json: bool,
+    workspace_name: Option<&str>,
+) -> Result<()> {
+    #[cfg(feature = "workspace")]
+    {
+        let _name = Workspace::active(workspace_name).context("No active workspace")?;
+        let mut ws = Workspace::open(workspace_name)?;
+
+        let reindex = roots.is_empty();
+        let roots = if reindex {
+            ws.config.indexed_roots.clone()
+        } else {
+            roots
+        };
+        if roots.is_empty() {
+            anyhow::bail!(
+                "Nothing to index. Run: workspace index <dirs...> (later runs without dirs re-index them)"
+            );
+        }
+        // Documents are stored under absolute paths, whichever directory this runs from
+        let roots = roots
+            .iter()
+            .map(|root| canonical_path(root))
+            .collect::<Result<Vec<_>>>()?;
+
+        let files = walk_files(&roots, &filter, walk_options)?;
+        let total = files.len();
+        let show_progress = !json && io::stderr().is_terminal();
+        if !json {
+            eprintln!("Indexing {total} files under {}...", roots.join(", "));
+        }
+
+        let model = StaticModel::from_pretrained(MODEL_NAME, None, None, None)?;
+        let config = SearchConfig {
+            max_file_size,
+            ..Default::default()
+        };
+        let outcome = index_workspace(&files, &model, &config, workspace_name, |done, outcome| {
+            if show_progress {
+                eprint!(
+                    "\r  {done}/{total} files, {} embedded, {} unchanged",
+                    outcome.embedded, outcome.unchanged
+                );
+            }
+        })?;
+        if show_progress && total > 0 {
+            eprintln!();
+        }
+
+        if reindex {
+            // Roots remembered by older versions may not be canonical yet
+            ws.config.indexed_roots.clear();
+        }
+        ws.config.add_indexed_roots(&roots);
+        ws.save()?;
+
+        if json {
+            let output = IndexOutput {
+                schema_version: SCHEMA_VERSION,
+                name: ws.config.name.clone(),
+                roots,
+                files_indexed: total,
+                files_embedded: outcome.embedded,
+                files_unchanged: outcome.unchanged,
+                lines_embedded: outcome.lines,
+                moved: outcome
+                    .moved
+                    .iter()
+                    .map(|(from, to)| MovedFileJSON {
+                        from: from.clone(),
+                        to: to.clone(),
+                    })
+                    .collect(),
+                skipped: outcome
+                    .skipped
+                    .iter()
+                    .map(|skipped_file| SkippedFileJSON {
+                        path: skipped_file.path.clone(),
+                        reason: skipped_file.reason.to_string(),
+                    })
+                    .collect(),
+            };
+            let json_output = serde_json::to_string_pretty(&output)?;
+            println!("{}", json_output);
+        } else {
             println!(
-                "Removed {} stale documents from workspace.",
-                missing_paths.len()
+                "Indexed {total} files into workspace '{}': {} new or changed ({} lines), {} unchanged",
+                ws.config.name, outcome.embedded, outcome.lines, outcome.unchanged
             );
+            if !outcome.moved.is_empty() {
+                println!("Moved {} files:", outcome.moved.len());
+                for (from, to) in &outcome.moved {
+                    println!("  {from} -> {to}");
+                }
+            }
+            if !outcome.skipped.is_empty() {
+                eprintln!("Skipped {} files:", outcome.skipped.len());
+                for skipped_file in &outcome.skipped {
+                    eprintln!("  {}: {}", skipped_file.path, skipped_file.reason);
+                }
+            }
         }
     }
     #[cfg(not(feature = "workspace"))]
     {
+        let _ = (roots, filter, max_file_size, walk_options, workspace_name);
         if json {
             let error_output = ErrorOutput {
                 error: "workspace feature not enabled".to_string(),
@@ -1,5 +1,10 @@
 use serde::Serialize;
 
+/// Version of the JSON output of `search`, `ask` and `workspace`.
+/// Bump when fields are renamed, removed or change meaning; adding fields is compatible.
+/// Version 2 uses 1-based inclusive line and column numbers throughout.
+pub const SCHEMA_VERSION: u32 = 2;
+
 // Parse
 #[derive(Debug, Serialize)]
 pub struct ParseResultJSON {
@@ -16,22 +21,86 @@ pub struct ParseOutput {
 // Search
 #[derive(Debug, Serialize)]
 pub struct SearchResultJSON {
-    pub filename: String,
-    pub start_line_number: usize,
-    pub end_line_number: usize,
-    pub match_line_number: usize,
-    pub distance: f64,
-    pub content: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>, // Original document when `path` is a `semtools parse` output
+    pub distance: f64, // Cosine distance, lower is better
+    pub score: f64,    // Cosine similarity (1 - distance), higher is better
+    #[serde(rename = "match")]
+    pub matched: MatchJSON,
+    pub context: ContextJSON,
+    // Context is from the workspace index, the file has changed since (byte offsets are 0)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool,
+}
+
+/// The matched line, or span of a long line
+#[derive(Debug, Serialize)]
+pub struct MatchJSON {
+    pub line: usize,         // 1-based
+    pub start_column: usize, // 1-based, inclusive, in characters
+    pub end_column: usize,   // 1-based, inclusive, in characters
+    pub byte_start: usize,   // 0-based offset into the file's text (as UTF-8)
+    pub byte_end: usize,     // Exclusive
+    pub text: String,
+}
+
+/// The lines around a match, as shown in text output
+#[derive(Debug, Serialize)]
+pub struct ContextJSON {
+    pub start_line: usize, // 1-based, inclusive
+    pub end_line: usize,   // 1-based, inclusive
+    pub text: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedFileJSON {
+    pub path: String,
+    pub reason: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct SearchOutput {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_id: Option<String>, // Set in batch mode (`--queries`)
     pub results: Vec<SearchResultJSON>,
+    pub skipped: Vec<SkippedFileJSON>, // Binary, too large or unreadable files
+}
+
+/// A single result on its own line (`--format jsonl`)
+#[derive(Debug, Serialize)]
+pub struct StreamedResultJSON<T> {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_id: Option<String>, // Set for the standing queries of `--follow`
+    #[serde(flatten)]
+    pub result: T,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentResultJSON {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub distance: f64,
+    pub score: f64,
+    pub best_match: SearchResultJSON,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DocumentSearchOutput {
+    pub schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_id: Option<String>, // Set in batch mode (`--queries`)
+    pub results: Vec<DocumentResultJSON>,
+    pub skipped: Vec<SkippedFileJSON>,
 }
 
 // Ask
 #[derive(Debug, Serialize)]
 pub struct AskOutput {
+    pub schema_version: u32,
     pub query: String,
     pub response: String,
     pub files_searched: Vec<String>,
@@ -40,15 +109,65 @@ pub struct AskOutput {
 // Workspace
 #[derive(Debug, Serialize)]
 pub struct WorkspaceOutput {
+    pub schema_version: u32,
     pub name: String,
     pub root_dir: String,
     pub total_documents: usize,
 }
 
+#[derive(Debug, Serialize)]
+pub struct WorkspaceListOutput {
+    pub schema_version: u32,
+    pub active: Option<String>, // Name of the active workspace, if any
+    pub workspaces: Vec<WorkspaceSummaryJSON>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceSummaryJSON {
+    pub name: String,
+    pub root_dir: String,
+    pub total_documents: Option<usize>, // null while the workspace is busy
+    pub total_lines: Option<usize>,     // null while the workspace is busy
+    pub disk_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigureOutput {
+    pub schema_version: u32,
+    pub name: String,
+    pub quantization: String, // "none", "scalar", "binary" or "product"
+    pub storage: String,      // "disk" or "memory"
+    pub hnsw_m: usize,
+    pub hnsw_ef_construct: usize,
+    pub hnsw_ef: Option<usize>, // null for Qdrant's default
+    pub oversample_factor: usize,
+}
+
 #[derive(Debug, Serialize)]
 pub struct PruneOutput {
+    pub schema_version: u32,
     pub files_removed: usize,
     pub files_remaining: usize,
+    pub lines_removed: usize, // Line embeddings past the end of, or no longer part of, a document
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexOutput {
+    pub schema_version: u32,
+    pub name: String,
+    pub roots: Vec<String>,     // Directories that were walked
+    pub files_indexed: usize,   // Files found under the roots
+    pub files_embedded: usize,  // New or changed files that were (re-)embedded
+    pub files_unchanged: usize, // Files already up to date in the workspace
+    pub lines_embedded: usize,
+    pub moved: Vec<MovedFileJSON>, // Files whose content was found under a path that is gone
+    pub skipped: Vec<SkippedFileJSON>, // Binary, too large or unreadable files
+}
+
+#[derive(Debug, Serialize)]
+pub struct MovedFileJSON {
+    pub from: String,
+    pub to: String,
 }
 
 // Error output
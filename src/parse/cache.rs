@@ -10,6 +10,8 @@ pub struct FileMetadata {
     pub modified_time: u64,
     pub size: u64,
     pub parsed_path: String,
+    #[serde(default)]
+    pub source_path: String, // Absolute path of the parsed document, reported by search
 }
 
 pub struct CacheManager {
@@ -88,6 +90,7 @@ impl CacheManager {
             modified_time,
             size: metadata.len(),
             parsed_path: String::new(), // Will be set later
+            source_path: String::new(),
         })
     }
 
@@ -123,6 +126,10 @@ impl CacheManager {
             modified_time,
             size: file_metadata.len(),
             parsed_path: parsed_path.to_string_lossy().to_string(),
+            source_path: fs::canonicalize(path)
+                .unwrap_or_else(|_| path.to_path_buf())
+                .to_string_lossy()
+                .to_string(),
         };
 
         fs::write(metadata_path, serde_json::to_string_pretty(&metadata)?)?;
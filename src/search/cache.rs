@@ -0,0 +1,417 @@
+//! Content-hash keyed cache of line embeddings for searches outside of a workspace.
+//!
+//! Entries live on disk under `~/.semtools/cache/embeddings`, one file per
+//! (model, case mode, content) digest, so repeated searches over unchanged files
+//! skip re-embedding. The least recently used entries are removed once the
+//! directory outgrows its size limit. Long-running sessions such as `ask` can
+//! additionally keep entries in memory.
+use anyhow::Result;
+use model2vec_rs::model::StaticModel;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use super::MODEL_NAME;
+use super::text::parse_file_size;
+
+/// Bump when the on-disk entry format changes
+const CACHE_FORMAT_VERSION: u32 = 2;
+
+/// Set to `0` or `off` to disable the on-disk cache
+const CACHE_ENV_VAR: &str = "SEMTOOLS_EMBEDDING_CACHE";
+
+/// Most space the on-disk cache takes, e.g. `512M` or `2G`
+const CACHE_MAX_SIZE_ENV_VAR: &str = "SEMTOOLS_EMBEDDING_CACHE_MAX_SIZE";
+
+/// Most space the on-disk cache takes when the environment doesn't say
+pub const DEFAULT_MAX_DISK_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Most space entries kept in memory take
+const MAX_MEMORY_BYTES: usize = 256 * 1024 * 1024;
+
+/// The directory is trimmed each time this fraction of its limit has been written
+const TRIM_INTERVAL_FRACTION: u64 = 16;
+
+/// SHA-256 of an entry's model, case mode and content, also stored in the entry
+type CacheKey = [u8; 32];
+
+type Embeddings = Arc<Vec<Vec<f32>>>;
+
+static GLOBAL_CACHE: OnceLock<EmbeddingCache> = OnceLock::new();
+
+// Distinguishes temporary files of concurrent writers within one process
+static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub struct EmbeddingCache {
+    dir: Option<PathBuf>,
+    max_disk_bytes: u64,
+    written: AtomicU64, // Bytes of entries written since the directory was last trimmed
+    trimmed: AtomicBool,
+    retain_in_memory: AtomicBool,
+    memory: Mutex<MemoryEntries>,
+}
+
+impl EmbeddingCache {
+    pub fn new(dir: Option<PathBuf>, max_disk_bytes: u64) -> Self {
+        if let Some(dir) = &dir {
+            // A cache that can't be written is just a cache that always misses
+            let _ = fs::create_dir_all(dir);
+        }
+        Self {
+            dir,
+            max_disk_bytes,
+            written: AtomicU64::new(0),
+            trimmed: AtomicBool::new(false),
+            retain_in_memory: AtomicBool::new(false),
+            memory: Mutex::new(MemoryEntries::default()),
+        }
+    }
+
+    /// The process-wide cache used by `search_files` and `rank_files`.
+    /// Unit tests never write to the user's cache directory.
+    pub fn global() -> &'static EmbeddingCache {
+        GLOBAL_CACHE.get_or_init(|| {
+            let enabled = !cfg!(test)
+                && std::env::var(CACHE_ENV_VAR)
+                    .map(|v| !matches!(v.to_lowercase().as_str(), "0" | "off" | "false"))
+                    .unwrap_or(true);
+            let dir = if enabled { Self::default_dir() } else { None };
+            let max_disk_bytes = std::env::var(CACHE_MAX_SIZE_ENV_VAR)
+                .ok()
+                .and_then(|size| parse_file_size(&size).ok())
+                .unwrap_or(DEFAULT_MAX_DISK_BYTES);
+            Self::new(dir, max_disk_bytes)
+        })
+    }
+
+    pub fn default_dir() -> Option<PathBuf> {
+        dirs::home_dir().map(|home| home.join(".semtools").join("cache").join("embeddings"))
+    }
+
+    /// Keep entries in memory as well, for sessions that search the same files repeatedly
+    pub fn retain_in_memory(&self, enabled: bool) {
+        self.retain_in_memory.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.memory.lock().unwrap().clear();
+        }
+    }
+
+    /// Line embeddings for `lines`, from the cache when the same content was embedded before
+    pub fn embed(&self, lines: &[String], model: &StaticModel, ignore_case: bool) -> Embeddings {
+        let key = cache_key(lines, ignore_case);
+        let retain_in_memory = self.retain_in_memory.load(Ordering::Relaxed);
+
+        if retain_in_memory && let Some(embeddings) = self.memory.lock().unwrap().get(&key) {
+            return embeddings;
+        }
+
+        let path = self.entry_path(&key);
+        let from_disk = path
+            .as_ref()
+            .and_then(|path| read_entry(path, &key))
+            .filter(|embeddings| embeddings.len() == lines.len());
+
+        let embeddings = Arc::new(match from_disk {
+            Some(embeddings) => {
+                // Recently used entries are the last to be evicted
+                if let Some(path) = &path {
+                    let _ = fs::File::options()
+                        .write(true)
+                        .open(path)
+                        .and_then(|file| file.set_modified(SystemTime::now()));
+                }
+                embeddings
+            }
+            None => {
+                let lines_for_embedding: Vec<String> = if ignore_case {
+                    lines.iter().map(|s| s.to_lowercase()).collect()
+                } else {
+                    lines.to_vec()
+                };
+                let embeddings = model.encode_with_args(&lines_for_embedding, Some(2048), 16384);
+                if let Some(path) = &path
+                    && let Ok(size) = write_entry(path, &key, &embeddings)
+                {
+                    self.record_write(size);
+                }
+                embeddings
+            }
+        });
+
+        if retain_in_memory {
+            self.memory
+                .lock()
+                .unwrap()
+                .insert(key, embeddings.clone(), MAX_MEMORY_BYTES);
+        }
+        embeddings
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.bin", hex::encode(key))))
+    }
+
+    /// Trim the directory on the first write, then each time enough has been written
+    fn record_write(&self, bytes: u64) {
+        let written = self.written.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let first = !self.trimmed.swap(true, Ordering::Relaxed);
+        if first || written >= self.max_disk_bytes / TRIM_INTERVAL_FRACTION {
+            self.written.store(0, Ordering::Relaxed);
+            if let Some(dir) = &self.dir {
+                let _ = evict(dir, self.max_disk_bytes);
+            }
+        }
+    }
+}
+
+/// Entries kept in memory, up to a total size, evicting the least recently used
+#[derive(Default)]
+struct MemoryEntries {
+    entries: HashMap<CacheKey, (Embeddings, u64)>, // With when they were last used
+    bytes: usize,
+    clock: u64,
+}
+
+impl MemoryEntries {
+    fn get(&mut self, key: &CacheKey) -> Option<Embeddings> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|(embeddings, used)| {
+            *used = clock;
+            embeddings.clone()
+        })
+    }
+
+    fn insert(&mut self, key: CacheKey, embeddings: Embeddings, max_bytes: usize) {
+        let size = embeddings_size(&embeddings);
+        if size > max_bytes {
+            return;
+        }
+        self.clock += 1;
+        if let Some((replaced, _)) = self.entries.insert(key, (embeddings, self.clock)) {
+            self.bytes -= embeddings_size(&replaced);
+        }
+        self.bytes += size;
+
+        while self.bytes > max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.bytes -= embeddings_size(&evicted);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+fn embeddings_size(embeddings: &[Vec<f32>]) -> usize {
+    embeddings
+        .iter()
+        .map(|embedding| embedding.len() * size_of::<f32>())
+        .sum()
+}
+
+/// Remove the least recently used files until the directory holds at most `max_bytes`
+fn evict(dir: &Path, max_bytes: u64) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    let mut total: u64 = files.iter().map(|(_, size, _)| size).sum();
+    if total <= max_bytes {
+        return Ok(());
+    }
+
+    files.sort_by_key(|(modified, _, _)| *modified);
+    for (_, size, path) in files {
+        if total <= max_bytes {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= size;
+        }
+    }
+    Ok(())
+}
+
+fn cache_key(lines: &[String], ignore_case: bool) -> CacheKey {
+    let mut hasher = Sha256::new();
+    hasher.update(CACHE_FORMAT_VERSION.to_le_bytes());
+    hasher.update(MODEL_NAME.as_bytes());
+    hasher.update([ignore_case as u8]);
+    for line in lines {
+        hasher.update((line.len() as u64).to_le_bytes());
+        hasher.update(line.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+// Entry layout: key (32 bytes), row count (u32), dimension (u32), then rows of
+// little-endian f32. Returns the number of bytes written.
+fn write_entry(path: &Path, key: &CacheKey, embeddings: &[Vec<f32>]) -> Result<u64> {
+    let dim = embeddings.first().map(|e| e.len()).unwrap_or(0);
+    let mut bytes = Vec::with_capacity(key.len() + 8 + embeddings.len() * dim * 4);
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(&(embeddings.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(dim as u32).to_le_bytes());
+    for embedding in embeddings {
+        for value in embedding {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    // Write then rename so concurrent searches never see a partial entry
+    let tmp_path = path.with_extension(format!(
+        "tmp{}-{}",
+        std::process::id(),
+        TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let size = bytes.len() as u64;
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(size)
+}
+
+/// The embeddings of an entry, or None when it is damaged or holds another key
+fn read_entry(path: &Path, key: &CacheKey) -> Option<Vec<Vec<f32>>> {
+    let bytes = fs::read(path).ok()?;
+    let (stored_key, bytes) = bytes.split_at_checked(key.len())?;
+    if stored_key != key {
+        return None;
+    }
+    let rows = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+    let dim = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?) as usize;
+    let data = bytes.get(8..)?;
+    if data.len() != rows * dim * 4 {
+        return None;
+    }
+
+    let values: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect();
+    Some(if dim == 0 {
+        vec![Vec::new(); rows]
+    } else {
+        values.chunks(dim).map(|row| row.to_vec()).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("entry.bin");
+        let embeddings = vec![vec![0.5, -1.0, 2.0], vec![0.0, 0.25, 3.5]];
+        let key = cache_key(&["a".to_string(), "b".to_string()], false);
+
+        let size = write_entry(&path, &key, &embeddings).unwrap();
+        assert_eq!(size, fs::metadata(&path).unwrap().len());
+        assert_eq!(read_entry(&path, &key), Some(embeddings));
+
+        // An entry stored under another key is a miss, even in the right file
+        let other_key = cache_key(&["c".to_string(), "d".to_string()], false);
+        assert_eq!(read_entry(&path, &other_key), None);
+
+        // Truncated entries are treated as misses
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+        assert_eq!(read_entry(&path, &key), None);
+    }
+
+    #[test]
+    fn test_evict_removes_least_recently_used_entries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        for (age, name) in [(30, "old.bin"), (20, "used.bin"), (10, "new.bin")] {
+            let path = temp_dir.path().join(name);
+            fs::write(&path, [0u8; 100]).unwrap();
+            let modified = now - std::time::Duration::from_secs(age);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(modified))
+                .unwrap();
+        }
+
+        evict(temp_dir.path(), 300).unwrap();
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 3);
+
+        evict(temp_dir.path(), 250).unwrap();
+        assert!(!temp_dir.path().join("old.bin").exists());
+        assert!(temp_dir.path().join("used.bin").exists());
+        assert!(temp_dir.path().join("new.bin").exists());
+    }
+
+    #[test]
+    fn test_memory_entries_are_bounded() {
+        let mut memory = MemoryEntries::default();
+        let entry = |value: f32| Arc::new(vec![vec![value; 4]]); // 16 bytes
+        let key = |byte: u8| [byte; 32];
+
+        memory.insert(key(1), entry(1.0), 40);
+        memory.insert(key(2), entry(2.0), 40);
+        assert!(memory.get(&key(1)).is_some()); // Now more recently used than 2
+        memory.insert(key(3), entry(3.0), 40);
+
+        assert!(memory.get(&key(2)).is_none());
+        assert!(memory.get(&key(1)).is_some());
+        assert!(memory.get(&key(3)).is_some());
+        assert_eq!(memory.bytes, 32);
+
+        // An entry larger than the limit isn't kept at all
+        memory.insert(key(4), Arc::new(vec![vec![0.0; 20]]), 40);
+        assert!(memory.get(&key(4)).is_none());
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_content_and_case() {
+        let lines = vec!["Hello".to_string(), "world".to_string()];
+        let joined = vec!["Hello\nworld".to_string()];
+
+        assert_eq!(cache_key(&lines, false), cache_key(&lines.clone(), false));
+        assert_ne!(cache_key(&lines, false), cache_key(&lines, true));
+        assert_ne!(cache_key(&lines, false), cache_key(&joined, false));
+    }
+
+    #[test]
+    fn test_embed_writes_and_reuses_disk_entries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let model = StaticModel::from_pretrained(MODEL_NAME, None, None, None)
+            .expect("Failed to load model for tests");
+        let cache =
+            EmbeddingCache::new(Some(temp_dir.path().to_path_buf()), DEFAULT_MAX_DISK_BYTES);
+        let lines = vec!["first line".to_string(), "second line".to_string()];
+
+        let embedded = cache.embed(&lines, &model, false);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+        // A fresh cache over the same directory reads the entry back
+        let reopened =
+            EmbeddingCache::new(Some(temp_dir.path().to_path_buf()), DEFAULT_MAX_DISK_BYTES);
+        assert_eq!(reopened.embed(&lines, &model, false), embedded);
+    }
+}
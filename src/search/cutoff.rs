@@ -0,0 +1,323 @@
+//! Cutoffs that adapt to the scores of a particular query, as an alternative
+//! to an absolute `--max-distance`
+use anyhow::Result;
+use std::fmt::Write;
+
+/// How many of the best distances are looked at when searching for an elbow,
+/// so that a sparse tail of unrelated lines doesn't hide it
+pub const ELBOW_WINDOW: usize = 200;
+
+/// Number of buckets in the histogram printed by `--explain`
+const HISTOGRAM_BUCKETS: usize = 10;
+
+/// Width of the longest histogram bar
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// Z-score used when suggesting a cutoff in `--explain`
+const SUGGESTED_Z_SCORE: f64 = 2.0;
+
+/// A cutoff that is worked out from the distances of all scored candidates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cutoff {
+    /// Keep results within this distance of the best result
+    Within(f64),
+    /// Keep results scoring at or above this percentile (0-100) of all candidates
+    Percentile(f64),
+    /// Keep results before the largest jump in distance among the best candidates
+    Elbow,
+    /// Keep results at least this many standard deviations closer than the mean
+    ZScore(f64),
+}
+
+impl Cutoff {
+    /// Parse an automatic cutoff method: 'elbow' or 'zscore'
+    pub fn parse(method: &str, z_score: f64) -> Result<Self> {
+        match method.to_lowercase().as_str() {
+            "elbow" => Ok(Self::Elbow),
+            "zscore" | "z-score" => Ok(Self::ZScore(z_score)),
+            _ => Err(anyhow::anyhow!(
+                "Invalid cutoff: '{}'. Must be 'elbow' or 'zscore'",
+                method
+            )),
+        }
+    }
+
+    pub fn percentile(percentile: f64) -> Result<Self> {
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(anyhow::anyhow!(
+                "Invalid percentile: {}. Must be between 0 and 100",
+                percentile
+            ));
+        }
+        Ok(Self::Percentile(percentile))
+    }
+
+    /// The largest distance that passes the cutoff (inclusive), None when nothing was scored
+    pub fn threshold(&self, distribution: &ScoreDistribution) -> Option<f64> {
+        match *self {
+            Self::Within(delta) => distribution.best().map(|best| best + delta),
+            Self::Percentile(percentile) => distribution.percentile(percentile),
+            Self::Elbow => distribution.elbow(),
+            Self::ZScore(z) => distribution
+                .mean_and_std()
+                .map(|(mean, std)| mean - z * std),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            Self::Within(delta) => format!("within {delta} of the best distance"),
+            Self::Percentile(percentile) => format!("scores at or above percentile {percentile}"),
+            Self::Elbow => "before the largest jump in distance".to_string(),
+            Self::ZScore(z) => format!("{z} standard deviations closer than the mean"),
+        }
+    }
+}
+
+/// Distances of every scored candidate (lines or documents), sorted best first
+#[derive(Debug, Clone, Default)]
+pub struct ScoreDistribution {
+    distances: Vec<f64>,
+}
+
+impl ScoreDistribution {
+    pub fn new(mut distances: Vec<f64>) -> Self {
+        distances.retain(|distance| !distance.is_nan());
+        distances.sort_by(|a, b| a.total_cmp(b));
+        Self { distances }
+    }
+
+    pub fn len(&self) -> usize {
+        self.distances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.distances.is_empty()
+    }
+
+    pub fn distances(&self) -> &[f64] {
+        &self.distances
+    }
+
+    pub fn best(&self) -> Option<f64> {
+        self.distances.first().copied()
+    }
+
+    /// Distance at the given percentile of scores: the best `100 - percentile`
+    /// percent of candidates (and always at least one) are at or below it
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        if self.distances.is_empty() {
+            return None;
+        }
+        let fraction = (100.0 - percentile).clamp(0.0, 100.0) / 100.0;
+        let kept = ((self.distances.len() as f64 * fraction).ceil() as usize).max(1);
+        Some(self.distances[kept.min(self.distances.len()) - 1])
+    }
+
+    pub fn mean_and_std(&self) -> Option<(f64, f64)> {
+        if self.distances.is_empty() {
+            return None;
+        }
+        let count = self.distances.len() as f64;
+        let mean = self.distances.iter().sum::<f64>() / count;
+        let variance = self
+            .distances
+            .iter()
+            .map(|distance| (distance - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        Some((mean, variance.sqrt()))
+    }
+
+    /// Distance of the last candidate before the largest gap among the best ones
+    pub fn elbow(&self) -> Option<f64> {
+        let window = &self.distances[..self.distances.len().min(ELBOW_WINDOW)];
+        let first = *window.first()?;
+        let elbow_idx = window
+            .windows(2)
+            .enumerate()
+            .max_by(|(_, a), (_, b)| (a[1] - a[0]).total_cmp(&(b[1] - b[0])))
+            .map(|(idx, _)| idx);
+        Some(elbow_idx.map_or(first, |idx| window[idx]))
+    }
+
+    /// How many candidates are at or below the threshold
+    pub fn count_within(&self, threshold: f64) -> usize {
+        self.distances
+            .partition_point(|&distance| distance <= threshold)
+    }
+
+    /// Candidate counts in equal-width distance buckets between the best and worst distance
+    fn histogram(&self, buckets: usize) -> Vec<(f64, f64, usize)> {
+        let (Some(&min), Some(&max)) = (self.distances.first(), self.distances.last()) else {
+            return Vec::new();
+        };
+        let width = ((max - min) / buckets as f64).max(f64::EPSILON);
+        let mut counts = vec![0; buckets];
+        for distance in &self.distances {
+            let bucket = (((distance - min) / width) as usize).min(buckets - 1);
+            counts[bucket] += 1;
+        }
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| (min + i as f64 * width, min + (i + 1) as f64 * width, count))
+            .collect()
+    }
+}
+
+/// Human readable summary of a distribution, with suggested thresholds and the
+/// effect of the cutoffs in use, as printed by `--explain`
+pub fn explain(
+    distribution: &ScoreDistribution,
+    unit: &str,
+    max_distance: Option<f64>,
+    cutoff: Option<Cutoff>,
+) -> String {
+    let mut out = String::new();
+    let (Some(best), Some((mean, std))) = (distribution.best(), distribution.mean_and_std()) else {
+        let _ = writeln!(out, "No {unit} were scored");
+        return out;
+    };
+
+    let _ = writeln!(
+        out,
+        "Distance distribution over {} {unit} (lower is more similar):",
+        distribution.len()
+    );
+    let _ = writeln!(out, "  best {best:.4}  mean {mean:.4}  std {std:.4}");
+    let percentiles: Vec<String> = [99.9, 99.0, 90.0, 50.0]
+        .iter()
+        .filter_map(|&p| distribution.percentile(p).map(|d| format!("p{p} {d:.4}")))
+        .collect();
+    let _ = writeln!(out, "  {}", percentiles.join("  "));
+
+    let histogram = distribution.histogram(HISTOGRAM_BUCKETS);
+    let largest = histogram
+        .iter()
+        .map(|(_, _, count)| *count)
+        .max()
+        .unwrap_or(0);
+    for (start, end, count) in histogram {
+        let bar_len = (count * HISTOGRAM_WIDTH).div_ceil(largest.max(1));
+        let _ = writeln!(
+            out,
+            "  {start:.3}-{end:.3} {:<width$} {count}",
+            "#".repeat(bar_len),
+            width = HISTOGRAM_WIDTH
+        );
+    }
+
+    let _ = writeln!(out, "Suggested thresholds:");
+    let suggestions = [
+        Cutoff::Elbow,
+        Cutoff::ZScore(SUGGESTED_Z_SCORE),
+        Cutoff::Percentile(99.0),
+    ];
+    for suggestion in suggestions {
+        if let Some(threshold) = suggestion.threshold(distribution) {
+            let _ = writeln!(
+                out,
+                "  --max-distance {:.4}  keeps {} ({})",
+                next_distance(threshold),
+                distribution.count_within(threshold),
+                suggestion.describe()
+            );
+        }
+    }
+
+    if let Some(max_distance) = max_distance {
+        let _ = writeln!(
+            out,
+            "Applied --max-distance {max_distance}: {} {unit} below it",
+            distribution
+                .distances
+                .partition_point(|&d| d < max_distance)
+        );
+    }
+    if let Some(cutoff) = cutoff
+        && let Some(threshold) = cutoff.threshold(distribution)
+    {
+        let _ = writeln!(
+            out,
+            "Applied cutoff ({}): distance <= {threshold:.4}, {} {unit}",
+            cutoff.describe(),
+            distribution.count_within(threshold)
+        );
+    }
+
+    out
+}
+
+/// The smallest printed `--max-distance` (exclusive) that still keeps `threshold` (inclusive)
+fn next_distance(threshold: f64) -> f64 {
+    (threshold * 10_000.0).floor() / 10_000.0 + 0.0001
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distribution(distances: &[f64]) -> ScoreDistribution {
+        ScoreDistribution::new(distances.to_vec())
+    }
+
+    #[test]
+    fn test_within_is_relative_to_best() {
+        let scores = distribution(&[0.5, 0.2, 0.25, 0.9]);
+        let threshold = Cutoff::Within(0.1).threshold(&scores).unwrap();
+        assert!((threshold - 0.3).abs() < 1e-9);
+        assert_eq!(scores.count_within(threshold), 2);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let scores = distribution(&(1..=100).map(|i| i as f64 / 100.0).collect::<Vec<_>>());
+        assert_eq!(scores.percentile(95.0), Some(0.05));
+        assert_eq!(scores.percentile(100.0), Some(0.01)); // Always keeps the best one
+        assert_eq!(scores.percentile(0.0), Some(1.0));
+        assert!(Cutoff::percentile(101.0).is_err());
+        assert_eq!(distribution(&[]).percentile(50.0), None);
+    }
+
+    #[test]
+    fn test_elbow_finds_largest_gap() {
+        let scores = distribution(&[0.10, 0.12, 0.13, 0.40, 0.42, 0.45]);
+        assert_eq!(scores.elbow(), Some(0.13));
+        assert_eq!(distribution(&[0.3]).elbow(), Some(0.3));
+        assert_eq!(distribution(&[]).elbow(), None);
+    }
+
+    #[test]
+    fn test_zscore() {
+        let scores = distribution(&[0.1, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5]);
+        let (mean, std) = scores.mean_and_std().unwrap();
+        assert!((mean - 0.46).abs() < 1e-9);
+        assert!((std - 0.12).abs() < 1e-9);
+
+        let threshold = Cutoff::ZScore(2.0).threshold(&scores).unwrap();
+        assert_eq!(scores.count_within(threshold), 1);
+    }
+
+    #[test]
+    fn test_cutoff_parse() {
+        assert_eq!(Cutoff::parse("elbow", 2.0).unwrap(), Cutoff::Elbow);
+        assert_eq!(Cutoff::parse("ZScore", 1.5).unwrap(), Cutoff::ZScore(1.5));
+        assert!(Cutoff::parse("knee", 2.0).is_err());
+    }
+
+    #[test]
+    fn test_explain_mentions_suggestions_and_applied_cutoff() {
+        let scores = distribution(&[0.10, 0.12, 0.13, 0.40, 0.42, 0.45]);
+        let text = explain(&scores, "lines", Some(0.3), Some(Cutoff::Elbow));
+        assert!(text.contains("over 6 lines"));
+        assert!(text.contains("--max-distance 0.1301  keeps 3"));
+        assert!(text.contains("Applied --max-distance 0.3: 3 lines"));
+        assert!(text.contains("Applied cutoff"));
+
+        assert_eq!(
+            explain(&distribution(&[]), "lines", None, None),
+            "No lines were scored\n"
+        );
+    }
+}
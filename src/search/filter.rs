@@ -0,0 +1,281 @@
+//! Regex and path filters that narrow a search before anything is scored
+use anyhow::{Result, anyhow};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::{Regex, RegexBuilder};
+use serde_json::{Map, Value};
+
+/// File types accepted by `--type`, with the globs they stand for
+pub const FILE_TYPES: &[(&str, &[&str])] = &[
+    ("c", &["*.c", "*.h"]),
+    ("cpp", &["*.cpp", "*.cc", "*.cxx", "*.hpp", "*.hh", "*.hxx"]),
+    ("csv", &["*.csv", "*.tsv"]),
+    ("go", &["*.go"]),
+    ("html", &["*.html", "*.htm"]),
+    ("java", &["*.java"]),
+    ("js", &["*.js", "*.jsx", "*.mjs", "*.cjs"]),
+    ("json", &["*.json", "*.jsonl"]),
+    ("log", &["*.log"]),
+    ("md", &["*.md", "*.markdown", "*.mdx"]),
+    ("py", &["*.py", "*.pyi"]),
+    ("rst", &["*.rst"]),
+    ("rust", &["*.rs"]),
+    ("sh", &["*.sh", "*.bash", "*.zsh"]),
+    ("tex", &["*.tex"]),
+    ("toml", &["*.toml"]),
+    ("ts", &["*.ts", "*.tsx"]),
+    ("txt", &["*.txt"]),
+    ("xml", &["*.xml"]),
+    ("yaml", &["*.yaml", "*.yml"]),
+];
+
+/// Which files and lines a search may return
+#[derive(Debug, Default, Clone)]
+pub struct SearchFilter {
+    line_regex: Option<Regex>,
+    include: Option<GlobSet>, // Files must match one of these, when set
+    exclude: Option<GlobSet>, // Files matching any of these are left out
+    metadata: Vec<(String, String)>, // `key=value` conditions on the metadata of stdin records
+}
+
+impl SearchFilter {
+    /// Build a filter from command line style options. `globs` and `types` both
+    /// select files to include; `excludes` wins over them.
+    pub fn new(
+        line_regex: Option<&str>,
+        globs: &[String],
+        excludes: &[String],
+        types: &[String],
+        ignore_case: bool,
+    ) -> Result<Self> {
+        let line_regex = line_regex
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(ignore_case)
+                    .build()
+                    .map_err(|e| anyhow!("Invalid filter regex '{}': {}", pattern, e))
+            })
+            .transpose()?;
+
+        let mut include: Vec<String> = globs.to_vec();
+        for name in types {
+            include.extend(type_globs(name)?.iter().map(|glob| glob.to_string()));
+        }
+
+        Ok(Self {
+            line_regex,
+            include: build_glob_set(&include)?,
+            exclude: build_glob_set(excludes)?,
+            metadata: Vec::new(),
+        })
+    }
+
+    /// Also require `key=value` metadata, for documents that have it (stdin records)
+    pub fn with_metadata(mut self, conditions: &[String]) -> Result<Self> {
+        for condition in conditions {
+            let (key, value) = condition
+                .split_once('=')
+                .filter(|(key, _)| !key.trim().is_empty())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid metadata filter: '{}'. Expected <key>=<value>",
+                        condition
+                    )
+                })?;
+            self.metadata
+                .push((key.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.line_regex.is_none()
+            && self.include.is_none()
+            && self.exclude.is_none()
+            && self.metadata.is_empty()
+    }
+
+    pub fn has_line_regex(&self) -> bool {
+        self.line_regex.is_some()
+    }
+
+    /// Whether a file passes the glob and type filters
+    pub fn matches_path(&self, path: &str) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+        self.include.as_ref().is_none_or(|set| set.is_match(path))
+            && self.exclude.as_ref().is_none_or(|set| !set.is_match(path))
+    }
+
+    /// Whether a line passes the regex filter
+    pub fn matches_line(&self, line: &str) -> bool {
+        self.line_regex
+            .as_ref()
+            .is_none_or(|regex| regex.is_match(line))
+    }
+
+    /// Whether a document's metadata satisfies every `key=value` condition.
+    /// Strings are compared as is, other values by their JSON text, and a
+    /// list matches when any of its items does.
+    pub fn matches_metadata(&self, metadata: &Map<String, Value>) -> bool {
+        self.metadata.iter().all(|(key, expected)| {
+            metadata
+                .get(key)
+                .is_some_and(|value| value_matches(value, expected))
+        })
+    }
+
+    /// The files that pass the glob and type filters, in their original order
+    pub fn filter_paths(&self, paths: &[String]) -> Vec<String> {
+        paths
+            .iter()
+            .filter(|path| self.matches_path(path))
+            .cloned()
+            .collect()
+    }
+}
+
+fn value_matches(value: &Value, expected: &str) -> bool {
+    match value {
+        Value::String(text) => text == expected,
+        Value::Array(items) => items.iter().any(|item| value_matches(item, expected)),
+        // Numbers, booleans and null match as written, e.g. `year=2024`
+        other => other.to_string().as_str() == expected,
+    }
+}
+
+/// Globs for a `--type` name
+fn type_globs(name: &str) -> Result<&'static [&'static str]> {
+    let name = name.to_lowercase();
+    let name = match name.as_str() {
+        "markdown" => "md",
+        "python" => "py",
+        "rs" => "rust",
+        "javascript" => "js",
+        "typescript" => "ts",
+        "text" => "txt",
+        "yml" => "yaml",
+        other => other,
+    };
+    FILE_TYPES
+        .iter()
+        .find(|(type_name, _)| *type_name == name)
+        .map(|(_, globs)| *globs)
+        .ok_or_else(|| {
+            let known: Vec<&str> = FILE_TYPES.iter().map(|(type_name, _)| *type_name).collect();
+            anyhow!(
+                "Unknown file type: '{}'. Must be one of: {}",
+                name,
+                known.join(", ")
+            )
+        })
+}
+
+/// Compile globs into a set, or None when there are none. A glob without a `/`
+/// matches file names in any directory, like in a `.gitignore`; one with a `/`
+/// is matched against the whole path, with `*` not crossing directories.
+fn build_glob_set(globs: &[String]) -> Result<Option<GlobSet>> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        let pattern = glob.strip_prefix("./").unwrap_or(glob);
+        let pattern = if pattern.contains('/') {
+            pattern.to_string()
+        } else {
+            format!("**/{pattern}")
+        };
+        let compiled = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| anyhow!("Invalid glob '{}': {}", glob, e))?;
+        builder.add(compiled);
+    }
+    Ok(Some(builder.build()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        let filter = SearchFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches_path("anything/at/all.bin"));
+        assert!(filter.matches_line("any line"));
+    }
+
+    #[test]
+    fn test_globs_and_excludes() {
+        let filter = SearchFilter::new(
+            None,
+            &strings(&["docs/**/*.md", "*.txt"]),
+            &strings(&["drafts/**"]),
+            &[],
+            false,
+        )
+        .unwrap();
+
+        assert!(filter.matches_path("docs/guide/retries.md"));
+        assert!(filter.matches_path("./docs/index.md"));
+        assert!(filter.matches_path("notes/todo.txt"));
+        assert!(!filter.matches_path("src/retries.md"));
+        assert!(!filter.matches_path("drafts/todo.txt"));
+
+        let paths = strings(&["a.txt", "b.rs", "docs/c.md"]);
+        assert_eq!(
+            filter.filter_paths(&paths),
+            strings(&["a.txt", "docs/c.md"])
+        );
+    }
+
+    #[test]
+    fn test_types() {
+        let filter =
+            SearchFilter::new(None, &[], &[], &strings(&["markdown", "rust"]), false).unwrap();
+        assert!(filter.matches_path("README.md"));
+        assert!(filter.matches_path("src/lib.rs"));
+        assert!(!filter.matches_path("setup.py"));
+
+        assert!(SearchFilter::new(None, &[], &[], &strings(&["cobol"]), false).is_err());
+    }
+
+    #[test]
+    fn test_line_regex() {
+        let filter = SearchFilter::new(Some("timeout|timed out"), &[], &[], &[], true).unwrap();
+        assert!(filter.has_line_regex());
+        assert!(filter.matches_line("Retry after a TIMEOUT"));
+        assert!(filter.matches_line("the request timed out"));
+        assert!(!filter.matches_line("retry with backoff"));
+
+        assert!(SearchFilter::new(Some("(unclosed"), &[], &[], &[], false).is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let filter = SearchFilter::default()
+            .with_metadata(&strings(&["team=auth", "priority=1"]))
+            .unwrap();
+        assert!(!filter.is_empty());
+
+        let metadata = |json: &str| match serde_json::from_str(json).unwrap() {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        };
+        assert!(filter.matches_metadata(&metadata(r#"{"team": "auth", "priority": 1}"#)));
+        assert!(filter.matches_metadata(&metadata(r#"{"team": ["ops", "auth"], "priority": 1}"#)));
+        assert!(!filter.matches_metadata(&metadata(r#"{"team": "ops", "priority": 1}"#)));
+        assert!(!filter.matches_metadata(&metadata(r#"{"team": "auth"}"#)));
+
+        assert!(
+            SearchFilter::default()
+                .with_metadata(&strings(&["team"]))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! Matching the lines of a stream (`tail -f app.log`, `journalctl -f`) against
+//! standing queries as they arrive, instead of reading all of the input first
+use model2vec_rs::model::StaticModel;
+use simsimd::SpatialSimilarity;
+use std::collections::VecDeque;
+
+use super::segment::{LineSegment, display_line, segment_lines};
+use super::{SearchConfig, SearchResult, is_near_negative, matched_part, passes_thresholds};
+
+/// A query that every incoming line is compared against
+pub struct StandingQuery {
+    pub id: Option<String>,
+    pub embedding: Vec<f32>,
+    pub config: SearchConfig, // Threshold, context lines, negatives and line filter of this query
+}
+
+/// A line that matched a standing query, with the context that was available for it
+pub struct FollowMatch {
+    pub query_id: Option<String>,
+    pub result: SearchResult,
+}
+
+/// A match that is still waiting for its after-context lines
+struct PendingMatch {
+    query: usize,
+    result: SearchResult,
+    remaining: usize,
+}
+
+/// Compares the lines of a stream against standing queries as they are pushed
+pub struct StreamMatcher {
+    filename: String,
+    queries: Vec<StandingQuery>,
+    context: usize,             // Most lines of context any query wants
+    recent: VecDeque<String>,   // The last `context` lines, as before-context of the next match
+    pending: Vec<PendingMatch>, // In the order they matched
+    line_count: usize,
+    byte_offset: usize,
+}
+
+impl StreamMatcher {
+    pub fn new(filename: &str, queries: Vec<StandingQuery>) -> Self {
+        let context = queries
+            .iter()
+            .map(|query| query.config.n_lines)
+            .max()
+            .unwrap_or(0);
+
+        Self {
+            filename: filename.to_string(),
+            queries,
+            context,
+            recent: VecDeque::with_capacity(context),
+            pending: Vec::new(),
+            line_count: 0,
+            byte_offset: 0,
+        }
+    }
+
+    /// Number of lines pushed so far
+    pub fn line_count(&self) -> usize {
+        self.line_count
+    }
+
+    /// Embed newly arrived lines (as read, with their line terminators if any) and
+    /// return the matches whose after-context is complete
+    pub fn push_lines(&mut self, raw_lines: &[Vec<u8>], model: &StaticModel) -> Vec<FollowMatch> {
+        let lines: Vec<String> = raw_lines.iter().map(|raw| strip_terminator(raw)).collect();
+        let (segments, texts) = segment_lines(&lines);
+
+        // Case-insensitive queries are compared against the lowercased lines, and
+        // only the variants some query needs are embedded
+        let embed = |texts: Vec<String>| model.encode_with_args(&texts, Some(2048), 16384);
+        let queries = || self.queries.iter().map(|query| query.config.ignore_case);
+        let folded = if queries().any(|ignore_case| ignore_case) {
+            embed(texts.iter().map(|text| text.to_lowercase()).collect())
+        } else {
+            Vec::new()
+        };
+        let embeddings = if queries().any(|ignore_case| !ignore_case) {
+            embed(texts)
+        } else {
+            Vec::new()
+        };
+
+        self.push_embedded(raw_lines, &lines, &segments, &embeddings, &folded)
+    }
+
+    /// Matches still waiting for after-context, with what has arrived of it.
+    /// Used when the stream goes quiet or ends, so that alerts aren't held back.
+    pub fn flush(&mut self) -> Vec<FollowMatch> {
+        std::mem::take(&mut self.pending)
+            .into_iter()
+            .map(|pending| self.finish(pending))
+            .collect()
+    }
+
+    /// `embeddings` are of the segments as they are, for case-sensitive queries,
+    /// and `folded` of the lowercased segments, for case-insensitive ones
+    fn push_embedded(
+        &mut self,
+        raw_lines: &[Vec<u8>],
+        lines: &[String],
+        segments: &[LineSegment],
+        embeddings: &[Vec<f32>],
+        folded: &[Vec<f32>],
+    ) -> Vec<FollowMatch> {
+        let mut matches = Vec::new();
+        let mut segment_idx = 0;
+
+        for (batch_line, line) in lines.iter().enumerate() {
+            let segments_end = segment_idx
+                + segments[segment_idx..]
+                    .iter()
+                    .take_while(|segment| segment.line == batch_line)
+                    .count();
+            let line_segments = segment_idx..segments_end;
+            segment_idx = segments_end;
+
+            // The line is after-context of earlier matches, which may now be complete
+            let mut still_pending = Vec::new();
+            for mut pending in std::mem::take(&mut self.pending) {
+                pending.result.lines.push(display_line(line, None));
+                pending.result.end += 1;
+                pending.remaining -= 1;
+                if pending.remaining == 0 {
+                    matches.push(self.finish(pending));
+                } else {
+                    still_pending.push(pending);
+                }
+            }
+            self.pending = still_pending;
+
+            for query in 0..self.queries.len() {
+                let query_embeddings = if self.queries[query].config.ignore_case {
+                    folded
+                } else {
+                    embeddings
+                };
+                let Some((idx, distance)) =
+                    self.best_segment(query, line, &query_embeddings[line_segments.clone()])
+                else {
+                    continue;
+                };
+                let segment = LineSegment {
+                    line: self.line_count,
+                    columns: segments[line_segments.start + idx].columns,
+                };
+                let result = self.result_for_line(query, line, segment, distance);
+                let remaining = self.queries[query].config.n_lines;
+                let pending = PendingMatch {
+                    query,
+                    result,
+                    remaining,
+                };
+                if remaining == 0 {
+                    matches.push(self.finish(pending));
+                } else {
+                    self.pending.push(pending);
+                }
+            }
+
+            self.recent.push_back(line.clone());
+            if self.recent.len() > self.context {
+                self.recent.pop_front();
+            }
+            self.line_count += 1;
+            self.byte_offset += raw_lines[batch_line].len();
+        }
+
+        matches
+    }
+
+    /// The closest segment of a line that passes the query's filter and thresholds
+    fn best_segment(
+        &self,
+        query: usize,
+        line: &str,
+        embeddings: &[Vec<f32>],
+    ) -> Option<(usize, f64)> {
+        let StandingQuery {
+            embedding, config, ..
+        } = &self.queries[query];
+        if !config.filter.matches_line(line) {
+            return None;
+        }
+
+        embeddings
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, line_embedding)| {
+                f32::cosine(embedding, line_embedding)
+                    .filter(|&distance| passes_thresholds(config, distance, None))
+                    .filter(|&distance| !is_near_negative(config, line_embedding, distance))
+                    .map(|distance| (idx, distance))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    /// A result for the current line, with the before-context that is still buffered
+    fn result_for_line(
+        &self,
+        query: usize,
+        line: &str,
+        segment: LineSegment,
+        distance: f64,
+    ) -> SearchResult {
+        let before = self.queries[query].config.n_lines.min(self.recent.len());
+        let mut lines: Vec<String> = self
+            .recent
+            .iter()
+            .skip(self.recent.len() - before)
+            .map(|context| display_line(context, None))
+            .collect();
+        lines.push(display_line(line, segment.columns));
+        let (match_text, match_bytes) = matched_part(line, self.byte_offset, segment);
+
+        SearchResult {
+            filename: self.filename.clone(),
+            lines,
+            start: self.line_count - before,
+            end: self.line_count + 1,
+            match_line: self.line_count,
+            match_columns: segment.columns,
+            match_text,
+            match_bytes,
+            distance,
+            stale: false,
+        }
+    }
+
+    fn finish(&self, pending: PendingMatch) -> FollowMatch {
+        FollowMatch {
+            query_id: self.queries[pending.query].id.clone(),
+            result: pending.result,
+        }
+    }
+}
+
+/// A line without its `\n` or `\r\n` terminator, like `str::lines` yields it.
+/// Invalid UTF-8 is replaced, the byte offsets of later lines still count the raw bytes.
+fn strip_terminator(raw: &[u8]) -> String {
+    let line = raw.strip_suffix(b"\n").unwrap_or(raw);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: &str, embedding: Vec<f32>, n_lines: usize) -> StandingQuery {
+        query_with_case(id, embedding, n_lines, false)
+    }
+
+    fn query_with_case(
+        id: &str,
+        embedding: Vec<f32>,
+        n_lines: usize,
+        ignore_case: bool,
+    ) -> StandingQuery {
+        StandingQuery {
+            id: Some(id.to_string()),
+            embedding,
+            config: SearchConfig {
+                n_lines,
+                max_distance: Some(0.1),
+                ignore_case,
+                ..Default::default()
+            },
+        }
+    }
+
+    // Push lines with hand-made embeddings: "error" lines point one way, the rest another
+    fn push(matcher: &mut StreamMatcher, raw_lines: &[&str]) -> Vec<FollowMatch> {
+        let raw_lines: Vec<&[u8]> = raw_lines.iter().map(|raw| raw.as_bytes()).collect();
+        push_bytes(matcher, &raw_lines)
+    }
+
+    fn push_bytes(matcher: &mut StreamMatcher, raw_lines: &[&[u8]]) -> Vec<FollowMatch> {
+        let raw_lines: Vec<Vec<u8>> = raw_lines.iter().map(|raw| raw.to_vec()).collect();
+        let lines: Vec<String> = raw_lines.iter().map(|raw| strip_terminator(raw)).collect();
+        let (segments, _) = segment_lines(&lines);
+        let embed = |fold: bool| -> Vec<Vec<f32>> {
+            segments
+                .iter()
+                .map(|segment| {
+                    let line = &lines[segment.line];
+                    let line = if fold {
+                        line.to_lowercase()
+                    } else {
+                        line.clone()
+                    };
+                    if line.contains("error") {
+                        vec![1.0, 0.0]
+                    } else {
+                        vec![0.0, 1.0]
+                    }
+                })
+                .collect()
+        };
+        matcher.push_embedded(&raw_lines, &lines, &segments, &embed(false), &embed(true))
+    }
+
+    #[test]
+    fn test_match_waits_for_after_context() {
+        let mut matcher = StreamMatcher::new("<stdin>", vec![query("err", vec![1.0, 0.0], 1)]);
+
+        let matches = push(&mut matcher, &["start\n", "disk error\r\n"]);
+        assert!(matches.is_empty());
+
+        let matches = push(&mut matcher, &["recovered\n", "idle\n"]);
+        assert_eq!(matches.len(), 1);
+        let result = &matches[0].result;
+        assert_eq!(matches[0].query_id.as_deref(), Some("err"));
+        assert_eq!(result.lines, vec!["start", "disk error", "recovered"]);
+        assert_eq!((result.start, result.end, result.match_line), (0, 3, 1));
+        assert_eq!(result.match_text, "disk error");
+        assert_eq!(result.match_bytes, 6..16);
+        assert_eq!(matcher.line_count(), 4);
+    }
+
+    #[test]
+    fn test_flush_emits_partial_context() {
+        let mut matcher = StreamMatcher::new("<stdin>", vec![query("err", vec![1.0, 0.0], 2)]);
+
+        assert!(push(&mut matcher, &["error one\n", "fine\n"]).is_empty());
+        let matches = matcher.flush();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].result.lines, vec!["error one", "fine"]);
+        assert!(matcher.flush().is_empty());
+    }
+
+    #[test]
+    fn test_several_standing_queries() {
+        let queries = vec![
+            query("err", vec![1.0, 0.0], 0),
+            query("other", vec![0.0, 1.0], 0),
+        ];
+        let mut matcher = StreamMatcher::new("<stdin>", queries);
+
+        let matches = push(&mut matcher, &["error\n", "ok\n", "ok"]);
+        let ids: Vec<(&str, usize)> = matches
+            .iter()
+            .map(|m| (m.query_id.as_deref().unwrap(), m.result.match_line))
+            .collect();
+        assert_eq!(ids, vec![("err", 0), ("other", 1), ("other", 2)]);
+    }
+
+    #[test]
+    fn test_ignore_case_is_per_query() {
+        let queries = vec![
+            query_with_case("folded", vec![1.0, 0.0], 0, true),
+            query_with_case("exact", vec![1.0, 0.0], 0, false),
+        ];
+        let mut matcher = StreamMatcher::new("<stdin>", queries);
+
+        let matches = push(&mut matcher, &["DISK ERROR\n", "disk error\n"]);
+        let ids: Vec<(&str, usize)> = matches
+            .iter()
+            .map(|m| (m.query_id.as_deref().unwrap(), m.result.match_line))
+            .collect();
+        assert_eq!(ids, vec![("folded", 0), ("folded", 1), ("exact", 1)]);
+    }
+
+    #[test]
+    fn test_byte_offsets_count_invalid_utf8_as_read() {
+        let mut matcher = StreamMatcher::new("<stdin>", vec![query("err", vec![1.0, 0.0], 0)]);
+
+        // Two invalid bytes, each replaced by a three-byte character in the text
+        let matches = push_bytes(&mut matcher, &[b"\xff\xfe bad\n", b"disk error\n"]);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].result.match_bytes, 7..17);
+    }
+}
@@ -2,10 +2,18 @@ use anyhow::Result;
 use model2vec_rs::model::StaticModel;
 use simsimd::SpatialSimilarity;
 use std::cmp::{max, min};
-use std::fs::read_to_string;
+use std::ops::Range;
+use std::sync::Arc;
 
 #[cfg(feature = "workspace")]
-use crate::workspace::store::{DocMeta, DocumentState, RankedLine};
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "workspace")]
+use std::path::Path;
+
+#[cfg(feature = "workspace")]
+use crate::workspace::store::{
+    DocMeta, DocumentState, LineSnapshot, PathScope, RankedDocument, RankedLine, line_text_hash,
+};
 
 #[cfg(feature = "workspace")]
 use crate::workspace::{
@@ -13,11 +21,59 @@ use crate::workspace::{
     store::{LineEmbedding, Store},
 };
 
+pub mod cache;
+pub mod cutoff;
+pub mod filter;
+pub mod follow;
+pub mod query;
+pub mod records;
+pub mod rerank;
+pub mod segment;
+pub mod text;
+pub mod top_k;
+pub mod walk;
+
+use cache::EmbeddingCache;
+use cutoff::{Cutoff, ScoreDistribution};
+use filter::SearchFilter;
+use rayon::prelude::*;
+use rerank::{MMR_CANDIDATE_FACTOR, diversified_order};
+use segment::{ColumnRange, LineSegment, display_line, segment_bytes, segment_lines};
+use text::{SkipReason, SkippedFile, line_offsets, read_text_file};
+use top_k::TopK;
+
 pub const MODEL_NAME: &str = "minishlab/potion-multilingual-128M";
 
+/// How many line hits to pull per requested document at first when ranking
+/// documents from workspace line embeddings. More are pulled as needed.
+#[cfg(feature = "workspace")]
+const DOCUMENT_CANDIDATE_FACTOR: usize = 10;
+
+/// How many line hits to pull per requested result when some of them may be
+/// dropped for being closer to a negative query than to the query itself
+#[cfg(feature = "workspace")]
+const NEGATIVE_FILTER_CANDIDATE_FACTOR: usize = 10;
+
+/// How many of the best workspace hits an adaptive cutoff is worked out from,
+/// since a workspace query doesn't score every line. A percentile or z-score
+/// over a workspace is therefore relative to these, not to every indexed line.
+#[cfg(feature = "workspace")]
+const CUTOFF_CANDIDATES: usize = 1000;
+
+/// Added to a cutoff's (inclusive) threshold when it is handed to the workspace
+/// store as an (exclusive) maximum distance
+#[cfg(feature = "workspace")]
+const CUTOFF_EPSILON: f64 = 1e-6;
+
+/// Files read and embedded at a time by `workspace index`, between progress reports
+#[cfg(feature = "workspace")]
+const INDEX_BATCH_FILES: usize = 200;
+
 pub struct Document {
     pub filename: String,
     pub lines: Vec<String>,
+    pub line_offsets: Vec<usize>, // Byte offset of each line in the file's text
+    pub segments: Vec<LineSegment>, // What each embedding covers: a line, or a span of a long line
     pub embeddings: Vec<Vec<f32>>,
 }
 
@@ -29,21 +85,164 @@ pub struct DocumentInfo {
     pub meta: DocMeta,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct SearchConfig {
     pub n_lines: usize,
     pub top_k: usize,
     pub max_distance: Option<f64>,
     pub ignore_case: bool,
+    pub mmr_lambda: Option<f64>, // Diversity re-ranking (1.0 = pure relevance), off when None
+    pub exclude_spans: Vec<LineSpan>, // Lines never returned, e.g. the source of a `--like` query
+    pub negative_embeddings: Vec<Vec<f32>>, // Lines closer to any of these than to the query are dropped
+    pub max_file_size: Option<u64>,         // Larger files are skipped
+    pub filter: SearchFilter,               // Regex and path filters applied before scoring
+    pub cutoff: Option<Cutoff>, // Threshold worked out from the distances of all candidates
+    pub explain: bool,          // Collect the distances of all candidates to report on them
+}
+
+impl SearchConfig {
+    /// Whether every hit under the thresholds is returned, rather than the top_k best
+    fn returns_all_hits(&self) -> bool {
+        self.max_distance.is_some() || self.cutoff.is_some()
+    }
+
+    fn needs_distribution(&self) -> bool {
+        self.cutoff.is_some() || self.explain
+    }
+}
+
+/// A range of lines in a file (0-based, end-exclusive)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineSpan {
+    pub path: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LineSpan {
+    /// Parse a `path:start-end` or `path:line` reference.
+    /// Line numbers are 1-based and inclusive, as printed in search results.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid line range: '{}'. Expected <path>:<start>-<end> or <path>:<line>",
+                spec
+            )
+        };
+
+        let (path, range) = spec.rsplit_once(':').ok_or_else(invalid)?;
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.trim().parse::<usize>(), end.trim().parse::<usize>()),
+            None => (range.trim().parse::<usize>(), range.trim().parse::<usize>()),
+        };
+        let (start, end) = (start.map_err(|_| invalid())?, end.map_err(|_| invalid())?);
+
+        if path.is_empty() || start == 0 || end < start {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            start: start - 1,
+            end,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_line(&self, line: usize) -> bool {
+        line >= self.start && line < self.end
+    }
+
+    /// Whether `path` refers to the same file, even if spelled differently
+    pub fn is_same_file(&self, path: &str) -> bool {
+        if self.path == path {
+            return true;
+        }
+        match (
+            std::fs::canonicalize(&self.path),
+            std::fs::canonicalize(path),
+        ) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
 pub struct SearchResult {
     pub filename: String,
-    pub lines: Vec<String>,
+    pub lines: Vec<String>, // Overly long lines are shortened, the match to its matching span
     pub start: usize,
     pub end: usize,
     pub match_line: usize, // The actual line number that matched
+    pub match_columns: Option<ColumnRange>, // Set when only a span of a long line matched
+    pub match_text: String, // The matched line or span, in full
+    pub match_bytes: Range<usize>, // Byte offsets of the match in the file's text
     pub distance: f64,
+    pub stale: bool, // Lines are from the workspace index, the file has changed since
+}
+
+impl SearchResult {
+    /// Similarity score for the distance: higher is better
+    pub fn score(&self) -> f64 {
+        1.0 - self.distance
+    }
+}
+
+/// The original document that a `semtools parse` output was produced from,
+/// recorded in the parse cache metadata next to it
+pub fn parsed_source_document(path: &str) -> Option<String> {
+    let stem = path.strip_suffix(".md")?;
+    let metadata = std::fs::read_to_string(format!("{stem}.metadata.json")).ok()?;
+    let metadata: serde_json::Value = serde_json::from_str(&metadata).ok()?;
+    metadata["source_path"]
+        .as_str()
+        .filter(|source| !source.is_empty())
+        .map(|source| source.to_string())
+}
+
+/// How per-line distances are combined into a single distance per document
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentAggregation {
+    /// Distance of the best matching line
+    Max,
+    /// Mean distance of the `n` best matching lines
+    MeanTopN(usize),
+    /// Distance to the mean of all line embeddings in the document
+    Embedding,
+}
+
+impl DocumentAggregation {
+    pub fn parse(name: &str, top_n: usize) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "max" => Ok(Self::Max),
+            "mean" => Ok(Self::MeanTopN(top_n.max(1))),
+            "embedding" => Ok(Self::Embedding),
+            _ => Err(anyhow::anyhow!(
+                "Invalid aggregation: '{}'. Must be 'max', 'mean' or 'embedding'",
+                name
+            )),
+        }
+    }
+}
+
+/// Results of a search over files, along with the files that were left out
+pub struct SearchOutcome<T> {
+    pub results: Vec<T>,
+    pub skipped: Vec<SkippedFile>,
+    pub distribution: Option<ScoreDistribution>, // Distances of all candidates, with `explain`
+}
+
+pub struct DocumentResult {
+    pub filename: String,
+    pub distance: f64,
+    pub best_match: SearchResult, // The best matching line with its context
 }
 
 pub(crate) fn create_document_from_content(
@@ -52,167 +251,1488 @@ pub(crate) fn create_document_from_content(
     model: &StaticModel,
     ignore_case: bool,
 ) -> Option<Document> {
-    let lines: Vec<&str> = content.lines().collect();
+    let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
 
     if lines.is_empty() {
         return None;
     }
 
-    let owned_lines: Vec<String> = lines.iter().map(|s| s.to_string()).collect();
-
-    let lines_for_embedding = if ignore_case {
-        owned_lines.iter().map(|s| s.to_lowercase()).collect()
+    let (segments, texts) = segment_lines(&lines);
+    let texts_for_embedding = if ignore_case {
+        texts.iter().map(|s| s.to_lowercase()).collect()
+    } else {
+        texts
+    };
+
+    let embeddings = model.encode_with_args(&texts_for_embedding, Some(2048), 16384);
+    Some(Document {
+        filename,
+        lines,
+        line_offsets: line_offsets(content),
+        segments,
+        embeddings,
+    })
+}
+
+/// Mean of a document's line embeddings, used as its document-level embedding
+pub(crate) fn document_embedding(embeddings: &[Vec<f32>]) -> Option<Vec<f32>> {
+    let first = embeddings.first()?;
+    let mut sum = vec![0_f32; first.len()];
+    for embedding in embeddings {
+        for (total, value) in sum.iter_mut().zip(embedding) {
+            *total += value;
+        }
+    }
+
+    let count = embeddings.len() as f32;
+    sum.iter_mut().for_each(|total| *total /= count);
+    Some(sum)
+}
+
+/// The result for a matching segment, with `n_lines` lines of context around its line
+fn search_result_for_segment(
+    doc: &Document,
+    segment_idx: usize,
+    distance: f64,
+    n_lines: usize,
+) -> SearchResult {
+    let segment = doc.segments[segment_idx];
+    let idx = segment.line;
+    let bottom_range = max(0, idx.saturating_sub(n_lines));
+    let top_range = min(doc.lines.len(), idx + n_lines + 1);
+    let (match_text, match_bytes) = matched_part(&doc.lines[idx], doc.line_offsets[idx], segment);
+
+    SearchResult {
+        filename: doc.filename.clone(),
+        lines: display_lines(&doc.lines[bottom_range..top_range], bottom_range, segment),
+        distance,
+        start: bottom_range,
+        end: top_range,
+        match_line: idx,
+        match_columns: segment.columns,
+        match_text,
+        match_bytes,
+        stale: false,
+    }
+}
+
+/// Text and byte offsets within the file of a matched segment, for a line at `line_offset`
+fn matched_part(line: &str, line_offset: usize, segment: LineSegment) -> (String, Range<usize>) {
+    let bytes = segment_bytes(line, segment.columns);
+    (
+        line[bytes.clone()].to_string(),
+        line_offset + bytes.start..line_offset + bytes.end,
+    )
+}
+
+/// Lines as shown in results, where the first one is line `start` of its file
+fn display_lines<S: AsRef<str>>(lines: &[S], start: usize, segment: LineSegment) -> Vec<String> {
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let columns = segment.columns.filter(|_| start + i == segment.line);
+            display_line(line.as_ref(), columns)
+        })
+        .collect()
+}
+
+/// Convert a workspace hit to a SearchResult, reading context lines from the file.
+/// When the hit carries the lines as indexed and the file no longer has them (or
+/// can't be read), those are shown instead and the result is marked stale.
+#[cfg(feature = "workspace")]
+pub fn ranked_line_to_search_result(ranked_line: &RankedLine, n_lines: usize) -> SearchResult {
+    let match_line_number = ranked_line.line_number as usize;
+    let start = match_line_number.saturating_sub(n_lines);
+    let mut end = match_line_number + n_lines + 1;
+    let segment = LineSegment {
+        line: match_line_number,
+        columns: ranked_line.columns,
+    };
+    let content = read_text_file(&ranked_line.path, None).ok();
+
+    if let Some(snapshot) = &ranked_line.snapshot
+        && let Some(snapshot_line) = match_line_number
+            .checked_sub(snapshot.start)
+            .and_then(|idx| snapshot.lines.get(idx))
+        && !content
+            .as_deref()
+            .is_some_and(|content| snapshot_matches(snapshot, content))
+    {
+        let bytes = segment_bytes(snapshot_line, segment.columns);
+        return SearchResult {
+            filename: ranked_line.path.clone(),
+            lines: display_lines(&snapshot.lines, snapshot.start, segment),
+            start: snapshot.start,
+            end: snapshot.start + snapshot.lines.len(),
+            match_line: match_line_number,
+            match_columns: ranked_line.columns,
+            match_text: snapshot_line[bytes].to_string(),
+            match_bytes: 0..0, // Offsets into the file would point at other text
+            distance: ranked_line.distance as f64,
+            stale: true,
+        };
+    }
+
+    let mut match_text = String::new();
+    let mut match_bytes = 0..0;
+    let lines = match content {
+        Some(content) => {
+            let lines: Vec<&str> = content.lines().collect();
+            end = end.min(lines.len());
+            // The file may have shrunk since it was embedded
+            if let Some(line) = lines.get(match_line_number) {
+                let line_offset = line_offsets(&content)[match_line_number];
+                (match_text, match_bytes) = matched_part(line, line_offset, segment);
+            }
+            display_lines(&lines[start.min(end)..end], start, segment)
+        }
+        None => vec!["[Error: Could not read file content]".to_string()],
+    };
+
+    SearchResult {
+        filename: ranked_line.path.clone(),
+        lines,
+        start,
+        end,
+        match_line: match_line_number,
+        match_columns: ranked_line.columns,
+        match_text,
+        match_bytes,
+        distance: ranked_line.distance as f64,
+        stale: false,
+    }
+}
+
+/// Whether a file's content still has the snapshot's lines where they were indexed
+#[cfg(feature = "workspace")]
+fn snapshot_matches(snapshot: &LineSnapshot, content: &str) -> bool {
+    let mut file_lines = content.lines().skip(snapshot.start);
+    snapshot
+        .lines
+        .iter()
+        .all(|indexed| file_lines.next() == Some(indexed.as_str()))
+}
+
+/// Distances of the segments of a document that are candidates, in line order.
+/// Thresholds are left to the caller, which may need every candidate's distance.
+fn score_lines(
+    doc: &Document,
+    query_embedding: &[f32],
+    config: &SearchConfig,
+) -> Vec<(usize, f64)> {
+    let excluded = excluded_spans_for(config, &doc.filename);
+
+    doc.embeddings
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| is_candidate_segment(doc, *idx, &excluded, config))
+        .filter_map(|(idx, line_embedding)| {
+            f32::cosine(query_embedding, line_embedding)
+                .filter(|&distance| !is_near_negative(config, line_embedding, distance))
+                .map(|distance| (idx, distance))
+        })
+        .collect()
+}
+
+/// The adaptive cutoff's threshold for a distribution of candidate distances, if one is set
+fn cutoff_threshold(
+    config: &SearchConfig,
+    distribution: Option<&ScoreDistribution>,
+) -> Option<f64> {
+    config
+        .cutoff
+        .zip(distribution)
+        .and_then(|(cutoff, distribution)| cutoff.threshold(distribution))
+}
+
+/// Whether a distance is under `max_distance` (exclusive) and the cutoff threshold (inclusive)
+fn passes_thresholds(config: &SearchConfig, distance: f64, cutoff_threshold: Option<f64>) -> bool {
+    config
+        .max_distance
+        .is_none_or(|max_distance| distance < max_distance)
+        && cutoff_threshold.is_none_or(|threshold| distance <= threshold)
+}
+
+/// Distances of every candidate line in the documents, e.g. to explain a search
+pub fn line_distribution(
+    documents: &[Document],
+    query_embedding: &[f32],
+    config: &SearchConfig,
+) -> ScoreDistribution {
+    ScoreDistribution::new(
+        documents
+            .iter()
+            .flat_map(|doc| score_lines(doc, query_embedding, config))
+            .map(|(_, distance)| distance)
+            .collect(),
+    )
+}
+
+/// Distances of every document that has a candidate line, e.g. to explain a search
+pub fn document_distribution(
+    documents: &[Document],
+    query_embedding: &[f32],
+    config: &SearchConfig,
+    aggregation: DocumentAggregation,
+) -> ScoreDistribution {
+    ScoreDistribution::new(
+        documents
+            .iter()
+            .filter_map(|doc| rank_document(doc, query_embedding, config, aggregation))
+            .map(|doc_result| doc_result.distance)
+            .collect(),
+    )
+}
+
+pub fn search_documents(
+    documents: &[Document],
+    query_embedding: &[f32],
+    config: &SearchConfig,
+) -> Vec<SearchResult> {
+    // (document index, line index, distance) for every line under the threshold
+    let mut hits: Vec<(usize, usize, f64)> = Vec::new();
+
+    for (doc_idx, doc) in documents.iter().enumerate() {
+        for (idx, distance) in score_lines(doc, query_embedding, config) {
+            hits.push((doc_idx, idx, distance));
+        }
+    }
+
+    // Sort by distance (best matches first)
+    hits.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal));
+
+    let distribution = config
+        .cutoff
+        .map(|_| ScoreDistribution::new(hits.iter().map(|hit| hit.2).collect()));
+    let cutoff_threshold = cutoff_threshold(config, distribution.as_ref());
+    hits.retain(|hit| passes_thresholds(config, hit.2, cutoff_threshold));
+
+    if let Some(lambda) = config.mmr_lambda {
+        let distances: Vec<f64> = hits.iter().map(|hit| hit.2).collect();
+        let embeddings: Vec<&[f32]> = hits
+            .iter()
+            .map(|&(doc_idx, idx, _)| documents[doc_idx].embeddings[idx].as_slice())
+            .collect();
+        hits = diversified_order(&distances, &embeddings, lambda, mmr_top_k(config))
+            .into_iter()
+            .map(|i| hits[i])
+            .collect();
+    }
+
+    // If threshold is specified, return all results under threshold
+    // Otherwise, limit to top_k results
+    let limit = if config.returns_all_hits() {
+        hits.len()
+    } else {
+        config.top_k
+    };
+
+    hits.into_iter()
+        .take(limit)
+        .map(|(doc_idx, idx, distance)| {
+            search_result_for_segment(&documents[doc_idx], idx, distance, config.n_lines)
+        })
+        .collect()
+}
+
+/// How many hits diversity re-ranking picks, or None when every hit under a threshold is kept
+fn mmr_top_k(config: &SearchConfig) -> Option<usize> {
+    (!config.returns_all_hits()).then_some(config.top_k)
+}
+
+/// Rank whole documents against the query, keeping each document's best matching line
+pub fn rank_documents(
+    documents: &[Document],
+    query_embedding: &[f32],
+    config: &SearchConfig,
+    aggregation: DocumentAggregation,
+) -> Vec<DocumentResult> {
+    let mut document_results: Vec<DocumentResult> = documents
+        .iter()
+        .filter_map(|doc| rank_document(doc, query_embedding, config, aggregation))
+        .collect();
+
+    // Sort by distance (best documents first)
+    document_results.sort_by(|a, b| {
+        a.distance
+            .partial_cmp(&b.distance)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let distribution = config
+        .cutoff
+        .map(|_| ScoreDistribution::new(document_results.iter().map(|r| r.distance).collect()));
+    let cutoff_threshold = cutoff_threshold(config, distribution.as_ref());
+    document_results.retain(|r| passes_thresholds(config, r.distance, cutoff_threshold));
+
+    // Same semantics as line search: threshold wins over top_k
+    if config.returns_all_hits() {
+        document_results
+    } else {
+        document_results.into_iter().take(config.top_k).collect()
+    }
+}
+
+/// A document's distance to the query along with its best matching line, before
+/// any threshold. None when none of its lines are candidates.
+fn rank_document(
+    doc: &Document,
+    query_embedding: &[f32],
+    config: &SearchConfig,
+    aggregation: DocumentAggregation,
+) -> Option<DocumentResult> {
+    let mut line_distances = score_lines(doc, query_embedding, config);
+    if line_distances.is_empty() {
+        return None;
+    }
+
+    line_distances.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    let (best_idx, best_distance) = line_distances[0];
+
+    let distance = match aggregation {
+        DocumentAggregation::Max => best_distance,
+        DocumentAggregation::MeanTopN(n) => {
+            let best_lines = &line_distances[..n.min(line_distances.len())];
+            best_lines.iter().map(|(_, d)| d).sum::<f64>() / best_lines.len() as f64
+        }
+        DocumentAggregation::Embedding => document_embedding(&doc.embeddings)
+            .and_then(|doc_embedding| f32::cosine(query_embedding, &doc_embedding))?,
+    };
+
+    Some(DocumentResult {
+        filename: doc.filename.clone(),
+        distance,
+        best_match: search_result_for_segment(doc, best_idx, best_distance, config.n_lines),
+    })
+}
+
+/// Whether a segment may be scored: its line is neither excluded nor filtered out
+fn is_candidate_segment(
+    doc: &Document,
+    segment_idx: usize,
+    excluded: &[&LineSpan],
+    config: &SearchConfig,
+) -> bool {
+    let line = doc.segments[segment_idx].line;
+    !excluded.iter().any(|span| span.contains_line(line))
+        && config.filter.matches_line(&doc.lines[line])
+}
+
+/// Exclusion spans that apply to the given file
+fn excluded_spans_for<'a>(config: &'a SearchConfig, path: &str) -> Vec<&'a LineSpan> {
+    config
+        .exclude_spans
+        .iter()
+        .filter(|span| span.is_same_file(path))
+        .collect()
+}
+
+#[cfg(feature = "workspace")]
+fn is_excluded(config: &SearchConfig, path: &str, line: usize) -> bool {
+    config
+        .exclude_spans
+        .iter()
+        .any(|span| span.contains_line(line) && span.is_same_file(path))
+}
+
+/// Whether a line is closer to one of the negative queries than to the query itself
+fn is_near_negative(config: &SearchConfig, line_embedding: &[f32], distance: f64) -> bool {
+    config.negative_embeddings.iter().any(|negative| {
+        f32::cosine(negative, line_embedding)
+            .is_some_and(|negative_distance| negative_distance < distance)
+    })
+}
+
+/// Embed a span of lines as a single query vector (the mean of its line embeddings)
+pub fn embed_span(span: &LineSpan, model: &StaticModel, ignore_case: bool) -> Result<Vec<f32>> {
+    let content = read_text_file(&span.path, None)
+        .map_err(|reason| anyhow::anyhow!("Cannot use '{}': {}", span.path, reason))?;
+    let lines: Vec<&str> = content.lines().collect();
+    let end = span.end.min(lines.len());
+    if span.start >= end {
+        return Err(anyhow::anyhow!(
+            "Lines {}-{} are outside of '{}' ({} lines)",
+            span.start + 1,
+            span.end,
+            span.path,
+            lines.len()
+        ));
+    }
+
+    let doc = create_document_from_content(
+        span.path.clone(),
+        &lines[span.start..end].join("\n"),
+        model,
+        ignore_case,
+    )
+    .ok_or_else(|| anyhow::anyhow!("Nothing to embed in '{}'", span.path))?;
+
+    document_embedding(&doc.embeddings)
+        .ok_or_else(|| anyhow::anyhow!("Nothing to embed in '{}'", span.path))
+}
+
+/// Read and embed a file, reusing cached embeddings when its content was embedded before
+fn load_document(
+    path: &str,
+    model: &StaticModel,
+    config: &SearchConfig,
+) -> Result<Option<Document>, SkipReason> {
+    let content = read_text_file(path, config.max_file_size)?;
+    let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+
+    // Files without a single line passing the regex filter aren't worth embedding
+    if lines.is_empty() || !lines.iter().any(|line| config.filter.matches_line(line)) {
+        return Ok(None);
+    }
+
+    let (segments, texts) = segment_lines(&lines);
+    let embeddings = EmbeddingCache::global().embed(&texts, model, config.ignore_case);
+    Ok(Some(Document {
+        filename: path.to_string(),
+        lines,
+        line_offsets: line_offsets(&content),
+        segments,
+        embeddings: Arc::unwrap_or_clone(embeddings),
+    }))
+}
+
+pub fn load_documents(
+    files: &[String],
+    model: &StaticModel,
+    config: &SearchConfig,
+) -> SearchOutcome<Document> {
+    let mut documents = Vec::new();
+    let mut skipped = Vec::new();
+    for f in files.iter().filter(|f| config.filter.matches_path(f)) {
+        match load_document(f, model, config) {
+            Ok(Some(doc)) => documents.push(doc),
+            Ok(None) => {}
+            Err(reason) => skipped.push(SkippedFile {
+                path: f.clone(),
+                reason,
+            }),
+        }
+    }
+    SearchOutcome {
+        results: documents,
+        skipped,
+        distribution: None,
+    }
+}
+
+/// Rank files without holding them all in memory: files are embedded and scored
+/// in parallel, and only the best documents seen so far are kept
+pub fn rank_files(
+    files: &[String],
+    query_embedding: &[f32],
+    model: &StaticModel,
+    config: &SearchConfig,
+    aggregation: DocumentAggregation,
+) -> Result<SearchOutcome<DocumentResult>> {
+    let files = &config.filter.filter_paths(files);
+    // With a threshold every match is returned, so the collection can't be bounded
+    let capacity = (!config.returns_all_hits()).then_some(config.top_k);
+
+    let partial = files
+        .par_iter()
+        .enumerate()
+        .fold(
+            || PartialResults::new(capacity),
+            |mut partial, (file_idx, f)| {
+                match load_document(f, model, config) {
+                    Ok(Some(doc)) => {
+                        if let Some(doc_result) =
+                            rank_document(&doc, query_embedding, config, aggregation)
+                        {
+                            partial.record(config, doc_result.distance);
+                            if passes_thresholds(config, doc_result.distance, None) {
+                                partial
+                                    .top
+                                    .push(doc_result.distance, (file_idx, 0), doc_result);
+                            }
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(reason) => partial.skipped.push((file_idx, reason)),
+                }
+                partial
+            },
+        )
+        .reduce(|| PartialResults::new(capacity), PartialResults::merge);
+
+    let distribution = partial.distribution(config);
+    let cutoff_threshold = cutoff_threshold(config, distribution.as_ref());
+    Ok(SearchOutcome {
+        results: partial
+            .top
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(_, doc_result)| doc_result)
+            .filter(|doc_result| passes_thresholds(config, doc_result.distance, cutoff_threshold))
+            .collect(),
+        skipped: skipped_in_file_order(files, partial.skipped),
+        distribution: distribution.filter(|_| config.explain),
+    })
+}
+
+/// What one thread of a parallel search over files has gathered
+struct PartialResults<T> {
+    top: TopK<T>,
+    skipped: Vec<(usize, SkipReason)>,
+    distances: Vec<f64>, // Of every candidate, when a cutoff or explanation needs them
+}
+
+impl<T> PartialResults<T> {
+    fn new(capacity: Option<usize>) -> Self {
+        Self {
+            top: TopK::new(capacity),
+            skipped: Vec::new(),
+            distances: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, config: &SearchConfig, distance: f64) {
+        if config.needs_distribution() {
+            self.distances.push(distance);
+        }
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        self.skipped.extend(other.skipped);
+        self.distances.extend(other.distances);
+        Self {
+            top: self.top.merge(other.top),
+            ..self
+        }
+    }
+
+    fn distribution(&self, config: &SearchConfig) -> Option<ScoreDistribution> {
+        config
+            .needs_distribution()
+            .then(|| ScoreDistribution::new(self.distances.clone()))
+    }
+}
+
+fn skipped_in_file_order(
+    files: &[String],
+    mut skipped: Vec<(usize, SkipReason)>,
+) -> Vec<SkippedFile> {
+    skipped.sort_by_key(|(file_idx, _)| *file_idx);
+    skipped
+        .into_iter()
+        .map(|(file_idx, reason)| SkippedFile {
+            path: files[file_idx].clone(),
+            reason,
+        })
+        .collect()
+}
+
+/// A line of a searched file that may be returned: its document, segment index,
+/// and its embedding when diversity re-ranking needs it
+type LineCandidate = (Arc<Document>, usize, Vec<f32>);
+
+/// Search files without holding them all in memory: files are embedded and scored
+/// in parallel, and only the best candidates seen so far are kept
+pub fn search_files(
+    files: &[String],
+    query_embedding: &[f32],
+    model: &StaticModel,
+    config: &SearchConfig,
+) -> Result<SearchOutcome<SearchResult>> {
+    let files = &config.filter.filter_paths(files);
+    // With a threshold every hit is returned, so the collection can't be bounded.
+    // Diversity re-ranking needs a larger pool, along with each candidate's vector.
+    let capacity = match (config.returns_all_hits(), config.mmr_lambda) {
+        (true, _) => None,
+        (false, Some(_)) => Some(config.top_k * MMR_CANDIDATE_FACTOR),
+        (false, None) => Some(config.top_k),
+    };
+    let keep_embeddings = config.mmr_lambda.is_some();
+
+    let partial = files
+        .par_iter()
+        .enumerate()
+        .fold(
+            || PartialResults::new(capacity),
+            |mut partial, (file_idx, f)| {
+                let mut doc = match load_document(f, model, config) {
+                    Ok(Some(doc)) => doc,
+                    Ok(None) => return partial,
+                    Err(reason) => {
+                        partial.skipped.push((file_idx, reason));
+                        return partial;
+                    }
+                };
+                let scored = score_lines(&doc, query_embedding, config);
+
+                // Candidates share their document (without its embeddings), and only
+                // become results once the cutoff has left the ones that are returned
+                let mut embeddings = std::mem::take(&mut doc.embeddings);
+                let doc = Arc::new(doc);
+                for (idx, distance) in scored {
+                    partial.record(config, distance);
+                    if !passes_thresholds(config, distance, None)
+                        || !partial.top.accepts(distance, (file_idx, idx))
+                    {
+                        continue;
+                    }
+                    let embedding = if keep_embeddings {
+                        std::mem::take(&mut embeddings[idx])
+                    } else {
+                        Vec::new()
+                    };
+                    partial.top.push(
+                        distance,
+                        (file_idx, idx),
+                        (Arc::clone(&doc), idx, embedding),
+                    );
+                }
+                partial
+            },
+        )
+        .reduce(|| PartialResults::new(capacity), PartialResults::merge);
+
+    let distribution = partial.distribution(config);
+    let cutoff_threshold = cutoff_threshold(config, distribution.as_ref());
+    let mut candidates: Vec<(f64, LineCandidate)> = partial
+        .top
+        .into_sorted_vec()
+        .into_iter()
+        .filter(|&(distance, _)| passes_thresholds(config, distance, cutoff_threshold))
+        .collect();
+
+    if let Some(lambda) = config.mmr_lambda {
+        let distances: Vec<f64> = candidates.iter().map(|(distance, _)| *distance).collect();
+        let embeddings: Vec<&[f32]> = candidates
+            .iter()
+            .map(|(_, (_, _, e))| e.as_slice())
+            .collect();
+        let order = diversified_order(&distances, &embeddings, lambda, mmr_top_k(config));
+
+        let mut slots: Vec<Option<(f64, LineCandidate)>> =
+            candidates.into_iter().map(Some).collect();
+        candidates = order.into_iter().filter_map(|i| slots[i].take()).collect();
+    }
+
+    // If threshold is specified, return all results under threshold
+    // Otherwise, limit to top_k results
+    if !config.returns_all_hits() {
+        candidates.truncate(config.top_k);
+    }
+
+    Ok(SearchOutcome {
+        results: candidates
+            .into_iter()
+            .map(|(distance, (doc, idx, _))| {
+                search_result_for_segment(&doc, idx, distance, config.n_lines)
+            })
+            .collect(),
+        skipped: skipped_in_file_order(files, partial.skipped),
+        distribution: distribution.filter(|_| config.explain),
+    })
+}
+
+/// What bringing a workspace up to date did
+#[cfg(feature = "workspace")]
+#[derive(Debug, Default)]
+pub struct SyncOutcome {
+    pub embedded: usize,              // New or changed files that were embedded
+    pub unchanged: usize,             // Files whose stored embeddings were still current
+    pub lines: usize, // Lines (or spans of long lines) embedded, not counting reused vectors
+    pub moved: Vec<(String, String)>, // Documents found under a new path, as (old path, new path)
+    pub skipped: Vec<SkippedFile>,
+}
+
+#[cfg(feature = "workspace")]
+impl SyncOutcome {
+    fn merge(&mut self, other: SyncOutcome) {
+        self.embedded += other.embedded;
+        self.unchanged += other.unchanged;
+        self.lines += other.lines;
+        self.moved.extend(other.moved);
+        self.skipped.extend(other.skipped);
+    }
+}
+
+/// Bring the workspace up to date for the given files, embedding new and changed documents
+#[cfg(feature = "workspace")]
+fn sync_workspace(
+    store: &Store,
+    files: &[String],
+    model: &StaticModel,
+    config: &SearchConfig,
+) -> Result<SyncOutcome> {
+    // Step 1: Analyze document states (changed/new/unchanged)
+    let doc_states = store.analyze_document_states(files, config.max_file_size)?;
+
+    // Documents whose content is already stored under another path (copies, or
+    // moves when the other file is gone) reuse the vectors stored for that path
+    let content_hashes: Vec<String> = doc_states
+        .iter()
+        .filter_map(|state| match state {
+            DocumentState::Changed(doc_info) | DocumentState::New(doc_info) => {
+                doc_info.meta.content_hash.clone()
+            }
+            _ => None,
+        })
+        .collect();
+    let stored_copies = store.find_documents_by_content_hash(&content_hashes)?;
+
+    // Stored vectors of changed documents and of copies, so lines that are still
+    // there aren't embedded again
+    let mut reusable_paths: Vec<String> = doc_states
+        .iter()
+        .filter_map(|state| match state {
+            DocumentState::Changed(doc_info) => Some(doc_info.filename.clone()),
+            _ => None,
+        })
+        .collect();
+    reusable_paths.extend(stored_copies.values().cloned());
+    let stored_vectors = store.get_line_vectors(&reusable_paths)?;
+    let no_vectors = HashMap::new();
+
+    // Step 2: Process documents that need embedding updates
+    let mut line_embeddings_to_upsert = Vec::new();
+    let mut docs_to_upsert = Vec::new();
+    let mut replaced_paths = Vec::new();
+    let mut touched = Vec::new();
+    let mut outcome = SyncOutcome::default();
+
+    for state in doc_states {
+        let is_new = matches!(state, DocumentState::New(_));
+        match state {
+            DocumentState::Changed(doc_info) | DocumentState::New(doc_info) => {
+                replaced_paths.push(doc_info.filename.clone());
+                let copy_of =
+                    (doc_info.meta.content_hash.as_ref()).and_then(|hash| stored_copies.get(hash));
+                if is_new
+                    && let Some(old_path) = copy_of
+                    && !Path::new(old_path).exists()
+                {
+                    outcome
+                        .moved
+                        .push((old_path.clone(), doc_info.filename.clone()));
+                }
+                let stored = stored_vectors
+                    .get(copy_of.unwrap_or(&doc_info.filename))
+                    .unwrap_or(&no_vectors);
+                // Generate line-by-line embeddings, reusing those of unchanged lines
+                let (line_embeddings, embedded) = workspace_line_embeddings(
+                    &doc_info.filename,
+                    &doc_info.content,
+                    model,
+                    config.ignore_case,
+                    stored,
+                );
+                if line_embeddings.is_empty() {
+                    continue;
+                }
+                // Also track document metadata for change detection, along with
+                // the document-level embedding used for document ranking
+                let embeddings: Vec<Vec<f32>> = line_embeddings
+                    .iter()
+                    .map(|line_embedding| line_embedding.embedding.clone())
+                    .collect();
+                let mut meta = doc_info.meta.clone();
+                meta.embedding = document_embedding(&embeddings).unwrap_or_default();
+                docs_to_upsert.push(meta);
+                line_embeddings_to_upsert.extend(line_embeddings);
+                outcome.lines += embedded;
+            }
+            DocumentState::Touched(meta) => {
+                touched.push(meta);
+                outcome.unchanged += 1;
+            }
+            DocumentState::Unchanged(_) => outcome.unchanged += 1,
+            DocumentState::Skipped(skipped_file) => outcome.skipped.push(skipped_file),
+        }
+    }
+
+    // A moved document only lives on under its new path
+    let mut moved_from: Vec<String> = outcome.moved.iter().map(|(old, _)| old.clone()).collect();
+    moved_from.sort();
+    moved_from.dedup();
+
+    // Step 3: Write both shards as one update, so a crash part way doesn't leave
+    // documents whose metadata and line embeddings disagree
+    let mut updated_paths = replaced_paths.clone();
+    updated_paths.extend(touched.iter().map(|meta| meta.path.clone()));
+    updated_paths.extend(moved_from.iter().cloned());
+    if !updated_paths.is_empty() {
+        store.update_documents(&updated_paths, |store| {
+            // Replace the line embeddings of new/changed documents, so that lines a
+            // document no longer has (e.g. after it shrank) don't linger in the
+            // workspace. Lines that only moved are written under their new line number.
+            if !replaced_paths.is_empty() {
+                store.replace_line_embeddings(&replaced_paths, &line_embeddings_to_upsert)?;
+            }
+
+            // Also update document metadata for tracking changes
+            if !docs_to_upsert.is_empty() {
+                store.upsert_document_metadata(&docs_to_upsert)?;
+            }
+            if !touched.is_empty() {
+                store.touch_document_metadata(&touched)?;
+            }
+            if !moved_from.is_empty() {
+                store.delete_document_metadata(&moved_from)?;
+                store.delete_line_embeddings(&moved_from)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    outcome.embedded = docs_to_upsert.len();
+    Ok(outcome)
+}
+
+/// The outcome of syncing files that the workspace is up to date with, or None
+/// when syncing them would write to it
+#[cfg(feature = "workspace")]
+fn up_to_date(
+    store: &Store,
+    files: &[String],
+    config: &SearchConfig,
+) -> Result<Option<SyncOutcome>> {
+    let mut outcome = SyncOutcome::default();
+    for state in store.analyze_document_states(files, config.max_file_size)? {
+        match state {
+            DocumentState::Unchanged(_) => outcome.unchanged += 1,
+            DocumentState::Skipped(skipped_file) => outcome.skipped.push(skipped_file),
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(outcome))
+}
+
+/// Line embeddings of a document's content for the workspace, one per line or span
+/// of a long line, carrying their text as written. Text that has a stored vector
+/// (by its fingerprint) reuses it, so after a small edit only inserted or modified
+/// lines are embedded. Also returns how many distinct texts were embedded.
+#[cfg(feature = "workspace")]
+fn workspace_line_embeddings(
+    path: &str,
+    content: &str,
+    model: &StaticModel,
+    ignore_case: bool,
+    stored: &HashMap<u64, Vec<f32>>,
+) -> (Vec<LineEmbedding>, usize) {
+    let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+    let (segments, originals) = segment_lines(&lines);
+    let texts: Vec<String> = if ignore_case {
+        originals.iter().map(|s| s.to_lowercase()).collect()
+    } else {
+        originals.clone()
+    };
+    let hashes: Vec<u64> = texts.iter().map(|text| line_text_hash(text)).collect();
+
+    // Embed each new text once, even when it appears on several lines
+    let mut missing: Vec<(u64, String)> = Vec::new();
+    let mut seen = HashSet::new();
+    for (text, &text_hash) in texts.iter().zip(&hashes) {
+        if !stored.contains_key(&text_hash) && seen.insert(text_hash) {
+            missing.push((text_hash, text.clone()));
+        }
+    }
+    let missing_texts: Vec<String> = missing.iter().map(|(_, text)| text.clone()).collect();
+    let embedded: HashMap<u64, Vec<f32>> = if missing_texts.is_empty() {
+        HashMap::new()
+    } else {
+        let embeddings = model.encode_with_args(&missing_texts, Some(2048), 16384);
+        missing
+            .iter()
+            .map(|(text_hash, _)| *text_hash)
+            .zip(embeddings)
+            .collect()
+    };
+
+    let line_embeddings = segments
+        .iter()
+        .zip(&hashes)
+        .zip(originals)
+        .map(|((segment, &text_hash), text)| LineEmbedding {
+            path: path.to_string(),
+            line_number: segment.line as i32, // Store as 0-based for consistency
+            start_column: segment.columns.map(|c| c.start),
+            end_column: segment.columns.map(|c| c.end),
+            text_hash: Some(text_hash),
+            text: Some(text),
+            embedding: stored
+                .get(&text_hash)
+                .or_else(|| embedded.get(&text_hash))
+                .cloned()
+                .unwrap_or_default(),
+        })
+        .collect();
+
+    (line_embeddings, missing.len())
+}
+
+/// Bring the workspace up to date for every file given, a batch of files at a
+/// time, calling `progress` with the number of files done after each batch
+#[cfg(feature = "workspace")]
+pub fn index_workspace(
+    files: &[String],
+    model: &StaticModel,
+    config: &SearchConfig,
+    workspace_name: Option<&str>,
+    mut progress: impl FnMut(usize, &SyncOutcome),
+) -> Result<SyncOutcome> {
+    let ws = Workspace::open(workspace_name)?;
+    let store = ws.open_store()?;
+
+    let mut outcome = SyncOutcome::default();
+    let mut done = 0;
+    for batch in files.chunks(INDEX_BATCH_FILES) {
+        outcome.merge(sync_workspace(&store, batch, model, config)?);
+        done += batch.len();
+        progress(done, &outcome);
+    }
+    Ok(outcome)
+}
+
+/// The files a workspace search covers: every document indexed in the workspace
+/// when no files are given, with a directory standing for the indexed documents
+/// under it. Documents whose files are gone are left out. Given paths are made
+/// canonical, like the paths of indexed documents.
+#[cfg(feature = "workspace")]
+pub fn workspace_files(files: &[String], workspace_name: Option<&str>) -> Result<Vec<String>> {
+    let files: Vec<String> = files
+        .iter()
+        .map(|file| walk::canonical_path(file).unwrap_or_else(|_| file.clone()))
+        .collect();
+    let is_dir = |path: &String| Path::new(path).is_dir();
+    if !files.is_empty() && !files.iter().any(is_dir) {
+        return Ok(files);
+    }
+
+    let ws = Workspace::open(workspace_name)?;
+    let store = ws.open_store_shared()?;
+    let mut indexed = store.get_all_document_paths()?;
+    indexed.retain(|path| Path::new(path).exists());
+    indexed.sort();
+    if files.is_empty() {
+        return Ok(indexed);
+    }
+
+    let mut resolved = Vec::new();
+    for file in files {
+        if is_dir(&file) {
+            resolved.extend(
+                indexed
+                    .iter()
+                    .filter(|path| walk::is_under(path, &file))
+                    .cloned(),
+            );
+        } else {
+            resolved.push(file);
+        }
+    }
+    let mut seen = HashSet::new();
+    resolved.retain(|path| seen.insert(path.clone()));
+    Ok(resolved)
+}
+
+#[cfg(feature = "workspace")]
+pub async fn search_with_workspace(
+    files: &[String],
+    query_embedding: &[f32],
+    model: &StaticModel,
+    config: &SearchConfig,
+    workspace_name: Option<&str>,
+) -> Result<SearchOutcome<RankedLine>> {
+    let workspace = WorkspaceSearch::open(files, model, config, workspace_name)?;
+    let distribution = if config.explain {
+        Some(workspace.line_distribution(files, query_embedding, config)?)
     } else {
-        owned_lines.clone()
+        None
     };
-
-    let embeddings = model.encode_with_args(&lines_for_embedding, Some(2048), 16384);
-    Some(Document {
-        filename,
-        lines: owned_lines,
-        embeddings,
+    Ok(SearchOutcome {
+        results: workspace.search(files, query_embedding, config)?,
+        skipped: workspace.skipped,
+        distribution,
     })
 }
 
-pub fn search_documents(
-    documents: &[Document],
+#[cfg(feature = "workspace")]
+pub async fn rank_documents_with_workspace(
+    files: &[String],
     query_embedding: &[f32],
+    model: &StaticModel,
     config: &SearchConfig,
-) -> Vec<SearchResult> {
-    let mut search_results = Vec::new();
-
-    for doc in documents {
-        for (idx, line_embedding) in doc.embeddings.iter().enumerate() {
-            let distance = f32::cosine(query_embedding, line_embedding);
-            if let Some(distance) = distance {
-                let distance_threshold = config.max_distance.unwrap_or(100.0);
-                if distance < distance_threshold {
-                    let bottom_range = max(0, idx.saturating_sub(config.n_lines));
-                    let top_range = min(doc.lines.len(), idx + config.n_lines + 1);
-
-                    search_results.push(SearchResult {
-                        filename: doc.filename.clone(),
-                        lines: doc.lines[bottom_range..top_range].to_vec(),
-                        distance,
-                        start: bottom_range,
-                        end: top_range,
-                        match_line: idx,
-                    })
-                }
+    aggregation: DocumentAggregation,
+    workspace_name: Option<&str>,
+) -> Result<SearchOutcome<RankedDocument>> {
+    let workspace = WorkspaceSearch::open(files, model, config, workspace_name)?;
+    let distribution = if config.explain {
+        Some(workspace.document_distribution(files, query_embedding, config, aggregation)?)
+    } else {
+        None
+    };
+    Ok(SearchOutcome {
+        results: workspace.rank_documents(files, query_embedding, config, aggregation)?,
+        skipped: workspace.skipped,
+        distribution,
+    })
+}
+
+/// A workspace store that has been brought up to date for a set of files,
+/// so that any number of queries can run against it without re-syncing
+#[cfg(feature = "workspace")]
+pub struct WorkspaceSearch {
+    store: Store,
+    indexed: Option<HashSet<String>>, // Indexed documents whose files exist, when the files may cover them all
+    gone: Vec<String>,                // Indexed documents whose files are gone
+    pub skipped: Vec<SkippedFile>,    // Files that couldn't be added to the workspace
+}
+
+#[cfg(feature = "workspace")]
+impl WorkspaceSearch {
+    pub fn open(
+        files: &[String],
+        model: &StaticModel,
+        config: &SearchConfig,
+        workspace_name: Option<&str>,
+    ) -> Result<Self> {
+        let ws = Workspace::open(workspace_name)?;
+        let files = config.filter.filter_paths(files);
+
+        // Searches that find nothing to update run alongside each other, the
+        // rest wait to have the workspace to themselves
+        let store = ws.open_store_shared()?;
+        let (store, outcome) = match up_to_date(&store, &files, config)? {
+            Some(outcome) => (store, outcome),
+            None => {
+                drop(store);
+                let store = ws.open_store()?;
+                let outcome = sync_workspace(&store, &files, model, config)?;
+                (store, outcome)
             }
+        };
+        if outcome.embedded > 0 {
+            eprintln!(
+                "Updated workspace with {} lines from {} new/changed documents",
+                outcome.lines, outcome.embedded
+            );
+        }
+        if !outcome.moved.is_empty() {
+            eprintln!(
+                "Recorded {} documents moved to a new path",
+                outcome.moved.len()
+            );
         }
+
+        Self::new(store, &files, outcome.skipped)
     }
 
-    // Sort by distance (best matches first)
-    search_results.sort_by(|a, b| {
-        a.distance
-            .partial_cmp(&b.distance)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
+    /// Search a store that is up to date for `files`
+    fn new(store: Store, files: &[String], skipped: Vec<SkippedFile>) -> Result<Self> {
+        // Queries over every indexed document that still exists can leave out the
+        // path filter, and only need to exclude the documents whose files are gone
+        let (present, gone): (Vec<String>, Vec<String>) = store
+            .get_all_document_paths()?
+            .into_iter()
+            .partition(|path| Path::new(path).exists());
+        let indexed = (present.len() <= files.len()).then(|| present.into_iter().collect());
+
+        Ok(Self {
+            store,
+            indexed,
+            gone,
+            skipped,
+        })
+    }
 
-    // If threshold is specified, return all results under threshold
-    // Otherwise, limit to top_k results
-    if config.max_distance.is_some() {
-        search_results
-    } else {
-        search_results.into_iter().take(config.top_k).collect()
+    /// Which documents the store looks at for a query over these files
+    fn scope<'a>(&'a self, files: &'a [String]) -> PathScope<'a> {
+        let covers_workspace = self.indexed.as_ref().is_some_and(|indexed| {
+            let files: HashSet<&String> = files.iter().collect();
+            indexed.iter().all(|path| files.contains(path))
+        });
+        if covers_workspace {
+            PathScope::AllExcept(&self.gone)
+        } else {
+            PathScope::Paths(files)
+        }
     }
-}
 
-pub fn search_files(
-    files: &[String],
-    query: &str,
-    model: &StaticModel,
-    config: &SearchConfig,
-) -> Result<Vec<SearchResult>> {
-    let mut documents = Vec::new();
-    for f in files {
-        let content = read_to_string(f)?;
-        if let Some(doc) =
-            create_document_from_content(f.clone(), &content, model, config.ignore_case)
-        {
-            documents.push(doc);
+    pub fn search(
+        &self,
+        files: &[String],
+        query_embedding: &[f32],
+        config: &SearchConfig,
+    ) -> Result<Vec<RankedLine>> {
+        let (files, line_filter) = candidate_files(files, config);
+        let scope = self.scope(&files);
+
+        // An adaptive cutoff becomes an absolute threshold, along with the number
+        // of hits under it, worked out from the best candidates. With a threshold
+        // every hit under it is returned, as when searching files.
+        let resolved_config;
+        let (config, limit) = match config.cutoff {
+            Some(cutoff) => {
+                let distribution = self.line_pool(scope, line_filter, query_embedding, config)?;
+                let cutoff_threshold = cutoff.threshold(&distribution);
+                let kept: Vec<f64> = distribution
+                    .distances()
+                    .iter()
+                    .copied()
+                    .filter(|&distance| passes_thresholds(config, distance, cutoff_threshold))
+                    .collect();
+                let Some(&last_kept) = kept.last() else {
+                    return Ok(Vec::new());
+                };
+                resolved_config = SearchConfig {
+                    max_distance: Some(last_kept + CUTOFF_EPSILON),
+                    top_k: kept.len(),
+                    cutoff: None,
+                    ..config.clone()
+                };
+                (&resolved_config, kept.len())
+            }
+            None if config.returns_all_hits() => (config, self.store.count_line_embeddings()?),
+            None => (config, config.top_k),
+        };
+
+        // Search line embeddings directly from the workspace
+        let max_distance = config.max_distance.map(|d| d as f32);
+        let mut ranked_lines = match config.mmr_lambda {
+            Some(lambda) if config.negative_embeddings.is_empty() => {
+                // Over-fetch by the number of excluded lines so that filtering
+                // them out still leaves top_k results
+                let excluded_lines: usize = config.exclude_spans.iter().map(|s| s.len()).sum();
+                let mut ranked_lines = self.store.search_line_embeddings_diverse(
+                    query_embedding,
+                    scope,
+                    line_filter,
+                    mmr_top_k(config).map(|top_k| top_k + excluded_lines),
+                    max_distance,
+                    lambda,
+                )?;
+                ranked_lines.retain(|r| !is_excluded(config, &r.path, r.line_number as usize));
+                ranked_lines.truncate(limit);
+                ranked_lines
+            }
+            Some(lambda) => {
+                let hits = filtered_line_hits(
+                    &self.store,
+                    query_embedding,
+                    scope,
+                    line_filter,
+                    mmr_top_k(config).map_or(limit, |top_k| top_k * MMR_CANDIDATE_FACTOR),
+                    max_distance,
+                    config,
+                )?;
+                let distances: Vec<f64> = hits.iter().map(|(r, _)| r.distance as f64).collect();
+                let embeddings: Vec<&[f32]> = hits.iter().map(|(_, e)| e.as_slice()).collect();
+                diversified_order(&distances, &embeddings, lambda, mmr_top_k(config))
+                    .into_iter()
+                    .map(|i| hits[i].0.clone())
+                    .collect()
+            }
+            None => filtered_line_hits(
+                &self.store,
+                query_embedding,
+                scope,
+                line_filter,
+                limit,
+                max_distance,
+                config,
+            )?
+            .into_iter()
+            .map(|(ranked_line, _)| ranked_line)
+            .collect(),
+        };
+
+        for ranked_line in &mut ranked_lines {
+            self.attach_snapshot(ranked_line, config.n_lines)?;
         }
+        Ok(ranked_lines)
     }
 
-    let query_embedding = model.encode_single(query);
+    /// Attach the indexed text of a hit's line and `n_lines` lines around it
+    fn attach_snapshot(&self, ranked_line: &mut RankedLine, n_lines: usize) -> Result<()> {
+        let line = ranked_line.line_number as usize;
+        ranked_line.snapshot = self.store.get_line_snapshot(
+            &ranked_line.path,
+            line.saturating_sub(n_lines),
+            line + n_lines + 1,
+        )?;
+        Ok(())
+    }
 
-    let results = search_documents(&documents, &query_embedding, config);
+    pub fn rank_documents(
+        &self,
+        files: &[String],
+        query_embedding: &[f32],
+        config: &SearchConfig,
+        aggregation: DocumentAggregation,
+    ) -> Result<Vec<RankedDocument>> {
+        let (files, line_filter) = candidate_files(files, config);
+        // With a threshold every document under it is returned, as when ranking files
+        let pool_size = match config.cutoff {
+            Some(_) => CUTOFF_CANDIDATES.max(config.top_k),
+            None if config.returns_all_hits() => self.store.count_documents()?,
+            None => config.top_k,
+        };
+        let pool = self.document_pool(
+            self.scope(&files),
+            line_filter,
+            query_embedding,
+            config,
+            aggregation,
+            pool_size,
+        )?;
+
+        let distribution = config
+            .cutoff
+            .map(|_| ScoreDistribution::new(pool.iter().map(|d| d.distance as f64).collect()));
+        let cutoff_threshold = cutoff_threshold(config, distribution.as_ref());
+        let mut ranked_docs: Vec<RankedDocument> = pool
+            .into_iter()
+            .filter(|d| passes_thresholds(config, d.distance as f64, cutoff_threshold))
+            .collect();
+        if !config.returns_all_hits() {
+            ranked_docs.truncate(config.top_k);
+        }
 
-    Ok(results)
-}
+        // Attach the best matching line of each document as its snippet
+        if aggregation == DocumentAggregation::Embedding {
+            for ranked_doc in &mut ranked_docs {
+                ranked_doc.best_line = filtered_line_hits(
+                    &self.store,
+                    query_embedding,
+                    PathScope::Paths(std::slice::from_ref(&ranked_doc.path)),
+                    line_filter,
+                    1,
+                    None,
+                    config,
+                )?
+                .into_iter()
+                .next()
+                .map(|(ranked_line, _)| ranked_line);
+            }
+        }
+        for ranked_line in ranked_docs.iter_mut().filter_map(|d| d.best_line.as_mut()) {
+            self.attach_snapshot(ranked_line, config.n_lines)?;
+        }
 
-#[cfg(feature = "workspace")]
-pub async fn search_with_workspace(
-    files: &[String],
-    query: &str,
-    model: &StaticModel,
-    config: &SearchConfig,
-    workspace_name: Option<&str>,
-) -> Result<Vec<RankedLine>> {
-    let query_embedding = model.encode_single(query);
-    let ws = Workspace::open(workspace_name)?;
-    let store = Store::open(&ws.config.root_dir)?;
+        Ok(ranked_docs)
+    }
 
-    // Step 1: Analyze document states (changed/new/unchanged)
-    let doc_states = store.analyze_document_states(files)?;
+    /// Distances of the best candidate lines, e.g. to explain a search
+    pub fn line_distribution(
+        &self,
+        files: &[String],
+        query_embedding: &[f32],
+        config: &SearchConfig,
+    ) -> Result<ScoreDistribution> {
+        let (files, line_filter) = candidate_files(files, config);
+        self.line_pool(self.scope(&files), line_filter, query_embedding, config)
+    }
 
-    // Step 2: Process documents that need embedding updates
-    let mut line_embeddings_to_upsert = Vec::new();
-    let mut docs_to_upsert = Vec::new();
+    /// Distances of the best candidate documents, e.g. to explain a search
+    pub fn document_distribution(
+        &self,
+        files: &[String],
+        query_embedding: &[f32],
+        config: &SearchConfig,
+        aggregation: DocumentAggregation,
+    ) -> Result<ScoreDistribution> {
+        let (files, line_filter) = candidate_files(files, config);
+        let pool = self.document_pool(
+            self.scope(&files),
+            line_filter,
+            query_embedding,
+            config,
+            aggregation,
+            CUTOFF_CANDIDATES.max(config.top_k),
+        )?;
+        Ok(ScoreDistribution::new(
+            pool.iter().map(|d| d.distance as f64).collect(),
+        ))
+    }
 
-    for state in &doc_states {
-        match state {
-            DocumentState::Changed(doc_info) | DocumentState::New(doc_info) => {
-                // Generate line-by-line embeddings and store them
-                if let Some(doc) = create_document_from_content(
-                    doc_info.filename.clone(),
-                    &doc_info.content,
-                    model,
-                    config.ignore_case,
-                ) {
-                    // Create LineEmbedding entries for each line
-                    for (line_idx, embedding) in doc.embeddings.iter().enumerate() {
-                        line_embeddings_to_upsert.push(LineEmbedding {
-                            path: doc_info.filename.clone(),
-                            line_number: line_idx as i32, // Store as 0-based for consistency
-                            embedding: embedding.clone(),
-                        });
-                    }
-                    // Also track document metadata for change detection
-                    docs_to_upsert.push(doc_info.meta.clone());
+    /// Distances of up to CUTOFF_CANDIDATES of the best lines, before any threshold
+    fn line_pool(
+        &self,
+        scope: PathScope,
+        line_filter: Option<&SearchFilter>,
+        query_embedding: &[f32],
+        config: &SearchConfig,
+    ) -> Result<ScoreDistribution> {
+        let hits = filtered_line_hits(
+            &self.store,
+            query_embedding,
+            scope,
+            line_filter,
+            CUTOFF_CANDIDATES.max(config.top_k),
+            None,
+            config,
+        )?;
+        Ok(ScoreDistribution::new(
+            hits.iter().map(|(r, _)| r.distance as f64).collect(),
+        ))
+    }
+
+    /// Up to `limit` of the best documents, sorted by distance, before any threshold.
+    /// Documents ranked by their embedding don't have their best line attached yet.
+    fn document_pool(
+        &self,
+        scope: PathScope,
+        line_filter: Option<&SearchFilter>,
+        query_embedding: &[f32],
+        config: &SearchConfig,
+        aggregation: DocumentAggregation,
+        limit: usize,
+    ) -> Result<Vec<RankedDocument>> {
+        if aggregation == DocumentAggregation::Embedding {
+            return self
+                .store
+                .search_document_embeddings(query_embedding, scope, limit, None);
+        }
+
+        // Line-based aggregations work over a pool of the best line hits. The lines
+        // of a document in the pool are its best ones, and every line left out is
+        // no closer than the farthest one in, so a document that comes out within
+        // that distance ranks as it would over all its lines. The pool grows until
+        // `limit` documents do, or it holds every line.
+        let top_n = match aggregation {
+            DocumentAggregation::MeanTopN(n) => n,
+            _ => 1,
+        };
+        let total_lines = self.store.count_line_embeddings()?.max(1);
+        let mut candidate_limit =
+            (limit.max(1) * top_n * DOCUMENT_CANDIDATE_FACTOR).min(total_lines);
+        loop {
+            let ranked_lines = filtered_line_hits(
+                &self.store,
+                query_embedding,
+                scope,
+                line_filter,
+                candidate_limit,
+                None,
+                config,
+            )?;
+            // Lines near negative queries are dropped after the search, so a short
+            // pool only shows every line was seen without them
+            let is_complete = candidate_limit >= total_lines
+                || (config.negative_embeddings.is_empty() && ranked_lines.len() < candidate_limit);
+            let pool_distance = ranked_lines
+                .last()
+                .map_or(f32::INFINITY, |(ranked_line, _)| ranked_line.distance);
+
+            // ranked_lines is sorted by distance, so each document's lines stay sorted too
+            let mut lines_by_doc: HashMap<String, Vec<RankedLine>> = HashMap::new();
+            for (ranked_line, _) in ranked_lines {
+                lines_by_doc
+                    .entry(ranked_line.path.clone())
+                    .or_default()
+                    .push(ranked_line);
+            }
+
+            let mut ranked_docs = Vec::new();
+            for (path, mut lines) in lines_by_doc {
+                if lines.len() < top_n && !is_complete {
+                    // Some of its best lines may be outside the pool
+                    lines = filtered_line_hits(
+                        &self.store,
+                        query_embedding,
+                        PathScope::Paths(std::slice::from_ref(&path)),
+                        line_filter,
+                        top_n,
+                        None,
+                        config,
+                    )?
+                    .into_iter()
+                    .map(|(ranked_line, _)| ranked_line)
+                    .collect();
+                }
+                let best_lines = &lines[..top_n.min(lines.len())];
+                let distance =
+                    best_lines.iter().map(|l| l.distance).sum::<f32>() / best_lines.len() as f32;
+                if is_complete || distance <= pool_distance {
+                    ranked_docs.push(RankedDocument {
+                        path,
+                        distance,
+                        best_line: lines.into_iter().next(),
+                    });
                 }
             }
-            DocumentState::Unchanged(_) => {
-                // Skip - already in workspace and unchanged
+
+            if is_complete || ranked_docs.len() >= limit {
+                ranked_docs.sort_by(|a, b| {
+                    a.distance
+                        .partial_cmp(&b.distance)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                ranked_docs.truncate(limit);
+                return Ok(ranked_docs);
             }
+            candidate_limit = (candidate_limit * 2).min(total_lines);
         }
     }
+}
 
-    // Step 3: Update workspace with new/changed line embeddings
-    if !line_embeddings_to_upsert.is_empty() {
-        eprintln!(
-            "Updating workspace with {} lines from new/changed docs...",
-            line_embeddings_to_upsert.len()
-        );
-        store.upsert_line_embeddings(&line_embeddings_to_upsert)?;
-    }
-
-    // Also update document metadata for tracking changes
-    if !docs_to_upsert.is_empty() {
-        eprintln!(
-            "Updating workspace with {} new/changed documents...",
-            docs_to_upsert.len()
-        );
-        store.upsert_document_metadata(&docs_to_upsert)?;
-    }
+/// The files a workspace query covers after path filters, along with the filter
+/// the indexed text of their lines must pass when there is a regex
+#[cfg(feature = "workspace")]
+fn candidate_files<'a>(
+    files: &[String],
+    config: &'a SearchConfig,
+) -> (Vec<String>, Option<&'a SearchFilter>) {
+    let line_filter = config.filter.has_line_regex().then_some(&config.filter);
+    (config.filter.filter_paths(files), line_filter)
+}
 
-    // Step 4: Search line embeddings directly from the workspace
-    let max_distance = config.max_distance.map(|d| d as f32);
-    let ranked_lines =
-        store.search_line_embeddings(&query_embedding, files, config.top_k, max_distance)?;
+/// Best line hits from the workspace, leaving out excluded spans and lines near negative queries.
+/// Vectors are only returned (non-empty) when negative queries are in use.
+#[cfg(feature = "workspace")]
+fn filtered_line_hits(
+    store: &Store,
+    query_embedding: &[f32],
+    scope: PathScope,
+    line_filter: Option<&SearchFilter>,
+    limit: usize,
+    max_distance: Option<f32>,
+    config: &SearchConfig,
+) -> Result<Vec<(RankedLine, Vec<f32>)>> {
+    let excluded_lines: usize = config.exclude_spans.iter().map(|s| s.len()).sum();
+
+    let hits = if config.negative_embeddings.is_empty() {
+        store
+            .search_line_embeddings(
+                query_embedding,
+                scope,
+                line_filter,
+                limit + excluded_lines,
+                max_distance,
+            )?
+            .into_iter()
+            .map(|ranked_line| (ranked_line, Vec::new()))
+            .collect()
+    } else {
+        store
+            .search_line_embeddings_with_vectors(
+                query_embedding,
+                scope,
+                line_filter,
+                limit * NEGATIVE_FILTER_CANDIDATE_FACTOR + excluded_lines,
+                max_distance,
+            )?
+            .into_iter()
+            .filter(|(ranked_line, embedding)| {
+                !is_near_negative(config, embedding, ranked_line.distance as f64)
+            })
+            .collect::<Vec<_>>()
+    };
 
-    Ok(ranked_lines)
+    Ok(hits
+        .into_iter()
+        .filter(|(r, _)| !is_excluded(config, &r.path, r.line_number as usize))
+        .take(limit)
+        .collect())
 }
 
 #[cfg(test)]
@@ -245,6 +1765,13 @@ mod tests {
             top_k: 3,
             max_distance: None,
             ignore_case: false,
+            mmr_lambda: None,
+            exclude_spans: vec![],
+            negative_embeddings: vec![],
+            max_file_size: None,
+            filter: SearchFilter::default(),
+            cutoff: None,
+            explain: false,
         }
     }
 
@@ -461,4 +1988,610 @@ mod tests {
         // But embeddings should be based on lowercase versions
         assert_eq!(doc.embeddings.len(), 2);
     }
+
+    #[test]
+    fn test_search_documents_mmr_skips_duplicates() {
+        let model = get_model();
+        let doc = create_test_document_with_model(
+            "dupes.txt",
+            vec![
+                "the cat sat on the mat",
+                "the cat sat on the mat",
+                "dogs bark loudly",
+            ],
+        );
+        let documents = vec![doc];
+
+        let query_embedding = model.encode_single("cat");
+        let mut config = create_test_config();
+        config.top_k = 2;
+        config.mmr_lambda = Some(0.0);
+
+        let results = search_documents(&documents, &query_embedding, &config);
+
+        assert_eq!(results.len(), 2);
+        let match_lines: Vec<usize> = results.iter().map(|r| r.match_line).collect();
+        // Only one of the two identical lines survives
+        assert!(match_lines.contains(&2));
+    }
+
+    #[test]
+    fn test_rank_documents_returns_one_result_per_file() {
+        let model = get_model();
+        let doc1 = create_test_document_with_model(
+            "fruit.txt",
+            vec![
+                "apples and oranges",
+                "bananas are yellow",
+                "grapes grow on vines",
+            ],
+        );
+        let doc2 = create_test_document_with_model(
+            "cars.txt",
+            vec!["the engine needs oil", "tires wear out", "brakes squeak"],
+        );
+        let documents = vec![doc1, doc2];
+
+        let query_embedding = model.encode_single("fruit");
+        let mut config = create_test_config();
+        config.top_k = 10;
+
+        for aggregation in [
+            DocumentAggregation::Max,
+            DocumentAggregation::MeanTopN(2),
+            DocumentAggregation::Embedding,
+        ] {
+            let results = rank_documents(&documents, &query_embedding, &config, aggregation);
+
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0].filename, "fruit.txt");
+            assert!(results[0].distance <= results[1].distance);
+            // The best match snippet belongs to the ranked file
+            assert_eq!(results[0].best_match.filename, "fruit.txt");
+        }
+    }
+
+    #[test]
+    fn test_rank_documents_max_uses_best_line() {
+        let model = get_model();
+        let doc = create_test_document_with_model("test.txt", vec!["line 1", "line 2", "line 3"]);
+        let documents = vec![doc];
+
+        let query_embedding = model.encode_single("test");
+        let config = create_test_config();
+
+        let line_results = search_documents(&documents, &query_embedding, &config);
+        let doc_results = rank_documents(
+            &documents,
+            &query_embedding,
+            &config,
+            DocumentAggregation::Max,
+        );
+
+        assert_eq!(doc_results.len(), 1);
+        assert_eq!(doc_results[0].distance, line_results[0].distance);
+        assert_eq!(
+            doc_results[0].best_match.match_line,
+            line_results[0].match_line
+        );
+    }
+
+    #[test]
+    fn test_document_aggregation_parse() {
+        assert_eq!(
+            DocumentAggregation::parse("max", 3).unwrap(),
+            DocumentAggregation::Max
+        );
+        assert_eq!(
+            DocumentAggregation::parse("MEAN", 5).unwrap(),
+            DocumentAggregation::MeanTopN(5)
+        );
+        assert_eq!(
+            DocumentAggregation::parse("mean", 0).unwrap(),
+            DocumentAggregation::MeanTopN(1)
+        );
+        assert_eq!(
+            DocumentAggregation::parse("embedding", 3).unwrap(),
+            DocumentAggregation::Embedding
+        );
+        assert!(DocumentAggregation::parse("median", 3).is_err());
+    }
+
+    #[test]
+    fn test_document_embedding_is_mean_of_lines() {
+        let embeddings = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![2.0, 2.0]];
+        let doc_embedding = document_embedding(&embeddings).unwrap();
+        assert_eq!(doc_embedding, vec![1.0, 1.0]);
+        assert!(document_embedding(&[]).is_none());
+    }
+
+    #[test]
+    fn test_line_span_parse() {
+        let span = LineSpan::parse("notes.md:40-55").unwrap();
+        assert_eq!(span.path, "notes.md");
+        assert_eq!((span.start, span.end), (39, 55));
+        assert_eq!(span.len(), 16);
+
+        let span = LineSpan::parse("C:/docs/notes.md:7").unwrap();
+        assert_eq!(span.path, "C:/docs/notes.md");
+        assert_eq!((span.start, span.end), (6, 7));
+        assert!(span.contains_line(6));
+        assert!(!span.contains_line(7));
+
+        assert!(LineSpan::parse("notes.md").is_err());
+        assert!(LineSpan::parse("notes.md:0-3").is_err());
+        assert!(LineSpan::parse("notes.md:5-3").is_err());
+        assert!(LineSpan::parse(":1-3").is_err());
+    }
+
+    #[test]
+    fn test_search_documents_skips_excluded_span() {
+        let model = get_model();
+        let doc = create_test_document_with_model(
+            "test.txt",
+            vec![
+                "the cat sat on the mat",
+                "dogs bark loudly",
+                "cats purr softly",
+            ],
+        );
+        let documents = vec![doc];
+
+        let query_embedding = model.encode_single("the cat sat on the mat");
+        let mut config = create_test_config();
+        config.top_k = 10;
+        config.exclude_spans.push(LineSpan {
+            path: "test.txt".to_string(),
+            start: 0,
+            end: 1,
+        });
+
+        let results = search_documents(&documents, &query_embedding, &config);
+
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|r| r.match_line != 0));
+    }
+
+    #[test]
+    fn test_search_documents_filters_near_negative() {
+        let model = get_model();
+        let doc = create_test_document_with_model(
+            "test.txt",
+            vec![
+                "oauth token refresh",
+                "password login form",
+                "oauth token refresh",
+            ],
+        );
+        let documents = vec![doc];
+
+        let query_embedding = model.encode_single("password login form");
+        let mut config = create_test_config();
+        config.top_k = 10;
+        // A negative identical to the first and last lines: they are closer to it than to the query
+        config.negative_embeddings = vec![model.encode_single("oauth token refresh")];
+
+        let results = search_documents(&documents, &query_embedding, &config);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].match_line, 1);
+    }
+
+    #[test]
+    fn test_search_files_matches_in_memory_search() {
+        let model = get_model();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let contents = [
+            "hello world\ngoodbye world\ntest line",
+            "another test\nmore content",
+            "the cat sat on the mat\ndogs bark loudly\ncats purr softly\nbirds sing",
+        ];
+        let files: Vec<String> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                let path = temp_dir.path().join(format!("file{i}.txt"));
+                std::fs::write(&path, content).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+
+        let query_embedding = model.encode_single("test query");
+        for config in [
+            create_test_config(),
+            SearchConfig {
+                max_distance: Some(2.0),
+                ..create_test_config()
+            },
+        ] {
+            let documents = load_documents(&files, model, &config).results;
+            let expected = search_documents(&documents, &query_embedding, &config);
+            let results = search_files(&files, &query_embedding, model, &config)
+                .unwrap()
+                .results;
+
+            assert_eq!(results.len(), expected.len());
+            for (result, expected) in results.iter().zip(&expected) {
+                assert_eq!(result.distance, expected.distance);
+                assert_eq!(result.lines, expected.lines);
+            }
+        }
+    }
+
+    #[test]
+    fn test_search_files_skips_binary_and_reports_it() {
+        let model = get_model();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = ["notes.txt", "latin1.txt", "data.bin", "missing.txt"]
+            .iter()
+            .map(|name| temp_dir.path().join(name).to_string_lossy().to_string())
+            .collect();
+        std::fs::write(&files[0], "hello world\ntest line").unwrap();
+        std::fs::write(&files[1], b"caf\xe9 au lait\nna\xefve test").unwrap();
+        std::fs::write(&files[2], [0x00, 0x01, 0x02, 0xFF]).unwrap();
+
+        let query_embedding = model.encode_single("test");
+        let mut config = create_test_config();
+        config.top_k = 10;
+
+        let outcome = search_files(&files, &query_embedding, model, &config).unwrap();
+
+        assert_eq!(outcome.results.len(), 4);
+        assert!(outcome.results.iter().any(|r| r.lines[0] == "café au lait"));
+        assert_eq!(outcome.skipped.len(), 2);
+        assert_eq!(outcome.skipped[0].path, files[2]);
+        assert_eq!(outcome.skipped[0].reason, SkipReason::Binary);
+        assert_eq!(outcome.skipped[1].path, files[3]);
+        assert!(matches!(
+            outcome.skipped[1].reason,
+            SkipReason::Unreadable(_)
+        ));
+    }
+
+    #[test]
+    fn test_search_documents_returns_span_of_long_line() {
+        let filler = "lorem ipsum dolor sit amet ".repeat(40);
+        let long_line = format!("{filler}the database connection pool is exhausted {filler}");
+        let doc = create_test_document_with_model("log.txt", vec!["short first line", &long_line]);
+        assert!(doc.segments.len() > doc.lines.len());
+
+        let query_embedding = get_model().encode_single("database connection pool exhausted");
+        let mut config = create_test_config();
+        config.top_k = 1;
+
+        let results = search_documents(&[doc], &query_embedding, &config);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].match_line, 1);
+
+        // Only the matching span of the line is shown, within its reported columns
+        let columns = results[0].match_columns.expect("Match should be a span");
+        assert!(columns.end - columns.start <= segment::MAX_SEGMENT_CHARS);
+        let shown = &results[0].lines[1];
+        assert!(shown.chars().count() < long_line.chars().count());
+        let span: String = long_line
+            .chars()
+            .skip(columns.start)
+            .take(columns.end - columns.start)
+            .collect();
+        assert!(shown.contains(&span));
+    }
+
+    #[test]
+    fn test_search_result_match_bytes() {
+        let content = "intro line\r\nthe quick brown fox\nlast";
+        let doc = create_document_from_content("crlf.txt".to_string(), content, get_model(), false)
+            .unwrap();
+        let result = search_result_for_segment(&doc, 1, 0.1, 1);
+
+        assert_eq!(result.match_text, "the quick brown fox");
+        assert_eq!(&content[result.match_bytes.clone()], "the quick brown fox");
+        assert!((result.score() - 0.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_search_files_applies_filters() {
+        let model = get_model();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = ["retries.md", "retries.txt"]
+            .iter()
+            .map(|name| temp_dir.path().join(name).to_string_lossy().to_string())
+            .collect();
+        for file in &files {
+            std::fs::write(
+                file,
+                "retry with exponential backoff\nretry after a timeout\nunrelated line",
+            )
+            .unwrap();
+        }
+
+        let query_embedding = model.encode_single("retry");
+        let mut config = create_test_config();
+        config.top_k = 10;
+        config.filter =
+            SearchFilter::new(Some("timeout"), &[], &[], &["md".to_string()], false).unwrap();
+
+        let results = search_files(&files, &query_embedding, model, &config)
+            .unwrap()
+            .results;
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].filename, files[0]);
+        assert_eq!(results[0].match_line, 1);
+    }
+
+    #[test]
+    fn test_search_documents_within_cutoff_ignores_top_k() {
+        let model = get_model();
+        let doc = create_test_document_with_model(
+            "test.txt",
+            vec!["line 1", "line 2", "line 3", "line 4", "line 5"],
+        );
+        let documents = vec![doc];
+        let query_embedding = model.encode_single("test");
+        let mut config = create_test_config();
+        config.top_k = 1;
+
+        config.cutoff = Some(Cutoff::Within(10.0));
+        let results = search_documents(&documents, &query_embedding, &config);
+        assert_eq!(results.len(), 5);
+
+        config.cutoff = Some(Cutoff::Within(0.0));
+        let results = search_documents(&documents, &query_embedding, &config);
+        assert!(!results.is_empty());
+        assert!(results.iter().all(|r| r.distance == results[0].distance));
+    }
+
+    #[test]
+    fn test_search_files_cutoff_matches_in_memory_search() {
+        let model = get_model();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = ["a.txt", "b.txt"]
+            .iter()
+            .map(|name| temp_dir.path().join(name).to_string_lossy().to_string())
+            .collect();
+        std::fs::write(&files[0], "hello world\ngoodbye world\ntest line").unwrap();
+        std::fs::write(&files[1], "another test\nmore content\nthe cat sat").unwrap();
+
+        let query_embedding = model.encode_single("test query");
+        let mut config = create_test_config();
+        config.cutoff = Some(Cutoff::Percentile(50.0));
+        config.explain = true;
+
+        let documents = load_documents(&files, model, &config).results;
+        let expected = search_documents(&documents, &query_embedding, &config);
+        let outcome = search_files(&files, &query_embedding, model, &config).unwrap();
+
+        assert_eq!(outcome.results.len(), expected.len());
+        // The best half, plus any lines tied with the last of them
+        assert!(outcome.results.len() >= 3);
+        let distribution = outcome
+            .distribution
+            .expect("Explain collects the distribution");
+        assert_eq!(distribution.len(), 6);
+        assert_eq!(
+            distribution.len(),
+            line_distribution(&documents, &query_embedding, &config).len()
+        );
+    }
+
+    /// A workspace store in a temporary directory, synced with files written there.
+    /// The directory comes first so that it outlives the store when dropped.
+    #[cfg(feature = "workspace")]
+    fn workspace_with_files(
+        contents: &[&str],
+    ) -> (tempfile::TempDir, WorkspaceSearch, Vec<String>) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                let path = temp_dir.path().join(format!("file{i}.txt"));
+                std::fs::write(&path, content).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let store_dir = temp_dir.path().join("workspace");
+        let store = Store::open(&store_dir.to_string_lossy()).unwrap();
+        sync_workspace(&store, &files, get_model(), &create_test_config()).unwrap();
+
+        let workspace = WorkspaceSearch::new(store, &files, Vec::new()).unwrap();
+        (temp_dir, workspace, files)
+    }
+
+    #[cfg(feature = "workspace")]
+    #[test]
+    fn test_sync_embeds_only_edited_lines() {
+        let (_temp_dir, workspace, files) = workspace_with_files(&["alpha\nbeta\ngamma"]);
+        let store = &workspace.store;
+        let before = store.get_line_vectors(&files).unwrap();
+
+        // A line inserted at the top shifts every other line down by one
+        std::fs::write(&files[0], "preface\nalpha\nbeta\ngamma").unwrap();
+        let outcome = sync_workspace(store, &files, get_model(), &create_test_config()).unwrap();
+        assert_eq!(outcome.embedded, 1);
+        assert_eq!(outcome.lines, 1);
+
+        let snapshot = store.get_line_snapshot(&files[0], 0, 4).unwrap().unwrap();
+        assert_eq!(snapshot.lines, vec!["preface", "alpha", "beta", "gamma"]);
+
+        // The shifted lines kept the vectors stored for their text
+        let after = store.get_line_vectors(&files).unwrap();
+        let (before, after) = (&before[&files[0]], &after[&files[0]]);
+        assert_eq!(after.len(), 4);
+        for (text_hash, vector) in before {
+            assert_eq!(&after[text_hash], vector);
+        }
+    }
+
+    #[cfg(feature = "workspace")]
+    #[test]
+    fn test_sync_records_a_renamed_file_as_moved() {
+        let (temp_dir, workspace, files) = workspace_with_files(&["alpha\nbeta", "other"]);
+        let store = &workspace.store;
+
+        let renamed = temp_dir.path().join("renamed.txt");
+        let renamed = renamed.to_string_lossy().to_string();
+        std::fs::rename(&files[0], &renamed).unwrap();
+        let files = vec![files[1].clone(), renamed.clone()];
+        let old_path = temp_dir.path().join("file0.txt");
+        let old_path = old_path.to_string_lossy().to_string();
+
+        let outcome = sync_workspace(store, &files, get_model(), &create_test_config()).unwrap();
+        assert_eq!(outcome.moved, vec![(old_path.clone(), renamed.clone())]);
+        assert_eq!(outcome.lines, 0);
+
+        let mut indexed = store.get_all_document_paths().unwrap();
+        indexed.sort();
+        assert_eq!(indexed, files);
+        let old_lines = store.get_line_snapshot(&old_path, 0, 2).unwrap();
+        assert!(old_lines.is_none_or(|snapshot| snapshot.lines.is_empty()));
+        let snapshot = store.get_line_snapshot(&renamed, 0, 2).unwrap().unwrap();
+        assert_eq!(snapshot.lines, vec!["alpha", "beta"]);
+    }
+
+    #[cfg(feature = "workspace")]
+    #[test]
+    fn test_recovered_document_stays_in_whole_workspace_searches() {
+        let (temp_dir, workspace, files) =
+            workspace_with_files(&["hello world", "the cat sat on the mat"]);
+        drop(workspace);
+
+        // As if a process died while updating the first document
+        let store_dir = temp_dir.path().join("workspace");
+        let journal = serde_json::json!({ "paths": [files[0]] });
+        std::fs::write(store_dir.join("journal.json"), journal.to_string()).unwrap();
+        let store = Store::open(&store_dir.to_string_lossy()).unwrap();
+        let mut indexed = store.get_all_document_paths().unwrap();
+        indexed.sort();
+        assert_eq!(indexed, files);
+
+        // The next sync embeds it again, reusing the vectors of its lines
+        let outcome = sync_workspace(&store, &files, get_model(), &create_test_config()).unwrap();
+        assert_eq!((outcome.embedded, outcome.lines), (1, 0));
+
+        let workspace = WorkspaceSearch::new(store, &files, Vec::new()).unwrap();
+        assert!(matches!(workspace.scope(&files), PathScope::AllExcept([])));
+        let config = SearchConfig {
+            max_distance: Some(2.0),
+            ..create_test_config()
+        };
+        let ranked = workspace
+            .search(&files, &get_model().encode_single("hello"), &config)
+            .unwrap();
+        assert!(ranked.iter().any(|r| r.path == files[0]));
+    }
+
+    #[cfg(feature = "workspace")]
+    #[test]
+    fn test_workspace_search_covers_workspace_after_a_file_is_deleted() {
+        let (_temp_dir, workspace, files) =
+            workspace_with_files(&["hello world", "another test", "the cat sat on the mat"]);
+        std::fs::remove_file(&files[1]).unwrap();
+        let WorkspaceSearch { store, .. } = workspace;
+        let remaining = vec![files[0].clone(), files[2].clone()];
+        let workspace = WorkspaceSearch::new(store, &remaining, Vec::new()).unwrap();
+
+        // The remaining files are the whole workspace, minus the deleted document
+        assert!(matches!(
+            workspace.scope(&remaining),
+            PathScope::AllExcept(gone) if gone == [files[1].clone()]
+        ));
+        assert!(matches!(
+            workspace.scope(&remaining[..1]),
+            PathScope::Paths(_)
+        ));
+
+        let query_embedding = get_model().encode_single("test");
+        let config = SearchConfig {
+            max_distance: Some(2.0),
+            ..create_test_config()
+        };
+        let ranked = workspace
+            .search(&remaining, &query_embedding, &config)
+            .unwrap();
+        assert!(!ranked.is_empty());
+        assert!(ranked.iter().all(|r| r.path != files[1]));
+    }
+
+    #[cfg(feature = "workspace")]
+    #[test]
+    fn test_workspace_rank_documents_threshold_ignores_top_k() {
+        let (_temp_dir, workspace, files) =
+            workspace_with_files(&["hello world", "another test", "the cat sat on the mat"]);
+        let query_embedding = get_model().encode_single("test");
+        let config = SearchConfig {
+            top_k: 1,
+            max_distance: Some(2.0),
+            ..create_test_config()
+        };
+
+        for aggregation in [DocumentAggregation::Max, DocumentAggregation::Embedding] {
+            let ranked = workspace
+                .rank_documents(&files, &query_embedding, &config, aggregation)
+                .unwrap();
+            assert_eq!(ranked.len(), 3);
+        }
+    }
+
+    #[cfg(feature = "workspace")]
+    #[test]
+    fn test_workspace_rank_documents_matches_in_memory_ranking() {
+        // Documents with a single strong line among many others, so that their
+        // best few lines don't all make it into a small pool of line hits
+        let filler = |topic: &str| {
+            (0..30)
+                .map(|i| format!("{topic} number {i}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let contents = [
+            format!("ripe bananas\n{}", filler("invoice")),
+            "apples\npears and plums\na bowl of cherries\nthe meeting is at noon".to_string(),
+            filler("weather report"),
+            format!("oranges and lemons\ngrapes\n{}", filler("train timetable")),
+        ];
+        let contents: Vec<&str> = contents.iter().map(String::as_str).collect();
+        let (_temp_dir, workspace, files) = workspace_with_files(&contents);
+        let query_embedding = get_model().encode_single("fruit");
+
+        for top_k in [1, 3] {
+            let config = SearchConfig {
+                top_k,
+                ..create_test_config()
+            };
+            let documents = load_documents(&files, get_model(), &config).results;
+            for aggregation in [DocumentAggregation::Max, DocumentAggregation::MeanTopN(3)] {
+                let in_memory = rank_documents(&documents, &query_embedding, &config, aggregation);
+                let ranked = workspace
+                    .rank_documents(&files, &query_embedding, &config, aggregation)
+                    .unwrap();
+
+                let paths: Vec<&str> = ranked.iter().map(|r| r.path.as_str()).collect();
+                let expected: Vec<&str> = in_memory.iter().map(|r| r.filename.as_str()).collect();
+                assert_eq!(paths, expected, "{aggregation:?}, top {top_k}");
+                for (ranked, expected) in ranked.iter().zip(&in_memory) {
+                    assert!((ranked.distance as f64 - expected.distance).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parsed_source_document() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let parsed = temp_dir.path().join("report.pdf.md");
+        std::fs::write(&parsed, "# Report").unwrap();
+        std::fs::write(
+            temp_dir.path().join("report.pdf.metadata.json"),
+            r#"{"modified_time": 1, "size": 2, "parsed_path": "x", "source_path": "/docs/report.pdf"}"#,
+        )
+        .unwrap();
+
+        let parsed = parsed.to_string_lossy().to_string();
+        assert_eq!(
+            parsed_source_document(&parsed).as_deref(),
+            Some("/docs/report.pdf")
+        );
+        assert_eq!(parsed_source_document("/docs/notes.txt"), None);
+    }
 }
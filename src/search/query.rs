@@ -0,0 +1,301 @@
+//! Queries made of several weighted parts, combined in vector space before ranking
+use anyhow::Result;
+use model2vec_rs::model::StaticModel;
+use serde::Deserialize;
+
+use super::SearchConfig;
+
+/// Weight given to a negative query part when none is specified
+pub const DEFAULT_NEGATIVE_WEIGHT: f32 = 0.5;
+
+/// A single query string with its weight, written as `text` or `text^weight`
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedQuery {
+    pub text: String,
+    pub weight: f32,
+}
+
+impl WeightedQuery {
+    /// Parse `text^weight`, falling back to `default_weight` when there is no valid suffix
+    pub fn parse(spec: &str, default_weight: f32) -> Self {
+        if let Some((text, weight)) = spec.rsplit_once('^')
+            && !text.is_empty()
+            && let Ok(weight) = weight.trim().parse::<f32>()
+            && weight.is_finite()
+        {
+            return Self {
+                text: text.to_string(),
+                weight,
+            };
+        }
+
+        Self {
+            text: spec.to_string(),
+            weight: default_weight,
+        }
+    }
+}
+
+/// What to do with negative query parts
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NegativeMode {
+    /// Subtract the negative embeddings from the query vector
+    #[default]
+    Subtract,
+    /// Keep the query vector as is and drop results closer to a negative part than to the query
+    Filter,
+}
+
+impl NegativeMode {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "subtract" => Ok(Self::Subtract),
+            "filter" => Ok(Self::Filter),
+            _ => Err(anyhow::anyhow!(
+                "Invalid negative mode: '{}'. Must be 'subtract' or 'filter'",
+                name
+            )),
+        }
+    }
+}
+
+/// A query made of positive parts to move towards and negative parts to steer away from
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CompositeQuery {
+    pub positive: Vec<WeightedQuery>,
+    pub negative: Vec<WeightedQuery>,
+    pub negative_mode: NegativeMode,
+}
+
+impl CompositeQuery {
+    /// A plain single-string query
+    pub fn single(text: &str) -> Self {
+        Self {
+            positive: vec![WeightedQuery {
+                text: text.to_string(),
+                weight: 1.0,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Embed the query into a single vector.
+    ///
+    /// In `Filter` mode the negative embeddings are added to `config` so that
+    /// the search can drop results that are too close to them.
+    pub fn embed(&self, model: &StaticModel, config: &mut SearchConfig) -> Result<Vec<f32>> {
+        let total_weight: f32 = self.positive.iter().map(|part| part.weight).sum();
+        if self.positive.is_empty() || total_weight <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "A query needs at least one part with a positive weight"
+            ));
+        }
+
+        let ignore_case = config.ignore_case;
+        let prepare = |text: &str| {
+            if ignore_case {
+                text.to_lowercase()
+            } else {
+                text.to_string()
+            }
+        };
+
+        // A plain query is embedded exactly as before
+        if self.positive.len() == 1 && self.negative.is_empty() {
+            return Ok(model.encode_single(&prepare(&self.positive[0].text)));
+        }
+
+        let encode = |part: &WeightedQuery| normalized(model.encode_single(&prepare(&part.text)));
+
+        // Weighted mean of the positive parts
+        let mut query = Vec::new();
+        for part in &self.positive {
+            add_scaled(&mut query, &encode(part), part.weight / total_weight);
+        }
+
+        match self.negative_mode {
+            NegativeMode::Subtract => {
+                for part in &self.negative {
+                    add_scaled(&mut query, &encode(part), -part.weight);
+                }
+            }
+            NegativeMode::Filter => {
+                let negatives: Vec<Vec<f32>> = self.negative.iter().map(encode).collect();
+                config.negative_embeddings.extend(negatives);
+            }
+        }
+
+        Ok(query)
+    }
+}
+
+/// One query of a `--queries` file.
+///
+/// Each line of the file is either a plain query string or a JSON object
+/// with an optional `id` and per-query options that override the command line.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct BatchQuery {
+    pub id: Option<String>,
+    pub query: String,
+    #[serde(default)]
+    pub and: Vec<String>,
+    #[serde(default)]
+    pub not: Vec<String>,
+    pub negative_mode: Option<String>,
+    pub n_lines: Option<usize>,
+    pub top_k: Option<usize>,
+    pub max_distance: Option<f64>,
+    pub mmr_lambda: Option<f64>,
+}
+
+impl BatchQuery {
+    /// The combined query, using `default_mode` when the entry doesn't set one
+    pub fn composite(&self, default_mode: NegativeMode) -> Result<CompositeQuery> {
+        let negative_mode = match &self.negative_mode {
+            Some(name) => NegativeMode::parse(name)?,
+            None => default_mode,
+        };
+
+        Ok(CompositeQuery {
+            positive: std::iter::once(&self.query)
+                .chain(&self.and)
+                .map(|part| WeightedQuery::parse(part, 1.0))
+                .collect(),
+            negative: self
+                .not
+                .iter()
+                .map(|part| WeightedQuery::parse(part, DEFAULT_NEGATIVE_WEIGHT))
+                .collect(),
+            negative_mode,
+        })
+    }
+
+    /// The search configuration for this query, starting from `base`
+    pub fn config(&self, base: &SearchConfig) -> SearchConfig {
+        let mut config = base.clone();
+        config.n_lines = self.n_lines.unwrap_or(base.n_lines);
+        config.top_k = self.top_k.unwrap_or(base.top_k);
+        config.max_distance = self.max_distance.or(base.max_distance);
+        config.mmr_lambda = self.mmr_lambda.or(base.mmr_lambda);
+        config
+    }
+}
+
+/// Parse the contents of a `--queries` file, skipping blank lines.
+/// Queries without an `id` are identified by their 1-based line number.
+pub fn parse_batch_queries(content: &str) -> Result<Vec<BatchQuery>> {
+    let mut queries = Vec::new();
+
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut query = if line.starts_with('{') {
+            serde_json::from_str::<BatchQuery>(line)
+                .map_err(|e| anyhow::anyhow!("Invalid query on line {}: {}", idx + 1, e))?
+        } else {
+            BatchQuery {
+                query: line.to_string(),
+                ..Default::default()
+            }
+        };
+        query.id.get_or_insert_with(|| (idx + 1).to_string());
+        queries.push(query);
+    }
+
+    Ok(queries)
+}
+
+/// Scale a vector to unit length so every part contributes according to its weight only
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+fn add_scaled(target: &mut Vec<f32>, vector: &[f32], scale: f32) {
+    if target.is_empty() {
+        target.resize(vector.len(), 0.0);
+    }
+    for (total, value) in target.iter_mut().zip(vector) {
+        *total += value * scale;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_query_parse() {
+        assert_eq!(
+            WeightedQuery::parse("authentication", 1.0),
+            WeightedQuery {
+                text: "authentication".to_string(),
+                weight: 1.0
+            }
+        );
+        assert_eq!(
+            WeightedQuery::parse("oauth^2", 0.5),
+            WeightedQuery {
+                text: "oauth".to_string(),
+                weight: 2.0
+            }
+        );
+        // A caret that isn't followed by a number is part of the text
+        assert_eq!(WeightedQuery::parse("x^y", 1.0).text, "x^y");
+        assert_eq!(WeightedQuery::parse("^3", 1.0).text, "^3");
+    }
+
+    #[test]
+    fn test_negative_mode_parse() {
+        assert_eq!(
+            NegativeMode::parse("subtract").unwrap(),
+            NegativeMode::Subtract
+        );
+        assert_eq!(NegativeMode::parse("FILTER").unwrap(), NegativeMode::Filter);
+        assert!(NegativeMode::parse("ignore").is_err());
+    }
+
+    #[test]
+    fn test_add_scaled_and_normalized() {
+        let mut total = Vec::new();
+        add_scaled(&mut total, &normalized(vec![3.0, 4.0]), 1.0);
+        add_scaled(&mut total, &[1.0, 0.0], -0.5);
+        assert!((total[0] - 0.1).abs() < 1e-6);
+        assert!((total[1] - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_parse_batch_queries() {
+        let content = r#"authentication
+
+{"id": "q-oauth", "query": "login", "not": ["oauth^0.8"], "top_k": 10}
+"#;
+        let queries = parse_batch_queries(content).unwrap();
+
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].id.as_deref(), Some("1"));
+        assert_eq!(queries[0].query, "authentication");
+        assert_eq!(queries[1].id.as_deref(), Some("q-oauth"));
+        assert_eq!(queries[1].top_k, Some(10));
+
+        let composite = queries[1].composite(NegativeMode::Filter).unwrap();
+        assert_eq!(composite.negative_mode, NegativeMode::Filter);
+        assert_eq!(composite.negative[0].weight, 0.8);
+
+        let base = SearchConfig {
+            n_lines: 3,
+            top_k: 3,
+            ..Default::default()
+        };
+        let config = queries[1].config(&base);
+        assert_eq!((config.n_lines, config.top_k), (3, 10));
+
+        assert!(parse_batch_queries("{\"id\": \"missing query\"}").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Reading several logical documents from a single stream, e.g. database
+//! exports or `git log -p` split per commit, piped into `search` or `ask`
+use anyhow::{Result, anyhow};
+use serde_json::{Map, Value};
+
+use super::text::decode_text;
+
+/// Name of the single document read from stdin in `text` format
+pub const STDIN_NAME: &str = "<stdin>";
+
+/// How the input on stdin is split into documents
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StdinFormat {
+    /// All of stdin is one document
+    #[default]
+    Text,
+    /// One JSON object per line with `text`, an `id` or `path`, and optional metadata
+    Jsonl,
+    /// Documents separated by NUL bytes
+    Nul,
+}
+
+impl StdinFormat {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "jsonl" => Ok(Self::Jsonl),
+            "nul" | "null" | "0" => Ok(Self::Nul),
+            _ => Err(anyhow!(
+                "Invalid stdin format: '{}'. Must be 'text', 'jsonl' or 'nul'",
+                name
+            )),
+        }
+    }
+}
+
+/// A document read from stdin
+#[derive(Debug, Clone, PartialEq)]
+pub struct StdinRecord {
+    pub id: String, // Shown in results and citations in place of a file path
+    pub text: String,
+    pub metadata: Map<String, Value>,
+}
+
+/// Split the bytes read from stdin into documents. Records without an id are
+/// named after their position, e.g. `<stdin>:3` for the third one.
+pub fn parse_records(bytes: &[u8], format: StdinFormat) -> Result<Vec<StdinRecord>> {
+    let unnamed = |number: usize, text: String| StdinRecord {
+        id: format!("{STDIN_NAME}:{number}"),
+        text,
+        metadata: Map::new(),
+    };
+
+    match format {
+        StdinFormat::Text => {
+            let text = decode_text(bytes)
+                .ok_or_else(|| anyhow!("Input on stdin looks like binary data"))?;
+            Ok(vec![StdinRecord {
+                id: STDIN_NAME.to_string(),
+                text,
+                metadata: Map::new(),
+            }])
+        }
+        StdinFormat::Nul => bytes
+            .split(|&byte| byte == 0)
+            .filter(|chunk| !chunk.iter().all(u8::is_ascii_whitespace))
+            .enumerate()
+            .map(|(idx, chunk)| {
+                decode_text(chunk)
+                    .map(|text| unnamed(idx + 1, text))
+                    .ok_or_else(|| anyhow!("Document {} on stdin looks like binary data", idx + 1))
+            })
+            .collect(),
+        StdinFormat::Jsonl => {
+            let text = decode_text(bytes)
+                .ok_or_else(|| anyhow!("Input on stdin looks like binary data"))?;
+            let mut records = Vec::new();
+            for (idx, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let record = parse_record(line)
+                    .map_err(|e| anyhow!("Invalid record on line {} of stdin: {}", idx + 1, e))?;
+                let number = records.len() + 1;
+                records.push(match record {
+                    (Some(id), text, metadata) => StdinRecord { id, text, metadata },
+                    (None, text, metadata) => StdinRecord {
+                        metadata,
+                        ..unnamed(number, text)
+                    },
+                });
+            }
+            Ok(records)
+        }
+    }
+}
+
+/// The id, text and metadata of a JSON record. Metadata is taken from a
+/// `metadata` object and from any other top-level fields.
+fn parse_record(line: &str) -> Result<(Option<String>, String, Map<String, Value>)> {
+    let Value::Object(mut fields) = serde_json::from_str::<Value>(line)? else {
+        return Err(anyhow!("expected a JSON object"));
+    };
+
+    let text = match fields.remove("text") {
+        Some(Value::String(text)) => text,
+        Some(_) => return Err(anyhow!("\"text\" must be a string")),
+        None => return Err(anyhow!("missing \"text\"")),
+    };
+    let id = match fields.remove("id").or_else(|| fields.remove("path")) {
+        Some(Value::String(id)) => Some(id),
+        Some(Value::Number(id)) => Some(id.to_string()),
+        Some(Value::Null) | None => None,
+        Some(_) => return Err(anyhow!("\"id\" must be a string or a number")),
+    };
+    fields.remove("path");
+
+    let mut metadata = match fields.remove("metadata") {
+        Some(Value::Object(metadata)) => metadata,
+        Some(Value::Null) | None => Map::new(),
+        Some(_) => return Err(anyhow!("\"metadata\" must be an object")),
+    };
+    for (key, value) in fields {
+        metadata.entry(key).or_insert(value);
+    }
+
+    Ok((id, text, metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jsonl_records() {
+        let input = concat!(
+            "{\"id\": \"ticket-1\", \"text\": \"login fails\", \"metadata\": {\"team\": \"auth\"}}\n",
+            "\n",
+            "{\"path\": \"src/lib.rs\", \"text\": \"fn main() {}\", \"lang\": \"rust\"}\n",
+            "{\"text\": \"no id\", \"id\": 42}\n",
+            "{\"text\": \"anonymous\"}\n",
+        );
+        let records = parse_records(input.as_bytes(), StdinFormat::Jsonl).unwrap();
+        let ids: Vec<&str> = records.iter().map(|record| record.id.as_str()).collect();
+        assert_eq!(ids, vec!["ticket-1", "src/lib.rs", "42", "<stdin>:4"]);
+        assert_eq!(records[0].metadata["team"], "auth");
+        assert_eq!(records[1].metadata["lang"], "rust");
+        assert_eq!(records[1].text, "fn main() {}");
+
+        let error = parse_records(b"{\"id\": \"x\"}\n", StdinFormat::Jsonl).unwrap_err();
+        assert!(error.to_string().contains("line 1"));
+    }
+
+    #[test]
+    fn test_nul_separated_records() {
+        let records = parse_records(b"first doc\nline 2\0second\0\n", StdinFormat::Nul).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id, "<stdin>:1");
+        assert_eq!(records[0].text, "first doc\nline 2");
+        assert_eq!(records[1].text, "second");
+    }
+
+    #[test]
+    fn test_text_is_a_single_document() {
+        let records = parse_records(b"a\nb\n", StdinFormat::Text).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "<stdin>");
+        assert!(parse_records(b"bin\0ary", StdinFormat::Text).is_err());
+        assert_eq!(StdinFormat::parse("NUL").unwrap(), StdinFormat::Nul);
+        assert!(StdinFormat::parse("csv").is_err());
+    }
+}
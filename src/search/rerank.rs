@@ -0,0 +1,135 @@
+//! Re-ranking stages applied after the initial distance-sorted retrieval
+use simsimd::SpatialSimilarity;
+
+/// How many candidates per requested result are considered for diversity re-ranking
+pub const MMR_CANDIDATE_FACTOR: usize = 10;
+
+/// Upper bound on candidates re-ranked when returning everything under a threshold
+pub const MMR_MAX_CANDIDATES: usize = 1000;
+
+/// Maximal marginal relevance selection.
+///
+/// Picks up to `k` candidates, each time taking the one that maximizes
+/// `lambda * relevance - (1 - lambda) * max_similarity_to_already_picked`.
+/// `lambda = 1.0` keeps the original ranking, `lambda = 0.0` only optimizes for diversity.
+///
+/// Returns indices into `distances`/`embeddings` in the selected order.
+pub fn mmr_select(distances: &[f64], embeddings: &[&[f32]], lambda: f64, k: usize) -> Vec<usize> {
+    let lambda = lambda.clamp(0.0, 1.0);
+    let n = distances.len().min(embeddings.len());
+    let k = k.min(n);
+
+    let mut selected: Vec<usize> = Vec::with_capacity(k);
+    let mut remaining: Vec<usize> = (0..n).collect();
+    // Highest similarity of each candidate to anything selected so far
+    let mut max_similarity = vec![0_f64; n];
+
+    while selected.len() < k {
+        let mut best_pos = 0;
+        let mut best_score = f64::NEG_INFINITY;
+        for (pos, &candidate) in remaining.iter().enumerate() {
+            let relevance = 1.0 - distances[candidate];
+            let redundancy = if selected.is_empty() {
+                0.0
+            } else {
+                max_similarity[candidate]
+            };
+            let score = lambda * relevance - (1.0 - lambda) * redundancy;
+            if score > best_score {
+                best_score = score;
+                best_pos = pos;
+            }
+        }
+
+        let picked = remaining.swap_remove(best_pos);
+        selected.push(picked);
+
+        for &candidate in &remaining {
+            let similarity = f32::cosine(embeddings[picked], embeddings[candidate])
+                .map(|distance| 1.0 - distance)
+                .unwrap_or(0.0);
+            if similarity > max_similarity[candidate] {
+                max_similarity[candidate] = similarity;
+            }
+        }
+    }
+
+    selected
+}
+
+/// Order in which distance-sorted candidates are returned after re-ranking.
+/// With `top_k`, that many are picked from a pool of `top_k * MMR_CANDIDATE_FACTOR`.
+/// Without (every hit under a threshold is returned), all candidates are kept
+/// and only the head of the list is re-ordered.
+pub fn diversified_order(
+    distances: &[f64],
+    embeddings: &[&[f32]],
+    lambda: f64,
+    top_k: Option<usize>,
+) -> Vec<usize> {
+    let (pool_size, k) = match top_k {
+        Some(top_k) => (distances.len().min(top_k * MMR_CANDIDATE_FACTOR), top_k),
+        None => {
+            let pool_size = distances.len().min(MMR_MAX_CANDIDATES);
+            (pool_size, pool_size)
+        }
+    };
+
+    let mut order = mmr_select(&distances[..pool_size], &embeddings[..pool_size], lambda, k);
+    if top_k.is_none() {
+        order.extend(pool_size..distances.len());
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmr_select_lambda_one_keeps_order() {
+        let embeddings = [vec![1.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0]];
+        let embedding_refs: Vec<&[f32]> = embeddings.iter().map(|e| e.as_slice()).collect();
+        let distances = [0.1, 0.2, 0.3];
+
+        let order = mmr_select(&distances, &embedding_refs, 1.0, 3);
+        assert_eq!(order, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_mmr_select_skips_near_duplicates() {
+        // The second candidate duplicates the first one, the third is different
+        let embeddings = [vec![1.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0]];
+        let embedding_refs: Vec<&[f32]> = embeddings.iter().map(|e| e.as_slice()).collect();
+        let distances = [0.1, 0.15, 0.3];
+
+        let order = mmr_select(&distances, &embedding_refs, 0.5, 2);
+        assert_eq!(order, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_diversified_order_keeps_every_hit_without_top_k() {
+        let embeddings = [vec![1.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0]];
+        let embedding_refs: Vec<&[f32]> = embeddings.iter().map(|e| e.as_slice()).collect();
+        let distances = [0.1, 0.15, 0.3];
+
+        assert_eq!(
+            diversified_order(&distances, &embedding_refs, 0.5, Some(2)),
+            vec![0, 2]
+        );
+        assert_eq!(
+            diversified_order(&distances, &embedding_refs, 0.5, None),
+            vec![0, 2, 1]
+        );
+    }
+
+    #[test]
+    fn test_mmr_select_limits_to_available_candidates() {
+        let embeddings = [vec![1.0, 0.0]];
+        let embedding_refs: Vec<&[f32]> = embeddings.iter().map(|e| e.as_slice()).collect();
+
+        let order = mmr_select(&[0.1], &embedding_refs, 0.5, 5);
+        assert_eq!(order, vec![0]);
+        assert!(mmr_select(&[], &[], 0.5, 5).is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+//! Splitting overly long lines (minified JSON, logs, extracted paragraphs) into
+//! spans that are embedded and ranked separately
+use std::ops::Range;
+
+/// Lines longer than this many characters are split into several spans
+pub const MAX_SEGMENT_CHARS: usize = 512;
+
+/// A range of characters within a line (0-based, end-exclusive)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColumnRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The unit that gets one embedding: a whole line, or a span of a long line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineSegment {
+    pub line: usize,
+    pub columns: Option<ColumnRange>, // None when the segment is the whole line
+}
+
+/// Split lines into segments, returning them along with the text to embed for each
+pub fn segment_lines(lines: &[String]) -> (Vec<LineSegment>, Vec<String>) {
+    let mut segments = Vec::with_capacity(lines.len());
+    let mut texts = Vec::with_capacity(lines.len());
+
+    for (line_idx, line) in lines.iter().enumerate() {
+        // A line of at most MAX_SEGMENT_CHARS bytes can't have more characters
+        let chars: Vec<char> = if line.len() <= MAX_SEGMENT_CHARS {
+            Vec::new()
+        } else {
+            line.chars().collect()
+        };
+        let ranges = split_chars(&chars, MAX_SEGMENT_CHARS);
+        if ranges.len() == 1 {
+            segments.push(LineSegment {
+                line: line_idx,
+                columns: None,
+            });
+            texts.push(line.clone());
+            continue;
+        }
+
+        for range in ranges {
+            segments.push(LineSegment {
+                line: line_idx,
+                columns: Some(range),
+            });
+            texts.push(chars[range.start..range.end].iter().collect());
+        }
+    }
+
+    (segments, texts)
+}
+
+/// Column ranges of at most `max_chars` characters covering a line's characters.
+/// Breaks after whitespace or a separator when one is found in the second half
+/// of a span, so that words and values aren't cut in two.
+fn split_chars(chars: &[char], max_chars: usize) -> Vec<ColumnRange> {
+    let max_chars = max_chars.max(1);
+    let mut ranges = Vec::new();
+    let mut start = 0;
+
+    while chars.len() - start > max_chars {
+        let hard_end = start + max_chars;
+        let end = (start + max_chars / 2..hard_end)
+            .rev()
+            .find(|&i| is_break_char(chars[i]))
+            .map(|i| i + 1)
+            .unwrap_or(hard_end);
+        ranges.push(ColumnRange { start, end });
+        start = end;
+    }
+    ranges.push(ColumnRange {
+        start,
+        end: chars.len(),
+    });
+
+    ranges
+}
+
+fn is_break_char(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ',' | ';')
+}
+
+/// Byte range within `line` of a segment: the given columns, or the whole line
+pub fn segment_bytes(line: &str, columns: Option<ColumnRange>) -> Range<usize> {
+    let Some(columns) = columns else {
+        return 0..line.len();
+    };
+    let byte_offset = |column: usize| {
+        line.char_indices()
+            .nth(column)
+            .map(|(offset, _)| offset)
+            .unwrap_or(line.len())
+    };
+    byte_offset(columns.start)..byte_offset(columns.end)
+}
+
+/// A line as shown in results: the matched span with ellipses when `columns`
+/// is set, otherwise the line itself, cut short if it is overly long
+pub fn display_line(line: &str, columns: Option<ColumnRange>) -> String {
+    let char_count = line.chars().count();
+    let columns = match columns {
+        Some(columns) => columns,
+        None if char_count > MAX_SEGMENT_CHARS => ColumnRange {
+            start: 0,
+            end: MAX_SEGMENT_CHARS,
+        },
+        None => return line.to_string(),
+    };
+
+    let mut excerpt = String::new();
+    if columns.start > 0 {
+        excerpt.push('…');
+    }
+    excerpt.extend(
+        line.chars()
+            .skip(columns.start)
+            .take(columns.end.saturating_sub(columns.start)),
+    );
+    if columns.end < char_count {
+        excerpt.push('…');
+    }
+    excerpt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str, max_chars: usize) -> Vec<ColumnRange> {
+        split_chars(&line.chars().collect::<Vec<_>>(), max_chars)
+    }
+
+    #[test]
+    fn test_split_chars_short_lines_are_whole() {
+        assert_eq!(
+            split("short line", 20),
+            vec![ColumnRange { start: 0, end: 10 }]
+        );
+        assert_eq!(split("", 20), vec![ColumnRange { start: 0, end: 0 }]);
+        assert_eq!(split("héllo", 5), vec![ColumnRange { start: 0, end: 5 }]);
+    }
+
+    #[test]
+    fn test_split_chars_prefers_breaks() {
+        // Breaks after the space rather than inside "delta"
+        let ranges = split("alpha beta gamma delta", 12);
+        assert_eq!(
+            ranges,
+            vec![
+                ColumnRange { start: 0, end: 11 },
+                ColumnRange { start: 11, end: 22 },
+            ]
+        );
+
+        // No separator at all: hard split, covering every character once
+        let ranges = split(&"x".repeat(25), 10);
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0], ColumnRange { start: 0, end: 10 });
+        assert_eq!(ranges[2], ColumnRange { start: 20, end: 25 });
+    }
+
+    #[test]
+    fn test_segment_lines_counts_characters_not_bytes() {
+        // More bytes than the limit, but not more characters
+        let line = "é".repeat(MAX_SEGMENT_CHARS);
+        let (segments, texts) = segment_lines(&[line.clone()]);
+        assert_eq!(
+            segments,
+            vec![LineSegment {
+                line: 0,
+                columns: None
+            }]
+        );
+        assert_eq!(texts, vec![line]);
+    }
+
+    #[test]
+    fn test_segment_lines_keeps_line_numbers() {
+        let long_line = format!("{{\"a\": 1, {}}}", "\"key\": \"value\", ".repeat(100));
+        let lines = vec!["first".to_string(), long_line.clone(), "last".to_string()];
+
+        let (segments, texts) = segment_lines(&lines);
+        assert_eq!(segments.len(), texts.len());
+        assert_eq!(
+            segments[0],
+            LineSegment {
+                line: 0,
+                columns: None
+            }
+        );
+        assert_eq!(segments.last().unwrap().line, 2);
+
+        let long_segments: Vec<&LineSegment> = segments.iter().filter(|s| s.line == 1).collect();
+        assert!(long_segments.len() > 1);
+        assert!(texts.iter().all(|t| t.chars().count() <= MAX_SEGMENT_CHARS));
+        let rejoined: String = texts[1..texts.len() - 1].concat();
+        assert_eq!(rejoined, long_line);
+    }
+
+    #[test]
+    fn test_segment_bytes() {
+        let line = "héllo wörld";
+        assert_eq!(segment_bytes(line, None), 0..line.len());
+        let bytes = segment_bytes(line, Some(ColumnRange { start: 6, end: 11 }));
+        assert_eq!(&line[bytes], "wörld");
+    }
+
+    #[test]
+    fn test_display_line_excerpts() {
+        let line = "0123456789";
+        assert_eq!(
+            display_line(line, Some(ColumnRange { start: 3, end: 6 })),
+            "…345…"
+        );
+        assert_eq!(
+            display_line(line, Some(ColumnRange { start: 0, end: 4 })),
+            "0123…"
+        );
+        assert_eq!(display_line(line, None), line);
+
+        let long_line = "é".repeat(MAX_SEGMENT_CHARS + 10);
+        let shown = display_line(&long_line, None);
+        assert_eq!(shown.chars().count(), MAX_SEGMENT_CHARS + 1);
+        assert!(shown.ends_with('…'));
+    }
+}
@@ -0,0 +1,220 @@
+//! Reading files as text: size limits, binary detection and encoding detection
+use anyhow::Result;
+use std::fmt;
+
+/// How many leading bytes are checked for NUL bytes when detecting binary files
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// Files larger than this are skipped where no limit is given, as `--max-filesize` defaults to
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Why a file was left out of a search
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    Binary,
+    TooLarge { size: u64, max: u64 },
+    Unreadable(String),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Binary => write!(f, "binary file"),
+            Self::TooLarge { size, max } => {
+                write!(f, "file too large ({size} bytes, limit is {max})")
+            }
+            Self::Unreadable(error) => write!(f, "could not read file: {error}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedFile {
+    pub path: String,
+    pub reason: SkipReason,
+}
+
+/// Read a file as text, detecting its encoding.
+/// Files over `max_file_size` bytes and binary files are rejected.
+pub fn read_text_file(path: &str, max_file_size: Option<u64>) -> Result<String, SkipReason> {
+    if let Some(max) = max_file_size {
+        let size = std::fs::metadata(path)
+            .map_err(|e| SkipReason::Unreadable(e.to_string()))?
+            .len();
+        if size > max {
+            return Err(SkipReason::TooLarge { size, max });
+        }
+    }
+
+    let bytes = std::fs::read(path).map_err(|e| SkipReason::Unreadable(e.to_string()))?;
+    decode_text(&bytes).ok_or(SkipReason::Binary)
+}
+
+/// Decode bytes as text: UTF-8 (with or without BOM), UTF-16 with a BOM, and
+/// Windows-1252 (a superset of printable Latin-1) as the fallback.
+/// Returns `None` for content that looks binary.
+pub fn decode_text(bytes: &[u8]) -> Option<String> {
+    if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return Some(String::from_utf8_lossy(rest).into_owned());
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        return Some(decode_utf16(rest, false));
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        return Some(decode_utf16(rest, true));
+    }
+
+    let head = &bytes[..bytes.len().min(BINARY_SNIFF_LEN)];
+    if head.contains(&0) {
+        return None;
+    }
+
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text.to_string()),
+        Err(_) => Some(decode_windows_1252(bytes)),
+    }
+}
+
+fn decode_utf16(bytes: &[u8], big_endian: bool) -> String {
+    let units = bytes.chunks_exact(2).map(|pair| {
+        if big_endian {
+            u16::from_be_bytes([pair[0], pair[1]])
+        } else {
+            u16::from_le_bytes([pair[0], pair[1]])
+        }
+    });
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+// Windows-1252 differs from Latin-1 only in 0x80..=0x9F
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+fn decode_windows_1252(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            0x80..=0x9F => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+            _ => byte as char,
+        })
+        .collect()
+}
+
+/// Byte offset of each line of `content`, for the lines returned by `str::lines`
+pub fn line_offsets(content: &str) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        offsets.push(offset);
+        offset += line.len();
+    }
+    offsets
+}
+
+/// Parse a size such as `1048576`, `512K`, `10M` or `1G` (binary units)
+pub fn parse_file_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let invalid = || {
+        anyhow::anyhow!(
+            "Invalid file size: '{}'. Expected e.g. 512K, 10M or 1G",
+            size
+        )
+    };
+
+    let (digits, multiplier) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1024),
+        Some('M') => (&size[..size.len() - 1], 1024 * 1024),
+        Some('G') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+
+    digits
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| invalid())?
+        .checked_mul(multiplier)
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_text_encodings() {
+        assert_eq!(decode_text(b"plain utf-8").as_deref(), Some("plain utf-8"));
+        assert_eq!(
+            decode_text(&[0xEF, 0xBB, 0xBF, b'h', b'i']).as_deref(),
+            Some("hi")
+        );
+        // "hé" in UTF-16 LE and BE with BOM
+        assert_eq!(
+            decode_text(&[0xFF, 0xFE, b'h', 0x00, 0xE9, 0x00]).as_deref(),
+            Some("hé")
+        );
+        assert_eq!(
+            decode_text(&[0xFE, 0xFF, 0x00, b'h', 0x00, 0xE9]).as_deref(),
+            Some("hé")
+        );
+        // Latin-1 "café" and a Windows-1252 euro sign
+        assert_eq!(
+            decode_text(&[b'c', b'a', b'f', 0xE9]).as_deref(),
+            Some("café")
+        );
+        assert_eq!(decode_text(&[0x80, b'5']).as_deref(), Some("€5"));
+    }
+
+    #[test]
+    fn test_decode_text_detects_binary() {
+        assert_eq!(
+            decode_text(&[0x7F, b'E', b'L', b'F', 0x02, 0x00, 0x00]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_read_text_file_limits() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("big.txt");
+        std::fs::write(&path, "0123456789").unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        assert_eq!(read_text_file(&path, Some(10)).unwrap(), "0123456789");
+        assert_eq!(
+            read_text_file(&path, Some(5)),
+            Err(SkipReason::TooLarge { size: 10, max: 5 })
+        );
+        assert!(matches!(
+            read_text_file("/nonexistent/file.txt", None),
+            Err(SkipReason::Unreadable(_))
+        ));
+    }
+
+    #[test]
+    fn test_line_offsets_match_lines() {
+        let content = "first\r\nsecond\n\nlast";
+        let offsets = line_offsets(content);
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(offsets, vec![0, 7, 14, 15]);
+        for (offset, line) in offsets.iter().zip(lines) {
+            assert!(content[*offset..].starts_with(line));
+        }
+        assert!(line_offsets("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_file_size() {
+        assert_eq!(parse_file_size("1234").unwrap(), 1234);
+        assert_eq!(parse_file_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_file_size("10m").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_file_size("1G").unwrap(), 1024 * 1024 * 1024);
+        // The `--max-filesize` default
+        assert_eq!(parse_file_size("100M").unwrap(), DEFAULT_MAX_FILE_SIZE);
+        assert!(parse_file_size("ten").is_err());
+        assert!(parse_file_size("").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Bounded collection of the best scored items, used to stream results
+//! without keeping every scored line in memory
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// An item with its distance and a tie-breaking rank (e.g. file index, line index)
+struct Entry<T> {
+    distance: f64,
+    rank: (usize, usize),
+    item: T,
+}
+
+impl<T> Entry<T> {
+    fn key_cmp(&self, distance: f64, rank: (usize, usize)) -> Ordering {
+        self.distance
+            .total_cmp(&distance)
+            .then_with(|| self.rank.cmp(&rank))
+    }
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key_cmp(other.distance, other.rank)
+    }
+}
+
+/// Keeps the `capacity` items with the lowest distance; unbounded when `capacity` is `None`
+pub struct TopK<T> {
+    capacity: Option<usize>,
+    // Max-heap, so the worst kept entry is on top and evicted first
+    heap: BinaryHeap<Entry<T>>,
+}
+
+impl<T> TopK<T> {
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self {
+            capacity,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    /// Whether an item with this distance and rank would be kept
+    pub fn accepts(&self, distance: f64, rank: (usize, usize)) -> bool {
+        match self.capacity {
+            None => true,
+            Some(0) => false,
+            Some(capacity) if self.heap.len() < capacity => true,
+            Some(_) => self
+                .heap
+                .peek()
+                .is_some_and(|worst| worst.key_cmp(distance, rank) == Ordering::Greater),
+        }
+    }
+
+    pub fn push(&mut self, distance: f64, rank: (usize, usize), item: T) {
+        if !self.accepts(distance, rank) {
+            return;
+        }
+        self.heap.push(Entry {
+            distance,
+            rank,
+            item,
+        });
+        if let Some(capacity) = self.capacity
+            && self.heap.len() > capacity
+        {
+            self.heap.pop();
+        }
+    }
+
+    /// Combine two collections, keeping the best items of both
+    pub fn merge(mut self, other: Self) -> Self {
+        for entry in other.heap {
+            self.push(entry.distance, entry.rank, entry.item);
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// The kept items with their distances, best first
+    pub fn into_sorted_vec(self) -> Vec<(f64, T)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|entry| (entry.distance, entry.item))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_k_keeps_best_items() {
+        let mut top = TopK::new(Some(2));
+        top.push(0.5, (0, 0), "c");
+        top.push(0.1, (0, 1), "a");
+        top.push(0.9, (0, 2), "d");
+        top.push(0.3, (0, 3), "b");
+
+        assert_eq!(top.len(), 2);
+        assert!(!top.accepts(0.4, (0, 4)));
+        assert_eq!(top.into_sorted_vec(), vec![(0.1, "a"), (0.3, "b")]);
+    }
+
+    #[test]
+    fn test_top_k_merge_and_ties() {
+        let mut left = TopK::new(Some(2));
+        left.push(0.2, (1, 0), "later file");
+        let mut right = TopK::new(Some(2));
+        right.push(0.2, (0, 5), "earlier file");
+        right.push(0.7, (0, 6), "worse");
+
+        let merged = left.merge(right);
+        // Equal distances are ordered by rank, independent of merge order
+        assert_eq!(
+            merged.into_sorted_vec(),
+            vec![(0.2, "earlier file"), (0.2, "later file")]
+        );
+    }
+
+    #[test]
+    fn test_top_k_unbounded() {
+        let mut top = TopK::new(None);
+        for i in 0..100 {
+            top.push(i as f64, (0, i), i);
+        }
+        assert_eq!(top.len(), 100);
+        assert!(TopK::<()>::new(Some(0)).is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+//! Finding the files under directories, honouring `.gitignore` and `.semtoolsignore`
+use anyhow::{Result, anyhow};
+use ignore::WalkBuilder;
+use std::path::{Component, Path, PathBuf};
+
+use super::filter::SearchFilter;
+
+/// Ignore file read in every directory, in the `.gitignore` format, for files
+/// that should stay out of semantic search but not out of version control
+pub const IGNORE_FILENAME: &str = ".semtoolsignore";
+
+/// How directories are walked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WalkOptions {
+    pub respect_ignore_files: bool, // `.gitignore`, `.ignore` and `.semtoolsignore`
+    pub hidden: bool,               // Include hidden files and directories
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self {
+            respect_ignore_files: true,
+            hidden: false,
+        }
+    }
+}
+
+/// All files under `roots` that pass the filter's globs and types, sorted and
+/// without duplicates. Globs are matched against paths relative to their root.
+/// Paths keep the form of their root, minus a leading `./`, so they match the
+/// paths given to `search` from the same directory.
+pub fn walk_files(
+    roots: &[String],
+    filter: &SearchFilter,
+    options: WalkOptions,
+) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+
+    for root in roots {
+        if !Path::new(root).exists() {
+            return Err(anyhow!("No such file or directory: '{}'", root));
+        }
+
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .standard_filters(options.respect_ignore_files)
+            .hidden(!options.hidden)
+            .require_git(false);
+        if options.respect_ignore_files {
+            builder.add_custom_ignore_filename(IGNORE_FILENAME);
+        }
+
+        for entry in builder.build() {
+            let entry = entry?;
+            if !entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file())
+            {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            let relative = if relative.as_os_str().is_empty() {
+                entry.path() // The root is a file
+            } else {
+                relative
+            };
+            if filter.matches_path(&relative.to_string_lossy()) {
+                let path = entry.path().to_string_lossy();
+                files.push(path.strip_prefix("./").unwrap_or(&path).to_string());
+            }
+        }
+    }
+
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// A path made absolute with symbolic links resolved, the form in which a
+/// workspace stores its roots and documents
+pub fn canonical_path(path: &str) -> Result<String> {
+    std::fs::canonicalize(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .map_err(|_| anyhow!("No such file or directory: '{}'", path))
+}
+
+/// Whether a path lies under a directory, comparing whole components as they
+/// are written: a relative directory only matches relative paths, and `.`
+/// matches every relative path
+pub fn is_under(path: &str, dir: &str) -> bool {
+    let path = without_cur_dir(Path::new(path));
+    let dir = without_cur_dir(Path::new(dir));
+    if dir.as_os_str().is_empty() {
+        return path.is_relative() && !path.as_os_str().is_empty();
+    }
+    path != dir && path.starts_with(&dir)
+}
+
+/// A path without `.` components, e.g. `docs` for `./docs/`
+fn without_cur_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Paths relative to `root`, with `/` between components on every platform
+    fn relative(root: &Path, files: Vec<String>) -> Vec<String> {
+        files
+            .iter()
+            .map(|file| {
+                let file = Path::new(file).strip_prefix(root).unwrap();
+                let components: Vec<_> = file
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect();
+                components.join("/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_walk_honours_ignore_files_and_filters() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        write(root, ".gitignore", "target/\n");
+        write(root, ".semtoolsignore", "*.lock\n");
+        write(root, "README.md", "readme");
+        write(root, "docs/guide.md", "guide");
+        write(root, "src/lib.rs", "code");
+        write(root, "Cargo.lock", "lock");
+        write(root, "target/out.md", "build output");
+        write(root, ".hidden/notes.md", "hidden");
+
+        let roots = vec![root.to_string_lossy().to_string()];
+        let all = walk_files(&roots, &SearchFilter::default(), WalkOptions::default()).unwrap();
+        assert_eq!(
+            relative(root, all),
+            vec!["README.md", "docs/guide.md", "src/lib.rs"]
+        );
+
+        let markdown = SearchFilter::new(
+            None,
+            &[],
+            &["docs/**".to_string()],
+            &["md".to_string()],
+            false,
+        )
+        .unwrap();
+        let files = walk_files(&roots, &markdown, WalkOptions::default()).unwrap();
+        assert_eq!(relative(root, files), vec!["README.md"]);
+
+        let everything = WalkOptions {
+            respect_ignore_files: false,
+            hidden: true,
+        };
+        let files = walk_files(&roots, &SearchFilter::default(), everything).unwrap();
+        assert_eq!(files.len(), 8);
+
+        assert!(
+            walk_files(
+                &["/no/such/dir".to_string()],
+                &SearchFilter::default(),
+                WalkOptions::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_canonical_path() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path();
+        write(root, "docs/guide.md", "guide");
+
+        let spelled = root.join("docs").join("..").join("docs").join("guide.md");
+        let canonical = canonical_path(&spelled.to_string_lossy()).unwrap();
+        let canonical = Path::new(&canonical);
+        assert!(canonical.is_absolute());
+        assert!(canonical.ends_with(Path::new("docs").join("guide.md")));
+        assert!(
+            !canonical
+                .components()
+                .any(|component| component == Component::ParentDir)
+        );
+
+        assert!(canonical_path("/no/such/file").is_err());
+    }
+
+    #[test]
+    fn test_is_under() {
+        assert!(is_under("docs/guide.md", "docs"));
+        assert!(is_under("docs/guide.md", "./docs/"));
+        assert!(is_under("./docs/api/index.md", "docs"));
+        assert!(!is_under("docs-old/guide.md", "docs"));
+        assert!(!is_under("docs", "docs"));
+        assert!(is_under("src/lib.rs", "."));
+        assert!(is_under("src/lib.rs", "./"));
+
+        // Absolute paths as a workspace stores them, in the platform's own form
+        let temp_dir = tempfile::tempdir().unwrap();
+        let home = canonical_path(&temp_dir.path().to_string_lossy()).unwrap();
+        let file = Path::new(&home).join("src").join("lib.rs");
+        let file = file.to_string_lossy();
+        assert!(!is_under(&file, "."));
+        assert!(is_under(&file, &home));
+        assert!(is_under(
+            &file,
+            &Path::new(&home).join("src").to_string_lossy()
+        ));
+        assert!(!is_under(&file, &format!("{home}-old")));
+        let root = Path::new(&home).ancestors().last().unwrap();
+        assert!(is_under(&file, &root.to_string_lossy()));
+    }
+}
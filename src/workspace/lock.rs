@@ -0,0 +1,208 @@
+//! Coordinating processes that use the same workspace, e.g. two shells or an
+//! editor plugin and a terminal searching at once. Readers share the workspace,
+//! a writer has it to itself. Locks are released when the process exits, even
+//! when it crashes, so there is nothing to clean up by hand.
+use anyhow::{Result, anyhow};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Extension of the file next to a workspace directory that processes lock.
+/// It lives outside the directory so the workspace can be renamed or deleted
+/// while locked, and is left in place afterwards for processes still waiting on it.
+pub const LOCK_EXTENSION: &str = "lock";
+
+/// Environment variable with the number of seconds to wait for a busy workspace
+pub const LOCK_TIMEOUT_ENV: &str = "SEMTOOLS_LOCK_TIMEOUT";
+
+/// How long to wait for a busy workspace when the environment doesn't say
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How a process holds a workspace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    /// Reading only, alongside other readers
+    Shared,
+    /// Writing, with no one else reading or writing
+    Exclusive,
+}
+
+/// A lock on a workspace, held until it is dropped
+#[derive(Debug)]
+pub struct WorkspaceLock {
+    _file: File, // Closing the file releases the lock
+    mode: LockMode,
+}
+
+impl WorkspaceLock {
+    /// Lock the workspace at `workspace_dir`, waiting up to `timeout` for other
+    /// processes to finish with it. The directory itself needn't exist: the lock
+    /// is what guards creating it.
+    pub fn acquire(workspace_dir: &Path, mode: LockMode, timeout: Duration) -> Result<Self> {
+        let file = open_lock_file(workspace_dir)?;
+        let started = Instant::now();
+        loop {
+            if try_lock(&file, mode, workspace_dir)? {
+                return Ok(Self { _file: file, mode });
+            }
+            if started.elapsed() >= timeout {
+                return Err(anyhow!(
+                    "Workspace at '{}' is in use by another semtools process (waited {}s). \
+                     Try again once it finishes, or set {} to wait longer",
+                    workspace_dir.display(),
+                    timeout.as_secs_f32(),
+                    LOCK_TIMEOUT_ENV
+                ));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Lock the workspace without waiting, None when another process holds a lock
+    /// that conflicts with `mode`
+    pub fn try_acquire(workspace_dir: &Path, mode: LockMode) -> Result<Option<Self>> {
+        let file = open_lock_file(workspace_dir)?;
+        if try_lock(&file, mode, workspace_dir)? {
+            Ok(Some(Self { _file: file, mode }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+/// The lock file of the workspace at `workspace_dir`, e.g. `workspaces/notes.lock`
+pub fn lock_path(workspace_dir: &Path) -> PathBuf {
+    let mut name = workspace_dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(LOCK_EXTENSION);
+    workspace_dir.with_file_name(name)
+}
+
+fn open_lock_file(workspace_dir: &Path) -> Result<File> {
+    let path = lock_path(workspace_dir);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| {
+            anyhow!(
+                "Could not lock workspace at '{}': {}",
+                workspace_dir.display(),
+                e
+            )
+        })
+}
+
+/// Take the lock on an open lock file if no other process is in the way
+fn try_lock(file: &File, mode: LockMode, workspace_dir: &Path) -> Result<bool> {
+    let attempt = match mode {
+        LockMode::Shared => file.try_lock_shared(),
+        LockMode::Exclusive => file.try_lock(),
+    };
+    match attempt {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(anyhow!(
+            "Could not lock workspace at '{}': {}",
+            workspace_dir.display(),
+            e
+        )),
+    }
+}
+
+/// How long to wait for a busy workspace, from `SEMTOOLS_LOCK_TIMEOUT` (in seconds)
+pub fn lock_timeout() -> Result<Duration> {
+    match std::env::var(LOCK_TIMEOUT_ENV) {
+        Ok(secs) if !secs.trim().is_empty() => secs
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Invalid {}: '{}'. Must be a number of seconds",
+                    LOCK_TIMEOUT_ENV,
+                    secs
+                )
+            }),
+        _ => Ok(DEFAULT_LOCK_TIMEOUT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readers_share_and_writers_wait() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = &temp_dir.path().join("workspace");
+        let no_wait = Duration::from_millis(100);
+
+        let reader = WorkspaceLock::acquire(dir, LockMode::Shared, no_wait).unwrap();
+        let other_reader = WorkspaceLock::acquire(dir, LockMode::Shared, no_wait).unwrap();
+        assert_eq!(reader.mode(), LockMode::Shared);
+
+        let error = WorkspaceLock::acquire(dir, LockMode::Exclusive, no_wait).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("in use by another semtools process")
+        );
+
+        drop(reader);
+        drop(other_reader);
+        let writer = WorkspaceLock::acquire(dir, LockMode::Exclusive, no_wait).unwrap();
+        assert!(WorkspaceLock::acquire(dir, LockMode::Shared, no_wait).is_err());
+
+        // A writer that finishes while another process waits lets it in
+        let waiting = std::thread::spawn({
+            let dir = dir.to_path_buf();
+            move || WorkspaceLock::acquire(&dir, LockMode::Exclusive, Duration::from_secs(10))
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        drop(writer);
+        assert!(waiting.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_lock_lives_outside_the_workspace() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().join("notes");
+
+        // Locking neither waits nor creates the workspace directory
+        let writer = WorkspaceLock::try_acquire(&dir, LockMode::Exclusive).unwrap();
+        assert!(writer.is_some());
+        assert!(
+            WorkspaceLock::try_acquire(&dir, LockMode::Shared)
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(lock_path(&dir), temp_dir.path().join("notes.lock"));
+        assert!(lock_path(&dir).exists());
+        assert!(!dir.exists());
+
+        // The workspace can be moved while it is locked
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("data.bin"), b"embeddings").unwrap();
+        std::fs::rename(&dir, temp_dir.path().join("renamed")).unwrap();
+        drop(writer);
+        assert!(
+            WorkspaceLock::try_acquire(&dir, LockMode::Shared)
+                .unwrap()
+                .is_some()
+        );
+    }
+}
@@ -1,16 +1,25 @@
 use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
+pub mod lock;
 pub mod store;
 
 pub use store::WorkspaceStats;
 
+use lock::{LockMode, WorkspaceLock, lock_timeout};
+use store::{IndexSettings, Store};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WorkspaceConfig {
     pub name: String,
     pub root_dir: String,         // e.g., ~/.semtools/my-workspace
     pub in_batch_size: usize,     // default 5_000
-    pub oversample_factor: usize, // default 3
+    pub oversample_factor: usize, // default 3, candidates fetched per result with quantization
+    #[serde(default)]
+    pub indexed_roots: Vec<String>, // Directories added with `workspace index`, made canonical
+    #[serde(default)]
+    pub index: IndexSettings, // Storage, HNSW and quantization of line embeddings
 }
 
 impl Default for WorkspaceConfig {
@@ -20,6 +29,21 @@ impl Default for WorkspaceConfig {
             root_dir: String::new(),
             in_batch_size: 5_000,
             oversample_factor: 3,
+            indexed_roots: Vec::new(),
+            index: IndexSettings::default(),
+        }
+    }
+}
+
+impl WorkspaceConfig {
+    /// Remember roots passed to `workspace index`, keeping the order they were first added in
+    pub fn add_indexed_roots(&mut self, roots: &[String]) {
+        for root in roots {
+            let root = root.trim_end_matches('/');
+            let root = if root.is_empty() { "/" } else { root };
+            if !self.indexed_roots.iter().any(|known| known == root) {
+                self.indexed_roots.push(root.to_string());
+            }
         }
     }
 }
@@ -46,6 +70,26 @@ impl Workspace {
         Ok(Self { config })
     }
 
+    /// Open the workspace's store to read and write, with its index settings
+    pub fn open_store(&self) -> Result<Store> {
+        let mut store = Store::open(&self.config.root_dir)?;
+        store.configure(&self.config.index, self.config.oversample_factor)?;
+        Ok(store)
+    }
+
+    /// Open the workspace's store to read only, alongside other readers
+    pub fn open_store_shared(&self) -> Result<Store> {
+        let mut store = Store::open_shared(&self.config.root_dir)?;
+        store.configure(&self.config.index, self.config.oversample_factor)?;
+        Ok(store)
+    }
+
+    /// Open the store to read only if that can be done without waiting or
+    /// writing to it, e.g. to report on the workspace. None when it is busy.
+    pub fn try_open_store_shared(&self) -> Result<Option<Store>> {
+        Store::try_open_shared(&self.config.root_dir)
+    }
+
     pub fn save(&self) -> Result<()> {
         let cfg_path = Self::config_path_for(&self.config.name)?;
         let parent = std::path::Path::new(&cfg_path).parent().unwrap();
@@ -79,16 +123,132 @@ impl Workspace {
 }
 
 impl Workspace {
-    pub fn root_path(name: &str) -> Result<String> {
+    /// Directory that holds one directory per workspace
+    pub fn workspaces_dir() -> Result<PathBuf> {
         let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("No home dir found?"))?;
-        Ok(home
-            .join(".semtools")
-            .join("workspaces")
+        Ok(home.join(".semtools").join("workspaces"))
+    }
+
+    pub fn root_path(name: &str) -> Result<String> {
+        Ok(Self::workspaces_dir()?
             .join(name)
             .to_string_lossy()
             .to_string())
     }
 
+    /// Names of all workspaces, sorted
+    pub fn list() -> Result<Vec<String>> {
+        let dir = Self::workspaces_dir()?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Whether a workspace name can be used as a directory under `workspaces_dir`
+    pub fn validate_name(name: &str) -> Result<()> {
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.contains(['/', '\\'])
+            || name.chars().any(char::is_control)
+        {
+            bail!("Invalid workspace name: '{name}'. Names can't be empty or contain slashes");
+        }
+        Ok(())
+    }
+
+    /// Open a workspace that must already exist, unlike `open` which starts a new one
+    pub fn open_existing(name: &str) -> Result<Self> {
+        Self::existing_root(name)?;
+        Self::open(Some(name))
+    }
+
+    /// Bytes used on disk by the workspace's files
+    pub fn disk_usage(&self) -> u64 {
+        dir_size(Path::new(&self.config.root_dir))
+    }
+
+    /// Delete a workspace and everything indexed in it, once no other process uses it
+    pub fn delete(name: &str) -> Result<()> {
+        let root = Self::existing_root(name)?;
+        let _lock = Self::lock_existing(name, &root, LockMode::Exclusive)?;
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    /// Give a workspace a new name, once no other process uses it
+    pub fn rename(from: &str, to: &str) -> Result<Self> {
+        let from_root = Self::existing_root(from)?;
+        let to_root = Self::new_root(to)?;
+        {
+            let _lock = Self::lock_existing(from, &from_root, LockMode::Exclusive)?;
+            Self::new_root(to)?;
+            std::fs::rename(&from_root, &to_root)?;
+        }
+        Self::adopt(to, &to_root)
+    }
+
+    /// Copy a workspace, with its settings and everything indexed in it, to a
+    /// new one. Searches can go on meanwhile, updates wait for the copy.
+    pub fn copy(from: &str, to: &str) -> Result<Self> {
+        let from_root = Self::existing_root(from)?;
+        let to_root = Self::new_root(to)?;
+        {
+            let _lock = Self::lock_existing(from, &from_root, LockMode::Shared)?;
+            Self::new_root(to)?;
+            if let Err(e) = copy_dir(&from_root, &to_root) {
+                let _ = std::fs::remove_dir_all(&to_root);
+                return Err(e);
+            }
+        }
+        Self::adopt(to, &to_root)
+    }
+
+    fn existing_root(name: &str) -> Result<PathBuf> {
+        Self::validate_name(name)?;
+        let root = PathBuf::from(Self::root_path(name)?);
+        if !root.is_dir() {
+            bail!("No workspace named '{name}'. Run: workspace list");
+        }
+        Ok(root)
+    }
+
+    /// Lock the workspace at `root`, checking once the lock is held that it is
+    /// still there: whoever held it before may have deleted or renamed it
+    fn lock_existing(name: &str, root: &Path, mode: LockMode) -> Result<WorkspaceLock> {
+        let lock = WorkspaceLock::acquire(root, mode, lock_timeout()?);
+        Self::existing_root(name)?;
+        lock
+    }
+
+    fn new_root(name: &str) -> Result<PathBuf> {
+        Self::validate_name(name)?;
+        let root = PathBuf::from(Self::root_path(name)?);
+        if root.exists() {
+            bail!("A workspace named '{name}' already exists");
+        }
+        Ok(root)
+    }
+
+    /// Point the config of a workspace that was moved or copied to `root` at its new name
+    fn adopt(name: &str, root: &Path) -> Result<Self> {
+        let mut ws = Self::open(Some(name))?;
+        ws.config.name = name.to_string();
+        ws.config.root_dir = root.to_string_lossy().to_string();
+        ws.save()?;
+        Ok(ws)
+    }
+
     fn config_path_for(name: &str) -> Result<String> {
         let home = dirs::home_dir().ok_or_else(|| anyhow::anyhow!("No home dir found?"))?;
         Ok(home
@@ -101,6 +261,35 @@ impl Workspace {
     }
 }
 
+/// Total size of the files under a directory
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map(|m| m.len()).unwrap_or(0),
+        })
+        .sum()
+}
+
+/// Copy a directory tree
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -123,6 +312,11 @@ mod tests {
             root_dir: "/tmp/test".to_string(),
             in_batch_size: 1000,
             oversample_factor: 2,
+            indexed_roots: vec!["docs".to_string()],
+            index: IndexSettings {
+                hnsw_ef: Some(128),
+                ..Default::default()
+            },
         };
 
         // Test serialization
@@ -138,6 +332,24 @@ mod tests {
         assert_eq!(deserialized.root_dir, config.root_dir);
         assert_eq!(deserialized.in_batch_size, config.in_batch_size);
         assert_eq!(deserialized.oversample_factor, config.oversample_factor);
+        assert_eq!(deserialized.indexed_roots, config.indexed_roots);
+        assert_eq!(deserialized.index, config.index);
+
+        // Configs written before roots and index settings were recorded still load
+        let old: WorkspaceConfig = serde_json::from_str(
+            r#"{"name": "old", "root_dir": "/tmp/old", "in_batch_size": 10, "oversample_factor": 3}"#,
+        )
+        .expect("Failed to deserialize old config");
+        assert!(old.indexed_roots.is_empty());
+        assert_eq!(old.index, IndexSettings::default());
+    }
+
+    #[test]
+    fn test_add_indexed_roots() {
+        let mut config = WorkspaceConfig::default();
+        config.add_indexed_roots(&["docs/".to_string(), "src".to_string()]);
+        config.add_indexed_roots(&["docs".to_string(), "/".to_string()]);
+        assert_eq!(config.indexed_roots, vec!["docs", "src", "/"]);
     }
 
     #[test]
@@ -236,6 +448,8 @@ mod tests {
                 root_dir: Workspace::root_path(workspace_name).expect("Failed to get root path"),
                 in_batch_size: 456,
                 oversample_factor: 7,
+                indexed_roots: Vec::new(),
+                index: IndexSettings::default(),
             },
         };
 
@@ -309,4 +523,61 @@ mod tests {
         assert_eq!(workspace.config.name, workspace_name);
         assert!(!workspace.config.root_dir.is_empty());
     }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(Workspace::validate_name("docs-v2").is_ok());
+        assert!(Workspace::validate_name("my.notes").is_ok());
+        for name in ["", ".", "..", "a/b", "a\\b", "tab\there"] {
+            assert!(Workspace::validate_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn test_workspace_copy_rename_and_delete() {
+        let (source, copy, renamed) =
+            ("test-manage-src", "test-manage-copy", "test-manage-renamed");
+        for name in [source, copy, renamed] {
+            let _ = fs::remove_dir_all(Workspace::root_path(name).unwrap());
+        }
+
+        let mut workspace = Workspace::open(Some(source)).unwrap();
+        workspace.config.oversample_factor = 5;
+        workspace.save().unwrap();
+        let root = PathBuf::from(&workspace.config.root_dir);
+        fs::write(root.join("data.bin"), b"embeddings").unwrap();
+
+        // A copy has the settings and data, under its own name and directory
+        let copied = Workspace::copy(source, copy).unwrap();
+        assert_eq!(copied.config.name, copy);
+        assert_eq!(copied.config.root_dir, Workspace::root_path(copy).unwrap());
+        assert_eq!(copied.config.oversample_factor, 5);
+        let copy_root = PathBuf::from(&copied.config.root_dir);
+        assert_eq!(fs::read(copy_root.join("data.bin")).unwrap(), b"embeddings");
+        assert!(copied.disk_usage() >= 10);
+        assert!(Workspace::copy(source, copy).is_err());
+
+        let moved = Workspace::rename(copy, renamed).unwrap();
+        assert_eq!(moved.config.name, renamed);
+        assert!(!copy_root.exists());
+        assert_eq!(
+            Workspace::open_existing(renamed).unwrap().config.name,
+            renamed
+        );
+        assert!(Workspace::open_existing(copy).is_err());
+        assert!(Workspace::rename(renamed, source).is_err());
+
+        let names = Workspace::list().unwrap();
+        assert!(names.contains(&source.to_string()));
+        assert!(names.contains(&renamed.to_string()));
+        assert!(!names.contains(&copy.to_string()));
+        // Lock files sit next to the workspaces, and aren't workspaces themselves
+        assert!(lock::lock_path(&copy_root).exists());
+        assert!(!names.iter().any(|name| name.ends_with(".lock")));
+
+        Workspace::delete(source).unwrap();
+        Workspace::delete(renamed).unwrap();
+        assert!(!root.exists());
+        assert!(Workspace::delete(source).is_err());
+    }
 }
@@ -2,17 +2,26 @@
 use anyhow::{Result, anyhow};
 
 use crate::search::DocumentInfo;
+use crate::search::filter::SearchFilter;
+use crate::search::rerank::{MMR_CANDIDATE_FACTOR, diversified_order};
+use crate::search::segment::{ColumnRange, segment_lines};
+use crate::search::text::{SkippedFile, read_text_file};
+use crate::workspace::lock::{LockMode, WorkspaceLock, lock_timeout};
 use edge::EdgeShard;
 use ordered_float::OrderedFloat;
 use segment::data_types::vectors::{NamedQuery, VectorInternal, VectorStructInternal};
 use segment::json_path::JsonPath;
 use segment::types::{
-    AnyVariants, Condition, Distance, ExtendedPointId, FieldCondition, Filter, Match, Payload,
-    PayloadStorageType, SegmentConfig, ValueVariants, VectorDataConfig, VectorStorageType,
+    AnyVariants, BinaryQuantization, BinaryQuantizationConfig, CompressionRatio, Condition,
+    Distance, ExtendedPointId, FieldCondition, Filter, HnswConfig, Indexes, Match, Payload,
+    PayloadStorageType, ProductQuantization, ProductQuantizationConfig, QuantizationConfig,
+    QuantizationSearchParams, ScalarQuantization, ScalarQuantizationConfig, ScalarType,
+    SearchParams, SegmentConfig, ValueVariants, VectorDataConfig, VectorStorageType,
     WithPayloadInterface, WithVector,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use shard::count::CountRequestInternal;
 use shard::operations::CollectionUpdateOperations;
 use shard::operations::point_ops::{
@@ -21,23 +30,42 @@ use shard::operations::point_ops::{
 use shard::query::query_enum::QueryEnum;
 use shard::query::{ScoringQuery, ShardQueryRequest};
 use shard::scroll::ScrollRequestInternal;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 /// Current embedding/version number for stored document metadata.
 /// Bump this when the embedding model or preprocessing pipeline changes in a
 /// way that invalidates previously stored line embeddings.
 /// Backwards compatibility: if a workspace DB is missing the `_version` column,
 /// we treat all existing documents as version 1.
-pub const CURRENT_EMBEDDING_VERSION: u32 = 2;
+/// Version 3 splits overly long lines into separately embedded spans.
+pub const CURRENT_EMBEDDING_VERSION: u32 = 3;
 
 /// Embedding size (needed to inform Qdrant collection when it is instantiated)
 pub const LINE_EMBEDDING_SIZE: usize = 256;
-/// We are not actually storing document-level embeddings,
-/// but Qdrant requires a vector size to be defined for the collection, so we use a dummy size of 1.
-/// This collection is being used for document-level metadata
-pub const DOCUMENT_EMBEDDING_SIZE: usize = 1;
+/// Document-level embeddings are the mean of a document's line embeddings,
+/// so they share the line embedding size.
+/// This collection also holds the document-level metadata
+pub const DOCUMENT_EMBEDDING_SIZE: usize = LINE_EMBEDDING_SIZE;
+
+/// Version of the on-disk shard layout.
+/// Bump this when a shard's vector configuration changes in a way that requires
+/// recreating it. Layout 1 stored a dummy 1-dim vector in the documents shard.
+pub const STORE_LAYOUT_VERSION: u32 = 2;
+
+/// File (inside the workspace directory) recording the shard layout version
+const STORE_MANIFEST_FILE: &str = "store.json";
+
+const DOCUMENTS_SHARD_DIR: &str = "documents.qdrant";
+const LINE_EMBEDDINGS_SHARD_DIR: &str = "line_embeddings.qdrant";
+
+/// File in the workspace directory naming the documents of an update in progress
+const JOURNAL_FILE: &str = "journal.json";
+
+/// The line embeddings shard is only closed while a rebuild swaps it out
+const SHARD_OPEN: &str = "line embeddings shard is open outside of a rebuild";
 
 /// Vector name used in the documents shard
 const DOCUMENTS_VECTOR_NAME: &str = "documents";
@@ -48,25 +76,283 @@ const LINE_EMBEDDINGS_VECTOR_NAME: &str = "line_embeddings";
 /// Default limit for Qdrant retrieval
 const DEFAULT_RETRIEVAL_LIMIT: usize = 10000;
 
+/// Fewest links per vector, and candidates while building, that make a usable HNSW graph
+const MIN_HNSW_M: usize = 2;
+const MIN_HNSW_EF_CONSTRUCT: usize = 4;
+
+/// Segments with fewer vectors than fit in this many KB are searched without the HNSW graph
+const HNSW_FULL_SCAN_THRESHOLD_KB: usize = 10_000;
+
+/// Points read at a time when paging through a shard, e.g. to rebuild it
+const SCROLL_PAGE_POINTS: usize = 1000;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocMeta {
     pub path: String,
     pub size_bytes: u64,
     pub mtime: i64,
     pub _version: u32, // used to help manage new embedding models
+    // Hash of the text content, see `content_hash`. Missing in older workspaces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    #[serde(skip)]
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoreManifest {
+    layout_version: u32,
+    // Settings the line embeddings shard was built with
+    #[serde(default)]
+    index: IndexSettings,
+}
+
+/// How line embeddings are compressed for searching. Compressed vectors are kept
+/// in memory and compared first, then the best candidates are rescored with the
+/// full vectors, which can stay on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantization {
+    /// Full vectors only
+    #[default]
+    None,
+    /// One byte per dimension, 4x smaller
+    Scalar,
+    /// One bit per dimension, 32x smaller and fastest, but coarse
+    Binary,
+    /// Groups of dimensions encoded together, 16x smaller and slowest to build
+    Product,
+}
+
+impl Quantization {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "scalar" => Ok(Self::Scalar),
+            "binary" => Ok(Self::Binary),
+            "product" => Ok(Self::Product),
+            _ => Err(anyhow!(
+                "Invalid quantization: '{}'. Must be 'none', 'scalar', 'binary' or 'product'",
+                name
+            )),
+        }
+    }
+
+    fn config(&self) -> Option<QuantizationConfig> {
+        match self {
+            Self::None => None,
+            Self::Scalar => Some(QuantizationConfig::Scalar(ScalarQuantization {
+                scalar: ScalarQuantizationConfig {
+                    r#type: ScalarType::Int8,
+                    quantile: Some(0.99),
+                    always_ram: Some(true),
+                },
+            })),
+            Self::Binary => Some(QuantizationConfig::Binary(BinaryQuantization {
+                binary: BinaryQuantizationConfig {
+                    always_ram: Some(true),
+                    encoding: None,
+                    query_encoding: None,
+                },
+            })),
+            Self::Product => Some(QuantizationConfig::Product(ProductQuantization {
+                product: ProductQuantizationConfig {
+                    compression: CompressionRatio::X16,
+                    always_ram: Some(true),
+                },
+            })),
+        }
+    }
+}
+
+impl std::fmt::Display for Quantization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Scalar => write!(f, "scalar"),
+            Self::Binary => write!(f, "binary"),
+            Self::Product => write!(f, "product"),
+        }
+    }
+}
+
+/// Where full line embeddings are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorStorage {
+    /// Memory-mapped files, paged in as searches need them
+    #[default]
+    Disk,
+    /// Loaded into memory when the workspace is opened
+    Memory,
+}
+
+impl VectorStorage {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "disk" => Ok(Self::Disk),
+            "memory" | "ram" => Ok(Self::Memory),
+            _ => Err(anyhow!(
+                "Invalid storage: '{}'. Must be 'disk' or 'memory'",
+                name
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for VectorStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disk => write!(f, "disk"),
+            Self::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+/// How the line embeddings of a workspace are stored, indexed and searched
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexSettings {
+    pub quantization: Quantization,
+    pub storage: VectorStorage,
+    pub hnsw_m: usize,            // Links per vector in the HNSW graph
+    pub hnsw_ef_construct: usize, // Candidates considered while building the graph
+    pub hnsw_ef: Option<usize>, // Candidates considered while searching, None for Qdrant's default
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        Self {
+            quantization: Quantization::None,
+            storage: VectorStorage::Disk,
+            hnsw_m: 16,
+            hnsw_ef_construct: 100,
+            hnsw_ef: None,
+        }
+    }
+}
+
+impl IndexSettings {
+    /// Check that the HNSW graph can be built with these settings
+    pub fn validate(&self) -> Result<()> {
+        if self.hnsw_m < MIN_HNSW_M {
+            return Err(anyhow!(
+                "Invalid HNSW m: {}. Must be at least {}",
+                self.hnsw_m,
+                MIN_HNSW_M
+            ));
+        }
+        if self.hnsw_ef_construct < MIN_HNSW_EF_CONSTRUCT {
+            return Err(anyhow!(
+                "Invalid HNSW ef_construct: {}. Must be at least {}",
+                self.hnsw_ef_construct,
+                MIN_HNSW_EF_CONSTRUCT
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a shard built with these settings has to be rebuilt to use `other`.
+    /// The search-time `hnsw_ef` applies to any shard.
+    fn needs_rebuild(&self, other: &IndexSettings) -> bool {
+        Self {
+            hnsw_ef: None,
+            ..*self
+        } != Self {
+            hnsw_ef: None,
+            ..*other
+        }
+    }
+
+    fn line_embeddings_segment_config(&self) -> SegmentConfig {
+        let on_disk = self.storage == VectorStorage::Disk;
+        let mut vector_data = HashMap::new();
+        vector_data.insert(
+            LINE_EMBEDDINGS_VECTOR_NAME.to_string(),
+            VectorDataConfig {
+                size: LINE_EMBEDDING_SIZE,
+                distance: Distance::Cosine,
+                storage_type: if on_disk {
+                    VectorStorageType::ChunkedMmap
+                } else {
+                    VectorStorageType::InRamChunkedMmap
+                },
+                index: Indexes::Hnsw(HnswConfig {
+                    m: self.hnsw_m,
+                    ef_construct: self.hnsw_ef_construct,
+                    full_scan_threshold: HNSW_FULL_SCAN_THRESHOLD_KB,
+                    max_indexing_threads: 0, // Pick based on the number of CPUs
+                    on_disk: Some(on_disk),
+                    payload_m: None,
+                    inline_storage: None,
+                }),
+                quantization_config: self.quantization.config(),
+                multivector_config: None,
+                datatype: None,
+            },
+        );
+
+        SegmentConfig {
+            vector_data,
+            sparse_vector_data: HashMap::new(),
+            payload_storage_type: PayloadStorageType::Mmap,
+        }
+    }
+
+    /// Search parameters for these settings. Quantized searches fetch
+    /// `oversample_factor` times as many candidates and rescore them with the
+    /// full vectors.
+    fn search_params(&self, oversample_factor: usize) -> Option<SearchParams> {
+        let quantization =
+            (self.quantization != Quantization::None).then(|| QuantizationSearchParams {
+                ignore: false,
+                rescore: Some(true),
+                oversampling: Some(oversample_factor.max(1) as f64),
+            });
+        if quantization.is_none() && self.hnsw_ef.is_none() {
+            return None;
+        }
+
+        Some(SearchParams {
+            hnsw_ef: self.hnsw_ef,
+            quantization,
+            ..Default::default()
+        })
+    }
+}
+
+/// Documents that an update writes to both shards. Recorded before the update
+/// starts and removed once it is flushed, so one that was cut short is found
+/// (and undone) the next time the store is opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Journal {
+    paths: Vec<String>,
 }
 
 #[derive(Debug)]
 pub enum DocumentState {
     Unchanged(String),     // Just the filename, no need to process
+    Touched(DocMeta),      // Same content with new file metadata (e.g. after `touch`) to record
     Changed(DocumentInfo), // Full document info for processing
     New(DocumentInfo),     // Full document info for processing
+    Skipped(SkippedFile),  // Binary, too large or unreadable, left out of the workspace
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LineEmbedding {
     pub path: String,
     pub line_number: i32,
+    // Character range within the line, when only a span of a long line is embedded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_column: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_column: Option<usize>,
+    // Fingerprint of the text the embedding was computed from, see `line_text_hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_hash: Option<u64>,
+    // The line (or span) as indexed, so results can show it after the file changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
     #[serde(skip)]
     pub embedding: Vec<f32>,
 }
@@ -80,23 +366,72 @@ impl DocMeta {
 
 impl LineEmbedding {
     pub fn id(&self) -> u64 {
-        // Generate deterministic ID based on path + line number for consistent upserts
+        // Generate deterministic ID based on path + line number for consistent upserts.
+        // Later spans of a long line add their start column; the first span keeps the
+        // line's ID so it replaces the whole-line point.
         let mut bytes = self.path.as_bytes().to_vec();
         bytes.extend_from_slice(&self.line_number.to_le_bytes());
+        if let Some(start_column) = self.start_column.filter(|&start| start > 0) {
+            bytes.extend_from_slice(&(start_column as u64).to_le_bytes());
+        }
         fnv1a_hash(&bytes)
     }
 }
 
+/// SHA-256 of a document's text content, stored as a hex string so that it can be
+/// matched in a payload filter. Documents with the same hash share their vectors,
+/// so it has to be collision resistant.
+pub fn content_hash(content: &str) -> String {
+    hex::encode(Sha256::digest(content.as_bytes()))
+}
+
+/// Fingerprint of the text a line (or span) is embedded from. An edited document
+/// reuses the stored vectors of lines whose fingerprint it still has. The
+/// embedding version is part of it, so vectors from an older pipeline aren't reused.
+pub fn line_text_hash(text: &str) -> u64 {
+    let mut bytes = CURRENT_EMBEDDING_VERSION.to_le_bytes().to_vec();
+    bytes.extend_from_slice(text.as_bytes());
+    fnv1a_hash(&bytes)
+}
+
+/// Which documents a search looks at
+#[derive(Debug, Clone, Copy)]
+pub enum PathScope<'a> {
+    /// Every document in the workspace, without a path filter
+    All,
+    /// Every document but these, e.g. those whose files are gone
+    AllExcept(&'a [String]),
+    /// Only these documents
+    Paths(&'a [String]),
+}
+
 #[derive(Debug, Clone)]
 pub struct RankedLine {
     pub path: String,
     pub line_number: i32,
+    pub columns: Option<ColumnRange>, // Set when the hit is a span of a long line
+    pub distance: f32,
+    pub snapshot: Option<LineSnapshot>, // The lines around the hit as indexed, when stored
+}
+
+/// Consecutive lines of a document as they were when it was indexed
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineSnapshot {
+    pub start: usize, // 0-based line number of the first line
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RankedDocument {
+    pub path: String,
     pub distance: f32,
+    pub best_line: Option<RankedLine>,
 }
 
 #[derive(Debug, Clone)]
 pub struct WorkspaceStats {
     pub total_documents: usize,
+    pub total_lines: usize, // Line embeddings
     pub has_index: bool,
     pub index_type: Option<String>,
 }
@@ -104,15 +439,84 @@ pub struct WorkspaceStats {
 /// Storage wrapper around Qdrant Edge.
 pub struct Store {
     documents_shard: EdgeShard,
-    line_embeddings_shard: EdgeShard,
+    line_embeddings_shard: Option<EdgeShard>, // None only while a rebuild swaps it out
+    dir: PathBuf,
+    index: IndexSettings, // As the line embeddings shard was built
+    search_params: Option<SearchParams>, // For line embedding queries
+    lock: WorkspaceLock,  // Declared last so the shards are closed before it is released
 }
 
 impl Store {
-    /// Initialize or load storage for a workspace directory
+    /// Initialize or load storage for a workspace directory, to read and write.
+    /// Waits for other processes using the workspace to finish with it.
     pub fn open(workspace_dir: &str) -> Result<Self> {
-        let document_shard_path = Path::new(workspace_dir).join("documents.qdrant");
+        Self::open_locked(workspace_dir, LockMode::Exclusive)
+    }
+
+    /// Load storage for a workspace directory to read only, alongside other
+    /// readers. Nothing may be written through the returned store.
+    pub fn open_shared(workspace_dir: &str) -> Result<Self> {
+        Self::open_locked(workspace_dir, LockMode::Shared)
+    }
+
+    /// Load storage for a workspace directory to read only, if that can be done
+    /// right away, e.g. to report on it. None when another process is writing
+    /// to the workspace, or when its shards would have to be created, migrated
+    /// or recovered first.
+    pub fn try_open_shared(workspace_dir: &str) -> Result<Option<Self>> {
+        let Some(lock) = WorkspaceLock::try_acquire(Path::new(workspace_dir), LockMode::Shared)?
+        else {
+            return Ok(None);
+        };
+        if !shards_are_current(Path::new(workspace_dir)) {
+            return Ok(None);
+        }
+        Self::open_with_lock(workspace_dir, lock, Duration::ZERO).map(Some)
+    }
+
+    fn open_locked(workspace_dir: &str, mode: LockMode) -> Result<Self> {
+        let timeout = lock_timeout()?;
+        let lock = lock_workspace_dir(Path::new(workspace_dir), mode, timeout)?;
+        Self::open_with_lock(workspace_dir, lock, timeout)
+    }
 
-        let line_embeddings_shard_path = Path::new(workspace_dir).join("line_embeddings.qdrant");
+    fn open_with_lock(
+        workspace_dir: &str,
+        mut lock: WorkspaceLock,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let document_shard_path = Path::new(workspace_dir).join(DOCUMENTS_SHARD_DIR);
+
+        let line_embeddings_shard_path = Path::new(workspace_dir).join(LINE_EMBEDDINGS_SHARD_DIR);
+        let (rebuilt_path, replaced_path) = rebuild_paths(&line_embeddings_shard_path);
+
+        if !shards_are_current(Path::new(workspace_dir)) && lock.mode() == LockMode::Shared {
+            // Creating, migrating or recovering the shards writes to them
+            drop(lock);
+            lock = lock_workspace_dir(Path::new(workspace_dir), LockMode::Exclusive, timeout)?;
+        }
+
+        // Older layouts used an incompatible documents shard. It only holds metadata
+        // that is rebuilt on the next search, so drop it and let documents re-index.
+        let manifest_path = Path::new(workspace_dir).join(STORE_MANIFEST_FILE);
+        let manifest = std::fs::read_to_string(&manifest_path)
+            .ok()
+            .and_then(|s| serde_json::from_str::<StoreManifest>(&s).ok());
+        let layout_version = manifest.as_ref().map(|m| m.layout_version).unwrap_or(1);
+        if layout_version < STORE_LAYOUT_VERSION && document_shard_path.exists() {
+            std::fs::remove_dir_all(&document_shard_path)?;
+        }
+
+        // A rebuild of the line embeddings shard that was cut short is dropped,
+        // keeping the shard it was to replace, unless it had been swapped in already
+        if !line_embeddings_shard_path.exists() && replaced_path.exists() {
+            std::fs::rename(&replaced_path, &line_embeddings_shard_path)?;
+        }
+        for leftover in [&rebuilt_path, &replaced_path] {
+            if leftover.exists() {
+                std::fs::remove_dir_all(leftover)?;
+            }
+        }
 
         let segment_config_document_shard: Option<SegmentConfig> = if !document_shard_path.exists()
         {
@@ -141,32 +545,17 @@ impl Store {
             None
         };
 
-        // Create shard directories
+        // A new line embeddings shard starts out with the default settings
+        let mut index = manifest.map(|m| m.index).unwrap_or_default();
         let segment_config_line_embeddings_shard: Option<SegmentConfig> =
             if !line_embeddings_shard_path.exists() {
                 std::fs::create_dir_all(&line_embeddings_shard_path)?;
-                let mut vector_data_line_embeddings_shard = HashMap::new();
-                vector_data_line_embeddings_shard.insert(
-                    LINE_EMBEDDINGS_VECTOR_NAME.to_string(),
-                    VectorDataConfig {
-                        size: LINE_EMBEDDING_SIZE,
-                        distance: Distance::Cosine,
-                        storage_type: VectorStorageType::ChunkedMmap,
-                        index: Default::default(),
-                        quantization_config: None,
-                        multivector_config: None,
-                        datatype: None,
-                    },
-                );
-
-                Some(SegmentConfig {
-                    vector_data: vector_data_line_embeddings_shard,
-                    sparse_vector_data: HashMap::new(),
-                    payload_storage_type: PayloadStorageType::Mmap,
-                })
+                index = IndexSettings::default();
+                Some(index.line_embeddings_segment_config())
             } else {
                 None
             };
+        let is_new_line_embeddings_shard = segment_config_line_embeddings_shard.is_some();
 
         let documents_shard = EdgeShard::load(&document_shard_path, segment_config_document_shard)?;
 
@@ -175,10 +564,204 @@ impl Store {
             segment_config_line_embeddings_shard,
         )?;
 
-        Ok(Self {
+        let store = Self {
             documents_shard,
-            line_embeddings_shard,
-        })
+            line_embeddings_shard: Some(line_embeddings_shard),
+            dir: PathBuf::from(workspace_dir),
+            index,
+            search_params: None,
+            lock,
+        };
+        if layout_version != STORE_LAYOUT_VERSION || is_new_line_embeddings_shard {
+            store.write_manifest()?;
+        }
+        store.recover_interrupted_update()?;
+        Ok(store)
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let manifest = StoreManifest {
+            layout_version: STORE_LAYOUT_VERSION,
+            index: self.index,
+        };
+        std::fs::write(
+            self.dir.join(STORE_MANIFEST_FILE),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+        Ok(())
+    }
+
+    fn line_embeddings_shard(&self) -> &EdgeShard {
+        self.line_embeddings_shard.as_ref().expect(SHARD_OPEN)
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.dir.join(JOURNAL_FILE)
+    }
+
+    /// Settings the line embeddings are stored and indexed with
+    pub fn index_settings(&self) -> IndexSettings {
+        self.index
+    }
+
+    /// Use these settings for the line embeddings of the workspace. Search
+    /// settings apply right away. When the shard was built with other storage,
+    /// index or quantization settings, it is rebuilt with these, reusing the
+    /// stored vectors; a store opened shared leaves that to the next writer.
+    pub fn configure(&mut self, settings: &IndexSettings, oversample_factor: usize) -> Result<()> {
+        self.search_params = settings.search_params(oversample_factor);
+        if self.index.needs_rebuild(settings) && self.lock.mode() == LockMode::Exclusive {
+            self.rebuild_line_embeddings(settings)?;
+        }
+        self.index.hnsw_ef = settings.hnsw_ef;
+        Ok(())
+    }
+
+    /// Copy the line embeddings into a new shard built with these settings, and
+    /// swap it in for the current one
+    fn rebuild_line_embeddings(&mut self, settings: &IndexSettings) -> Result<()> {
+        let shard_path = self.dir.join(LINE_EMBEDDINGS_SHARD_DIR);
+        let (rebuilt_path, replaced_path) = rebuild_paths(&shard_path);
+        std::fs::create_dir_all(&rebuilt_path)?;
+        let rebuilt = EdgeShard::load(
+            &rebuilt_path,
+            Some(settings.line_embeddings_segment_config()),
+        )?;
+
+        // Points are copied a page at a time, so that large workspaces don't
+        // have to fit in memory
+        let current = self.line_embeddings_shard.replace(rebuilt);
+        let copied = self.copy_line_embeddings_from(current.as_ref().expect(SHARD_OPEN));
+        drop(std::mem::replace(&mut self.line_embeddings_shard, current));
+        if let Err(e) = copied {
+            std::fs::remove_dir_all(&rebuilt_path)?;
+            return Err(e);
+        }
+
+        // Both shards are closed for the swap, since some platforms don't move
+        // directories with open files. Should the process die part way, opening
+        // the store sorts out the swap.
+        drop(self.line_embeddings_shard.take());
+        let swapped = std::fs::rename(&shard_path, &replaced_path)
+            .and_then(|()| std::fs::rename(&rebuilt_path, &shard_path));
+        if swapped.is_err() {
+            if !shard_path.exists() {
+                std::fs::rename(&replaced_path, &shard_path)?;
+            }
+            let _ = std::fs::remove_dir_all(&rebuilt_path);
+        }
+        self.line_embeddings_shard = Some(EdgeShard::load(&shard_path, None)?);
+        swapped?;
+        std::fs::remove_dir_all(&replaced_path)?;
+
+        self.index = *settings;
+        self.write_manifest()
+    }
+
+    /// Upsert every line embedding of another shard into this store's
+    fn copy_line_embeddings_from(&self, source: &EdgeShard) -> Result<()> {
+        let mut offset = None;
+        loop {
+            let (records, next_offset) = source
+                .scroll(ScrollRequestInternal {
+                    offset,
+                    order_by: None,
+                    with_vector: WithVector::Bool(true),
+                    with_payload: Some(WithPayloadInterface::Bool(true)),
+                    filter: None,
+                    limit: Some(SCROLL_PAGE_POINTS),
+                })
+                .map_err(|e| anyhow!(e.to_string()))?;
+
+            let mut line_embeddings = Vec::with_capacity(records.len());
+            for record in records {
+                let (Some(payload), Some(vector)) = (record.payload, record.vector) else {
+                    continue;
+                };
+                let mut line_embedding = payload_to_line_embedding(&payload)?;
+                if let Some(embedding) = line_vector_from_struct(vector) {
+                    line_embedding.embedding = embedding;
+                    line_embeddings.push(line_embedding);
+                }
+            }
+            self.upsert_line_embeddings(&line_embeddings)?;
+
+            match next_offset {
+                Some(next_offset) => offset = Some(next_offset),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Write to both shards for these documents as a single update. Should the
+    /// process die (or the update fail) part way, the documents are marked to be
+    /// embedded again rather than left with metadata and line embeddings that don't
+    /// agree, see `recover_interrupted_update`.
+    pub fn update_documents<T>(
+        &self,
+        paths: &[String],
+        update: impl FnOnce(&Self) -> Result<T>,
+    ) -> Result<T> {
+        // Written in full or not at all, so a crash can't leave half a journal
+        let pending_path = self.journal_path().with_extension("json.tmp");
+        let mut pending = std::fs::File::create(&pending_path)?;
+        serde_json::to_writer(
+            &mut pending,
+            &Journal {
+                paths: paths.to_vec(),
+            },
+        )?;
+        pending.sync_all()?;
+        std::fs::rename(&pending_path, &self.journal_path())?;
+
+        match update(self) {
+            Ok(result) => {
+                self.flush_documents();
+                self.flush_line_embeddings();
+                std::fs::remove_file(&self.journal_path())?;
+                Ok(result)
+            }
+            Err(e) => {
+                // Left in place for the next open if this fails as well
+                let _ = self.recover_interrupted_update();
+                Err(e)
+            }
+        }
+    }
+
+    /// Settle the documents of an update that didn't finish. Those with metadata
+    /// keep it, and their line embeddings, so they stay part of the workspace, but
+    /// are marked to be embedded again by the next sync (which reuses the vectors of
+    /// lines it still finds). The line embeddings of the rest are dropped.
+    /// Returns how many documents it named.
+    fn recover_interrupted_update(&self) -> Result<usize> {
+        let Ok(journal) = std::fs::read_to_string(&self.journal_path()) else {
+            return Ok(0);
+        };
+        let journal: Journal = serde_json::from_str(&journal)?;
+
+        let existing = self.get_existing_docs(&journal.paths)?;
+        let without_metadata: Vec<String> = journal
+            .paths
+            .iter()
+            .filter(|path| !existing.contains_key(*path))
+            .cloned()
+            .collect();
+        let marked: Vec<DocMeta> = existing
+            .into_values()
+            .map(|meta| DocMeta {
+                _version: 0, // Never current, so the document isn't taken as unchanged
+                content_hash: None,
+                ..meta
+            })
+            .collect();
+        self.touch_document_metadata(&marked)?;
+        self.delete_line_embeddings(&without_metadata)?;
+        self.flush_documents();
+        self.flush_line_embeddings();
+
+        std::fs::remove_file(&self.journal_path())?;
+        Ok(journal.paths.len())
     }
 
     pub fn get_existing_docs(&self, paths: &[String]) -> Result<HashMap<String, DocMeta>> {
@@ -250,27 +833,8 @@ impl Store {
                 order_by: None,
                 with_vector: WithVector::Bool(false),
                 with_payload: Some(WithPayloadInterface::Bool(true)),
-                filter: Some(Filter {
-                    must: Some(vec![
-                        Condition::Field(FieldCondition::new_match(
-                            JsonPath::from_str("path").map_err(|_| {
-                                anyhow!("An error occurred while creating JSONPath from 'path'")
-                            })?,
-                            Match::from(AnyVariants::Strings(chunk.iter().cloned().collect())),
-                        )),
-                        Condition::Field(FieldCondition::new_match(
-                            JsonPath::from_str("_version").map_err(|_| {
-                                anyhow!("An error occurred while creating JSONPath from '_version'")
-                            })?,
-                            Match::new_value(ValueVariants::Integer(
-                                CURRENT_EMBEDDING_VERSION as i64,
-                            )),
-                        )),
-                    ]),
-                    must_not: None,
-                    should: None,
-                    min_should: None,
-                }),
+                // Whatever their version, e.g. documents marked to be embedded again
+                filter: Some(paths_filter(chunk)?),
                 limit: Some(retrieval_limit),
             });
             let records = match scroll_result {
@@ -305,47 +869,231 @@ impl Store {
             return Ok(());
         }
 
+        // collect all point IDs to be deleted
         let mut point_ids: Vec<ExtendedPointId> = vec![];
+        for chunk in paths.chunks(1000) {
+            let points = self.scroll_line_embeddings(Some(paths_filter(chunk)?))?;
+            point_ids.extend(points.into_iter().map(|(id, _)| id));
+        }
+
+        self.delete_line_points(point_ids)
+    }
+
+    /// Replace every line embedding of the given documents with new ones. The new
+    /// points are written before the documents' other points are deleted, so a
+    /// changed document is never left without embeddings, nor with lines past
+    /// its end after it shrank. Points stored with the same position and text
+    /// fingerprint are left as they are. Returns the number of points written.
+    pub fn replace_line_embeddings(
+        &self,
+        paths: &[String],
+        line_embeddings: &[LineEmbedding],
+    ) -> Result<usize> {
+        let mut stored: HashMap<ExtendedPointId, (Option<u64>, Option<String>)> = HashMap::new();
+        for chunk in paths.chunks(1000) {
+            for (id, line_embedding) in self.scroll_line_embeddings(Some(paths_filter(chunk)?))? {
+                stored.insert(id, (line_embedding.text_hash, line_embedding.text));
+            }
+        }
+
+        let changed: Vec<LineEmbedding> = line_embeddings
+            .iter()
+            .filter(|line_embedding| {
+                line_embedding.text_hash.is_none()
+                    || stored.get(&ExtendedPointId::NumId(line_embedding.id()))
+                        != Some(&(line_embedding.text_hash, line_embedding.text.clone()))
+            })
+            .cloned()
+            .collect();
+        self.upsert_line_embeddings(&changed)?;
+
+        let current: HashSet<u64> = line_embeddings.iter().map(LineEmbedding::id).collect();
+        let stale: Vec<ExtendedPointId> = stored
+            .into_keys()
+            .filter(|id| !is_point_in(id, &current))
+            .collect();
+        self.delete_line_points(stale)?;
+
+        Ok(changed.len())
+    }
+
+    /// Stored vectors of the given documents' lines, by the fingerprint of the
+    /// text each was embedded from. Lines stored without a fingerprint are left out.
+    pub fn get_line_vectors(
+        &self,
+        paths: &[String],
+    ) -> Result<HashMap<String, HashMap<u64, Vec<f32>>>> {
+        let mut vectors: HashMap<String, HashMap<u64, Vec<f32>>> = HashMap::new();
+
+        for chunk in paths.chunks(1000) {
+            let filter = paths_filter(chunk)?;
+            let mut offset = None;
+            loop {
+                let (records, next_offset) = self
+                    .line_embeddings_shard()
+                    .scroll(ScrollRequestInternal {
+                        offset,
+                        order_by: None,
+                        with_vector: WithVector::Bool(true),
+                        with_payload: Some(WithPayloadInterface::Bool(true)),
+                        filter: Some(filter.clone()),
+                        limit: Some(SCROLL_PAGE_POINTS),
+                    })
+                    .map_err(|e| anyhow!(e.to_string()))?;
+
+                for record in records {
+                    let (Some(payload), Some(vector)) = (record.payload, record.vector) else {
+                        continue;
+                    };
+                    let line_embedding = payload_to_line_embedding(&payload)?;
+                    if let Some(text_hash) = line_embedding.text_hash
+                        && let Some(embedding) = line_vector_from_struct(vector)
+                    {
+                        vectors
+                            .entry(line_embedding.path)
+                            .or_default()
+                            .insert(text_hash, embedding);
+                    }
+                }
+
+                match next_offset {
+                    Some(next_offset) => offset = Some(next_offset),
+                    None => break,
+                }
+            }
+        }
+
+        Ok(vectors)
+    }
+
+    /// Remove line embeddings that no line of their document accounts for: those of
+    /// documents without metadata, and, for documents unchanged since they were
+    /// embedded, those past the end of the file or of spans that its lines are no
+    /// longer split into. Workspaces written before changed documents had their
+    /// embeddings replaced can hold such points. Returns how many were removed.
+    pub fn remove_orphaned_line_embeddings(&self) -> Result<usize> {
+        let docs = self.get_existing_docs(&self.get_all_document_paths()?)?;
+
+        // Expected point IDs of each document, None when it can't be checked
+        let mut expected: HashMap<String, Option<HashSet<u64>>> = HashMap::new();
+        let mut orphaned: Vec<ExtendedPointId> = vec![];
+        for (id, line_embedding) in self.scroll_line_embeddings(None)? {
+            let Some(meta) = docs.get(&line_embedding.path) else {
+                orphaned.push(id);
+                continue;
+            };
+            let current = expected
+                .entry(line_embedding.path)
+                .or_insert_with(|| current_line_ids(meta));
+            if let Some(current) = current
+                && !is_point_in(&id, current)
+            {
+                orphaned.push(id);
+            }
+        }
+
+        let removed = orphaned.len();
+        self.delete_line_points(orphaned)?;
+        Ok(removed)
+    }
+
+    /// The indexed text of lines `start..end` of a document, or as many of them
+    /// as it had. None when the lines were indexed without their text.
+    pub fn get_line_snapshot(
+        &self,
+        path: &str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<LineSnapshot>> {
+        let path_key = JsonPath::from_str("path")
+            .map_err(|_| anyhow!("An error occurred while creating JSONPath from 'path'"))?;
+        let line_number_key = JsonPath::from_str("line_number")
+            .map_err(|_| anyhow!("An error occurred while creating JSONPath from 'line_number'"))?;
+        let filter = Filter {
+            must: Some(vec![
+                Condition::Field(FieldCondition::new_match(
+                    path_key,
+                    Match::new_value(ValueVariants::String(path.to_string())),
+                )),
+                Condition::Field(FieldCondition::new_match(
+                    line_number_key,
+                    Match::from(AnyVariants::Integers(
+                        (start..end).map(|line| line as i64).collect(),
+                    )),
+                )),
+            ]),
+            must_not: None,
+            should: None,
+            min_should: None,
+        };
+
+        // Spans of a long line are joined back together in column order
+        let mut spans: Vec<LineEmbedding> = self
+            .scroll_line_embeddings(Some(filter))?
+            .into_iter()
+            .map(|(_, line_embedding)| line_embedding)
+            .collect();
+        spans.sort_by_key(|span| (span.line_number, span.start_column));
+
+        let mut lines: Vec<String> = Vec::new();
+        for span in spans {
+            let Some(text) = span.text else {
+                return Ok(None);
+            };
+            let index = span.line_number as usize - start;
+            if index == lines.len() {
+                lines.push(text);
+            } else if index + 1 == lines.len() {
+                lines[index].push_str(&text);
+            } else {
+                return Ok(None); // A line in between is missing
+            }
+        }
+
+        Ok(Some(LineSnapshot { start, lines }))
+    }
+
+    /// Line embedding points matching a filter, with their payloads
+    fn scroll_line_embeddings(
+        &self,
+        filter: Option<Filter>,
+    ) -> Result<Vec<(ExtendedPointId, LineEmbedding)>> {
         let line_embds_count = self.count_line_embeddings();
         let retrieval_limit = match line_embds_count {
             Ok(count) => count,
             Err(_) => DEFAULT_RETRIEVAL_LIMIT,
         };
 
-        // collect all point IDs to be deleted
-        for chunk in paths.chunks(1000) {
-            let scroll_result = self.line_embeddings_shard.scroll(ScrollRequestInternal {
+        let (records, _) = self
+            .line_embeddings_shard()
+            .scroll(ScrollRequestInternal {
                 offset: None,
                 order_by: None,
                 with_vector: WithVector::Bool(false),
                 with_payload: Some(WithPayloadInterface::Bool(true)),
-                filter: Some(Filter::new_must(Condition::Field(
-                    FieldCondition::new_match(
-                        JsonPath::from_str("path").map_err(|_| {
-                            anyhow!("An error occurred while creating JSONPath from 'path'")
-                        })?,
-                        Match::from(AnyVariants::Strings(chunk.iter().cloned().collect())),
-                    ),
-                ))),
+                filter,
                 limit: Some(retrieval_limit),
-            });
-            let records = match scroll_result {
-                Ok(r) => {
-                    let (recs, _) = r;
-                    recs
-                }
-                Err(e) => return Err(anyhow!(e.to_string())),
-            };
-            for record in records {
-                point_ids.push(record.id);
-            }
+            })
+            .map_err(|e| anyhow!(e.to_string()))?;
+
+        records
+            .into_iter()
+            .filter_map(|record| record.payload.map(|p| (record.id, p)))
+            .map(|(id, p)| Ok((id, payload_to_line_embedding(&p)?)))
+            .collect()
+    }
+
+    /// Delete line embedding points by ID
+    fn delete_line_points(&self, point_ids: Vec<ExtendedPointId>) -> Result<()> {
+        if point_ids.is_empty() {
+            return Ok(());
         }
 
         let operation = CollectionUpdateOperations::PointOperation(PointOperations::DeletePoints {
             ids: point_ids,
         });
 
-        self.line_embeddings_shard
+        self.line_embeddings_shard()
             .update(operation)
             .map_err(|e| anyhow!(e.to_string()))?;
 
@@ -362,13 +1110,13 @@ impl Store {
         }
 
         // Delete from both tables to maintain synchronization
-        self.delete_document_metadata(paths)?;
-        self.delete_line_embeddings(paths)?;
-
-        Ok(())
+        self.update_documents(paths, |store| {
+            store.delete_document_metadata(paths)?;
+            store.delete_line_embeddings(paths)
+        })
     }
 
-    /// Upsert documents metadata (no embeddings stored)
+    /// Upsert documents metadata along with their document-level embeddings
     pub fn upsert_document_metadata(&self, metas: &[DocMeta]) -> Result<()> {
         if metas.is_empty() {
             return Ok(());
@@ -379,7 +1127,12 @@ impl Store {
             for meta in chunk {
                 let payload_json =
                     serde_json::to_value(meta).map_err(|e| anyhow!(e.to_string()))?;
-                let vector: Vec<f32> = vec![1_f32];
+                // Metadata without an embedding still needs a vector of the right size
+                let vector: Vec<f32> = if meta.embedding.len() == DOCUMENT_EMBEDDING_SIZE {
+                    meta.embedding.clone()
+                } else {
+                    vec![1_f32; DOCUMENT_EMBEDDING_SIZE]
+                };
                 let point = make_point(meta.id(), vector, payload_json, DOCUMENTS_VECTOR_NAME);
                 points.push(point);
             }
@@ -397,6 +1150,106 @@ impl Store {
         Ok(())
     }
 
+    /// Record the new file metadata of documents whose content didn't change,
+    /// keeping their stored document embeddings
+    pub fn touch_document_metadata(&self, metas: &[DocMeta]) -> Result<()> {
+        let paths: Vec<String> = metas.iter().map(|meta| meta.path.clone()).collect();
+        let embeddings = self.get_document_embeddings(&paths)?;
+        let metas: Vec<DocMeta> = metas
+            .iter()
+            .map(|meta| DocMeta {
+                embedding: embeddings.get(&meta.path).cloned().unwrap_or_default(),
+                ..meta.clone()
+            })
+            .collect();
+        self.upsert_document_metadata(&metas)
+    }
+
+    /// Stored document-level embeddings by path
+    fn get_document_embeddings(&self, paths: &[String]) -> Result<HashMap<String, Vec<f32>>> {
+        let mut embeddings = HashMap::new();
+        let retrieval_limit = match self.count_documents() {
+            Ok(count) => count,
+            Err(_) => DEFAULT_RETRIEVAL_LIMIT,
+        };
+
+        for chunk in paths.chunks(1000) {
+            let (records, _) = self
+                .documents_shard
+                .scroll(ScrollRequestInternal {
+                    offset: None,
+                    order_by: None,
+                    with_vector: WithVector::Bool(true),
+                    with_payload: Some(WithPayloadInterface::Bool(true)),
+                    filter: Some(paths_filter(chunk)?),
+                    limit: Some(retrieval_limit),
+                })
+                .map_err(|e| anyhow!(e.to_string()))?;
+
+            for record in records {
+                let (Some(payload), Some(vector)) = (record.payload, record.vector) else {
+                    continue;
+                };
+                if let Some(embedding) = document_vector_from_struct(vector) {
+                    embeddings.insert(payload_to_doc_meta(&payload)?.path, embedding);
+                }
+            }
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Paths of stored documents with the given content hashes, by hash. When
+    /// several documents share a hash, the first path in sort order is returned.
+    pub fn find_documents_by_content_hash(
+        &self,
+        hashes: &[String],
+    ) -> Result<HashMap<String, String>> {
+        let mut found: HashMap<String, String> = HashMap::new();
+        let retrieval_limit = match self.count_documents() {
+            Ok(count) => count,
+            Err(_) => DEFAULT_RETRIEVAL_LIMIT,
+        };
+        let hash_key = JsonPath::from_str("content_hash").map_err(|_| {
+            anyhow!("An error occurred while creating JSONPath from 'content_hash'")
+        })?;
+
+        for chunk in hashes.chunks(1000) {
+            let (records, _) = self
+                .documents_shard
+                .scroll(ScrollRequestInternal {
+                    offset: None,
+                    order_by: None,
+                    with_vector: WithVector::Bool(false),
+                    with_payload: Some(WithPayloadInterface::Bool(true)),
+                    filter: Some(Filter::new_must(Condition::Field(
+                        FieldCondition::new_match(
+                            hash_key.clone(),
+                            Match::from(AnyVariants::Strings(chunk.iter().cloned().collect())),
+                        ),
+                    ))),
+                    limit: Some(retrieval_limit),
+                })
+                .map_err(|e| anyhow!(e.to_string()))?;
+
+            for record in records {
+                let Some(payload) = record.payload else {
+                    continue;
+                };
+                let meta = payload_to_doc_meta(&payload)?;
+                let Some(hash) = meta.content_hash else {
+                    continue;
+                };
+                let path = found.entry(hash).or_insert_with(|| meta.path.clone());
+                if meta.path < *path {
+                    *path = meta.path;
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
     /// Upsert line embeddings
     pub fn upsert_line_embeddings(&self, line_embeddings: &[LineEmbedding]) -> Result<()> {
         if line_embeddings.is_empty() {
@@ -421,7 +1274,7 @@ impl Store {
             let operation = CollectionUpdateOperations::PointOperation(
                 PointOperations::UpsertPoints(PointInsertOperationsInternal::PointsList(points)),
             );
-            self.line_embeddings_shard
+            self.line_embeddings_shard()
                 .update(operation)
                 .map_err(|e| anyhow!(e.to_string()))?;
 
@@ -435,9 +1288,11 @@ impl Store {
     /// Get workspace statistics
     pub fn get_stats(&self) -> Result<WorkspaceStats> {
         let total_documents = self.count_documents()?;
+        let total_lines = self.count_line_embeddings()?;
 
         Ok(WorkspaceStats {
             total_documents,
+            total_lines,
             has_index: true,
             index_type: Some("HNSW".to_string()),
         })
@@ -480,40 +1335,197 @@ impl Store {
     pub fn search_line_embeddings(
         &self,
         query_vec: &[f32],
-        subset_paths: &[String],
+        scope: PathScope,
+        line_filter: Option<&SearchFilter>,
+        top_k: usize,
+        max_distance: Option<f32>,
+    ) -> Result<Vec<RankedLine>> {
+        let hits =
+            self.query_line_embeddings(query_vec, scope, line_filter, top_k, max_distance, false)?;
+        Ok(hits
+            .into_iter()
+            .map(|(ranked_line, _)| ranked_line)
+            .collect())
+    }
+
+    /// Search within line embeddings, returning each hit together with its stored vector
+    pub fn search_line_embeddings_with_vectors(
+        &self,
+        query_vec: &[f32],
+        scope: PathScope,
+        line_filter: Option<&SearchFilter>,
         top_k: usize,
         max_distance: Option<f32>,
+    ) -> Result<Vec<(RankedLine, Vec<f32>)>> {
+        let hits =
+            self.query_line_embeddings(query_vec, scope, line_filter, top_k, max_distance, true)?;
+        Ok(hits
+            .into_iter()
+            .filter_map(|(ranked_line, embedding)| embedding.map(|e| (ranked_line, e)))
+            .collect())
+    }
+
+    /// Search within line embeddings, re-ranking a larger candidate pool with
+    /// maximal marginal relevance so near-duplicate lines don't crowd out the results.
+    /// Without `top_k`, every hit under `max_distance` is returned.
+    pub fn search_line_embeddings_diverse(
+        &self,
+        query_vec: &[f32],
+        scope: PathScope,
+        line_filter: Option<&SearchFilter>,
+        top_k: Option<usize>,
+        max_distance: Option<f32>,
+        lambda: f64,
     ) -> Result<Vec<RankedLine>> {
+        let limit = match top_k {
+            Some(top_k) => top_k * MMR_CANDIDATE_FACTOR,
+            None => self.count_line_embeddings()?,
+        };
+        let mut hits =
+            self.query_line_embeddings(query_vec, scope, line_filter, limit, max_distance, true)?;
+        // Every hit needs its vector to take part in re-ranking
+        hits.retain(|(_, embedding)| embedding.is_some());
+
+        let distances: Vec<f64> = hits.iter().map(|(r, _)| r.distance as f64).collect();
+        let embeddings: Vec<&[f32]> = hits
+            .iter()
+            .filter_map(|(_, embedding)| embedding.as_deref())
+            .collect();
+        let order = diversified_order(&distances, &embeddings, lambda, top_k);
+
+        Ok(order.into_iter().map(|i| hits[i].0.clone()).collect())
+    }
+
+    /// Nearest-neighbour query over line embeddings, optionally returning the stored vectors.
+    /// With a `line_filter`, only lines whose indexed text passes it are considered;
+    /// lines indexed without their text don't pass.
+    fn query_line_embeddings(
+        &self,
+        query_vec: &[f32],
+        scope: PathScope,
+        line_filter: Option<&SearchFilter>,
+        top_k: usize,
+        max_distance: Option<f32>,
+        with_vector: bool,
+    ) -> Result<Vec<(RankedLine, Option<Vec<f32>>)>> {
+        if top_k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let filters = scope_filters(scope)?;
+
+        let mut all_results: Vec<(RankedLine, Option<Vec<f32>>)> = vec![];
+        let wanted = top_k * 2;
+
+        for filter in filters {
+            // Lines failing the line filter are dropped after the query, so keep
+            // fetching further (and larger) pages until enough lines pass
+            let mut offset = 0;
+            let mut page_size = wanted;
+            let mut kept = 0;
+            loop {
+                let query: Vec<f32> = query_vec.into();
+                let vector: VectorInternal = query.into();
+                let score_threshold: Option<OrderedFloat<f32>> =
+                    max_distance.map(|max_dist| OrderedFloat(1_f32 - max_dist));
+                let results = self
+                    .line_embeddings_shard()
+                    .query(ShardQueryRequest {
+                        prefetches: vec![],
+                        query: Some(ScoringQuery::Vector(QueryEnum::Nearest(NamedQuery {
+                            query: vector,
+                            using: Some(LINE_EMBEDDINGS_VECTOR_NAME.to_string()),
+                        }))),
+                        filter: filter.clone(),
+                        score_threshold,
+                        limit: page_size,
+                        offset,
+                        params: self.search_params,
+                        with_vector: WithVector::Bool(with_vector),
+                        with_payload: WithPayloadInterface::Bool(true),
+                    })
+                    .map_err(|e| anyhow!(e.to_string()))?;
+                let exhausted = results.len() < page_size;
+
+                for result in results {
+                    if let Some(p) = result.payload {
+                        let line_embd = payload_to_line_embedding(&p)?;
+                        if let Some(line_filter) = line_filter
+                            && !line_embd
+                                .text
+                                .as_deref()
+                                .is_some_and(|text| line_filter.matches_line(text))
+                        {
+                            continue;
+                        }
+                        let columns = line_embd
+                            .start_column
+                            .zip(line_embd.end_column)
+                            .map(|(start, end)| ColumnRange { start, end });
+                        let ranked_line = RankedLine {
+                            line_number: line_embd.line_number,
+                            columns,
+                            path: line_embd.path,
+                            distance: 1_f32 - result.score,
+                            snapshot: None,
+                        };
+                        let embedding = result.vector.and_then(line_vector_from_struct);
+                        all_results.push((ranked_line, embedding));
+                        kept += 1;
+                    }
+                }
+
+                if line_filter.is_none() || kept >= wanted || exhausted {
+                    break;
+                }
+                offset += page_size;
+                page_size *= 2;
+            }
+        }
+
+        all_results.sort_by(|a, b| {
+            a.0.distance
+                .partial_cmp(&b.0.distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        all_results.truncate(top_k);
+
+        Ok(all_results)
+    }
+
+    /// Search within document-level embeddings
+    pub fn search_document_embeddings(
+        &self,
+        query_vec: &[f32],
+        scope: PathScope,
+        top_k: usize,
+        max_distance: Option<f32>,
+    ) -> Result<Vec<RankedDocument>> {
+        let filters = scope_filters(scope)?;
+
         // Short-circuit on empty subsets
-        if subset_paths.is_empty() || top_k == 0 {
+        if filters.is_empty() || top_k == 0 {
             return Ok(Vec::new());
         }
 
-        let mut all_results: Vec<RankedLine> = vec![];
+        let mut all_results: Vec<RankedDocument> = vec![];
 
-        for chunk in subset_paths.chunks(1000) {
+        for filter in filters {
             let query: Vec<f32> = query_vec.into();
             let vector: VectorInternal = query.into();
             let score_threshold: Option<OrderedFloat<f32>> =
                 max_distance.map(|max_dist| OrderedFloat(1_f32 - max_dist));
             let results = self
-                .line_embeddings_shard
+                .documents_shard
                 .query(ShardQueryRequest {
                     prefetches: vec![],
                     query: Some(ScoringQuery::Vector(QueryEnum::Nearest(NamedQuery {
                         query: vector,
-                        using: Some(LINE_EMBEDDINGS_VECTOR_NAME.to_string()),
+                        using: Some(DOCUMENTS_VECTOR_NAME.to_string()),
                     }))),
-                    filter: Some(Filter::new_must(Condition::Field(
-                        FieldCondition::new_match(
-                            JsonPath::from_str("path").map_err(|_| {
-                                anyhow!("An error occurred while creating JSONPath from 'path'")
-                            })?,
-                            Match::from(AnyVariants::Strings(chunk.iter().cloned().collect())),
-                        ),
-                    ))),
+                    filter,
                     score_threshold,
-                    limit: top_k * 2,
+                    limit: top_k,
                     offset: 0,
                     params: None,
                     with_vector: WithVector::Bool(false),
@@ -523,13 +1535,12 @@ impl Store {
 
             for result in results {
                 if let Some(p) = result.payload {
-                    let line_embd = payload_to_line_embedding(&p)?;
-                    let ranked_line = RankedLine {
-                        line_number: line_embd.line_number,
-                        path: line_embd.path,
+                    let doc_meta = payload_to_doc_meta(&p)?;
+                    all_results.push(RankedDocument {
+                        path: doc_meta.path,
                         distance: 1_f32 - result.score,
-                    };
-                    all_results.push(ranked_line);
+                        best_line: None,
+                    });
                 }
             }
         }
@@ -545,7 +1556,11 @@ impl Store {
     }
 
     /// Analyze the state of documents within the workspace
-    pub fn analyze_document_states(&self, file_paths: &[String]) -> Result<Vec<DocumentState>> {
+    pub fn analyze_document_states(
+        &self,
+        file_paths: &[String],
+        max_file_size: Option<u64>,
+    ) -> Result<Vec<DocumentState>> {
         // Get existing document metadata from workspace
         let existing_docs = self.get_existing_docs(file_paths)?;
 
@@ -556,17 +1571,14 @@ impl Store {
             let current_meta = match std::fs::metadata(file_path) {
                 Ok(metadata) => {
                     let size_bytes = metadata.len();
-                    let mtime = metadata
-                        .modified()
-                        .ok()
-                        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-                        .map(|d| d.as_secs() as i64)
-                        .unwrap_or(0);
+                    let mtime = modified_secs(&metadata);
                     DocMeta {
                         path: file_path.clone(),
                         size_bytes,
                         mtime,
                         _version: CURRENT_EMBEDDING_VERSION,
+                        content_hash: None,
+                        embedding: Vec::new(),
                     }
                 }
                 Err(_) => {
@@ -576,34 +1588,51 @@ impl Store {
             };
 
             // Check if document exists in workspace and has changed
-            match existing_docs.get(file_path) {
-                Some(existing_meta) => {
-                    if existing_meta.size_bytes != current_meta.size_bytes
-                        || existing_meta.mtime != current_meta.mtime
-                        || existing_meta._version != CURRENT_EMBEDDING_VERSION
-                    {
-                        // Document has changed
-                        let content = std::fs::read_to_string(file_path)?;
-                        states.push(DocumentState::Changed(DocumentInfo {
-                            filename: file_path.clone(),
-                            content,
-                            meta: current_meta,
-                        }));
-                    } else {
-                        // Document unchanged
-                        states.push(DocumentState::Unchanged(file_path.clone()));
-                    }
-                }
-                None => {
-                    // New document
-                    let content = std::fs::read_to_string(file_path)?;
-                    states.push(DocumentState::New(DocumentInfo {
-                        filename: file_path.clone(),
-                        content,
-                        meta: current_meta,
+            let existing_meta = existing_docs.get(file_path);
+            if let Some(existing_meta) = existing_meta
+                && existing_meta.size_bytes == current_meta.size_bytes
+                && existing_meta.mtime == current_meta.mtime
+                && existing_meta._version == CURRENT_EMBEDDING_VERSION
+            {
+                // Document unchanged
+                states.push(DocumentState::Unchanged(file_path.clone()));
+                continue;
+            }
+
+            let content = match read_text_file(file_path, max_file_size) {
+                Ok(content) => content,
+                Err(reason) => {
+                    states.push(DocumentState::Skipped(SkippedFile {
+                        path: file_path.clone(),
+                        reason,
                     }));
+                    continue;
                 }
-            }
+            };
+            let meta = DocMeta {
+                content_hash: Some(content_hash(&content)),
+                ..current_meta
+            };
+
+            states.push(match existing_meta {
+                // Only the file metadata changed, e.g. after `touch` or `git checkout`
+                Some(existing_meta)
+                    if existing_meta._version == CURRENT_EMBEDDING_VERSION
+                        && existing_meta.content_hash == meta.content_hash =>
+                {
+                    DocumentState::Touched(meta)
+                }
+                Some(_) => DocumentState::Changed(DocumentInfo {
+                    filename: file_path.clone(),
+                    content,
+                    meta,
+                }),
+                None => DocumentState::New(DocumentInfo {
+                    filename: file_path.clone(),
+                    content,
+                    meta,
+                }),
+            });
         }
 
         Ok(states)
@@ -625,7 +1654,7 @@ impl Store {
     /// Get the number of indexed points in the documents shard
     pub fn count_line_embeddings(&self) -> Result<usize> {
         let count = self
-            .line_embeddings_shard
+            .line_embeddings_shard()
             .count(CountRequestInternal {
                 filter: None,
                 exact: true,
@@ -642,10 +1671,59 @@ impl Store {
 
     /// Flush all line embeddings data to disk.
     pub fn flush_line_embeddings(&self) {
-        self.line_embeddings_shard.flush();
+        self.line_embeddings_shard().flush();
     }
 }
 
+/// Modification time of a file in seconds since the epoch, or 0 when unknown
+fn modified_secs(metadata: &std::fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// IDs of the line embeddings of a document's current content, when the file is
+/// unchanged since it was embedded. A changed file has all of its embeddings
+/// replaced by the next search or index run that covers it.
+fn current_line_ids(meta: &DocMeta) -> Option<HashSet<u64>> {
+    let metadata = std::fs::metadata(&meta.path).ok()?;
+    if metadata.len() != meta.size_bytes
+        || modified_secs(&metadata) != meta.mtime
+        || meta._version != CURRENT_EMBEDDING_VERSION
+    {
+        return None;
+    }
+
+    let content = read_text_file(&meta.path, None).ok()?;
+    let lines: Vec<String> = content.lines().map(|line| line.to_string()).collect();
+    let (segments, _) = segment_lines(&lines);
+    Some(
+        segments
+            .iter()
+            .map(|segment| {
+                LineEmbedding {
+                    path: meta.path.clone(),
+                    line_number: segment.line as i32,
+                    start_column: segment.columns.map(|c| c.start),
+                    end_column: segment.columns.map(|c| c.end),
+                    text_hash: None,
+                    text: None,
+                    embedding: Vec::new(),
+                }
+                .id()
+            })
+            .collect(),
+    )
+}
+
+/// Whether a point has one of the given numeric IDs
+fn is_point_in(id: &ExtendedPointId, ids: &HashSet<u64>) -> bool {
+    matches!(id, ExtendedPointId::NumId(num) if ids.contains(num))
+}
+
 /// Generate a stable hash for a byte slice using the FNV-1a algorithm.
 fn fnv1a_hash(bytes: &[u8]) -> u64 {
     const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
@@ -676,6 +1754,91 @@ fn make_point(
     }
 }
 
+/// Where a rebuilt line embeddings shard is written, and where the shard it
+/// replaces is moved while they are swapped
+fn rebuild_paths(shard_path: &Path) -> (PathBuf, PathBuf) {
+    (
+        shard_path.with_extension("qdrant.rebuild"),
+        shard_path.with_extension("qdrant.old"),
+    )
+}
+
+/// Lock a workspace directory, creating it for a new workspace. Fails when the
+/// process that held the lock deleted or renamed the workspace meanwhile, rather
+/// than bringing it back without its config.
+fn lock_workspace_dir(
+    workspace_dir: &Path,
+    mode: LockMode,
+    timeout: Duration,
+) -> Result<WorkspaceLock> {
+    let existed = workspace_dir.exists();
+    let lock = WorkspaceLock::acquire(workspace_dir, mode, timeout)?;
+    if existed && !workspace_dir.exists() {
+        return Err(anyhow!(
+            "Workspace at '{}' was deleted or renamed by another semtools process",
+            workspace_dir.display()
+        ));
+    }
+    std::fs::create_dir_all(workspace_dir)?;
+    Ok(lock)
+}
+
+/// Whether the shards of a workspace can be loaded as they are, without being
+/// created, migrated or recovered first
+fn shards_are_current(workspace_dir: &Path) -> bool {
+    let line_embeddings_shard_path = workspace_dir.join(LINE_EMBEDDINGS_SHARD_DIR);
+    let (rebuilt_path, replaced_path) = rebuild_paths(&line_embeddings_shard_path);
+    let layout_version = std::fs::read_to_string(workspace_dir.join(STORE_MANIFEST_FILE))
+        .ok()
+        .and_then(|s| serde_json::from_str::<StoreManifest>(&s).ok())
+        .map(|m| m.layout_version)
+        .unwrap_or(1);
+    layout_version == STORE_LAYOUT_VERSION
+        && workspace_dir.join(DOCUMENTS_SHARD_DIR).exists()
+        && line_embeddings_shard_path.exists()
+        && !workspace_dir.join(JOURNAL_FILE).exists()
+        && !rebuilt_path.exists()
+        && !replaced_path.exists()
+}
+
+/// Payload filter matching the points of the documents with the given paths
+fn paths_filter(paths: &[String]) -> Result<Filter> {
+    Ok(Filter::new_must(paths_condition(paths)?))
+}
+
+fn paths_condition(paths: &[String]) -> Result<Condition> {
+    let path_key = JsonPath::from_str("path")
+        .map_err(|_| anyhow!("An error occurred while creating JSONPath from 'path'"))?;
+    Ok(Condition::Field(FieldCondition::new_match(
+        path_key,
+        Match::from(AnyVariants::Strings(paths.iter().cloned().collect())),
+    )))
+}
+
+/// One query filter per chunk of paths, or a single query over the whole
+/// workspace, unfiltered unless some documents are left out
+fn scope_filters(scope: PathScope) -> Result<Vec<Option<Filter>>> {
+    match scope {
+        PathScope::All => Ok(vec![None]),
+        PathScope::AllExcept([]) => Ok(vec![None]),
+        PathScope::AllExcept(paths) => Ok(vec![Some(Filter {
+            must: None,
+            must_not: Some(
+                paths
+                    .chunks(1000)
+                    .map(paths_condition)
+                    .collect::<Result<_>>()?,
+            ),
+            should: None,
+            min_should: None,
+        })]),
+        PathScope::Paths(paths) => paths
+            .chunks(1000)
+            .map(|chunk| paths_filter(chunk).map(Some))
+            .collect(),
+    }
+}
+
 /// Convert JSON value (DocMeta or LineEmbedding struct) to Qdrant Payload.
 fn json_to_payload(value: Value) -> Payload {
     if let Value::Object(map) = value {
@@ -689,6 +1852,26 @@ fn json_to_payload(value: Value) -> Payload {
     }
 }
 
+/// Extract the dense line embedding from a stored point's vectors
+fn line_vector_from_struct(vectors: VectorStructInternal) -> Option<Vec<f32>> {
+    named_vector_from_struct(vectors, LINE_EMBEDDINGS_VECTOR_NAME)
+}
+
+/// Extract the dense document embedding from a stored point's vectors
+fn document_vector_from_struct(vectors: VectorStructInternal) -> Option<Vec<f32>> {
+    named_vector_from_struct(vectors, DOCUMENTS_VECTOR_NAME)
+}
+
+fn named_vector_from_struct(vectors: VectorStructInternal, name: &str) -> Option<Vec<f32>> {
+    match vectors {
+        VectorStructInternal::Named(mut named) => match named.remove(name) {
+            Some(VectorInternal::Dense(vector)) => Some(vector),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 /// Convert Qdrant Payload back to DocMeta
 fn payload_to_doc_meta(payload: &Payload) -> Result<DocMeta> {
     let json_map: serde_json::Map<String, Value> = payload
@@ -734,18 +1917,24 @@ mod tests {
                 size_bytes: 100,
                 mtime: 1234567890,
                 _version: CURRENT_EMBEDDING_VERSION,
+                content_hash: None,
+                embedding: vec![],
             },
             DocMeta {
                 path: "/test/doc2.txt".to_string(),
                 size_bytes: 200,
                 mtime: 1234567891,
                 _version: CURRENT_EMBEDDING_VERSION,
+                content_hash: None,
+                embedding: vec![],
             },
             DocMeta {
                 path: "/test/doc3.txt".to_string(),
                 size_bytes: 150,
                 mtime: 1234567892,
                 _version: CURRENT_EMBEDDING_VERSION,
+                content_hash: None,
+                embedding: vec![],
             },
         ];
 
@@ -765,6 +1954,7 @@ mod tests {
         let stats = store.get_stats().expect("Failed to get stats");
 
         assert_eq!(stats.total_documents, 0);
+        assert_eq!(stats.total_lines, 0);
         assert!(stats.has_index);
         assert_eq!(stats.index_type, Some("HNSW".to_string()));
 
@@ -791,6 +1981,10 @@ mod tests {
             .map(|(i, doc)| LineEmbedding {
                 path: doc.path.clone(),
                 line_number: i as i32,
+                start_column: None,
+                end_column: None,
+                text_hash: None,
+                text: None,
                 embedding: embeddings[i].clone(),
             })
             .collect();
@@ -803,6 +1997,7 @@ mod tests {
         let stats = store.get_stats().expect("Failed to get stats");
 
         assert_eq!(stats.total_documents, 3);
+        assert_eq!(stats.total_lines, 3);
         assert!(stats.has_index);
         assert_eq!(stats.index_type, Some("HNSW".to_string()));
 
@@ -821,6 +2016,10 @@ mod tests {
             .map(|(i, doc)| LineEmbedding {
                 path: doc.path.clone(),
                 line_number: i as i32,
+                start_column: None,
+                end_column: None,
+                text_hash: None,
+                text: None,
                 embedding: embeddings[i].clone(),
             })
             .collect();
@@ -834,7 +2033,8 @@ mod tests {
         let search_results = store
             .search_line_embeddings(
                 exact_match_query.as_slice(),
-                &["/test/doc1.txt".to_string()],
+                PathScope::Paths(&["/test/doc1.txt".to_string()]),
+                None,
                 1,
                 Some(0.1_f32),
             )
@@ -848,6 +2048,291 @@ mod tests {
         drop(_temp_dir);
     }
 
+    #[test]
+    fn test_search_line_embeddings_line_filter() {
+        let (store, _temp_dir) = create_test_store();
+        let (docs, embeddings) = create_test_docs();
+
+        let texts = ["connect to the database", "retry after a timeout", "close"];
+        let line_embeddings: Vec<LineEmbedding> = docs
+            .iter()
+            .enumerate()
+            .map(|(i, doc)| LineEmbedding {
+                path: doc.path.clone(),
+                line_number: i as i32,
+                start_column: None,
+                end_column: None,
+                text_hash: None,
+                // The last line is indexed without its text
+                text: (i < 2).then(|| texts[i].to_string()),
+                embedding: embeddings[i].clone(),
+            })
+            .collect();
+        store
+            .upsert_line_embeddings(&line_embeddings)
+            .expect("Failed to upsert line embeddings");
+
+        // Only the second document's line passes, even though the first is a closer match
+        let paths: Vec<String> = docs.iter().map(|doc| doc.path.clone()).collect();
+        let filter = SearchFilter::new(Some("timeout|close"), &[], &[], &[], false).unwrap();
+        let search_results = store
+            .search_line_embeddings(
+                &[0.1; 256],
+                PathScope::Paths(&paths),
+                Some(&filter),
+                3,
+                None,
+            )
+            .expect("Should be able to retrieve search results");
+
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].path, docs[1].path);
+        assert_eq!(search_results[0].line_number, 1);
+
+        // Searching the whole workspace applies the filter too, even for a single hit
+        let search_results = store
+            .search_line_embeddings(&[0.1; 256], PathScope::All, Some(&filter), 1, None)
+            .expect("Should be able to retrieve search results");
+        assert_eq!(search_results.len(), 1);
+        assert_eq!(search_results[0].path, docs[1].path);
+
+        // Without a line filter, every line is a candidate
+        let search_results = store
+            .search_line_embeddings(&[0.1; 256], PathScope::All, None, 3, None)
+            .expect("Should be able to retrieve search results");
+        assert_eq!(search_results.len(), docs.len());
+
+        // Documents left out of the whole workspace aren't
+        let gone = [docs[0].path.clone()];
+        let search_results = store
+            .search_line_embeddings(&[0.1; 256], PathScope::AllExcept(&gone), None, 3, None)
+            .expect("Should be able to retrieve search results");
+        assert_eq!(search_results.len(), docs.len() - 1);
+        assert!(search_results.iter().all(|r| r.path != docs[0].path));
+
+        drop(store);
+        drop(_temp_dir);
+    }
+
+    #[test]
+    fn test_search_line_embeddings_diverse() {
+        let (store, _temp_dir) = create_test_store();
+
+        // Two identical lines and one distinct line that is slightly further away
+        let near: Vec<f32> = (0..256).map(|i| if i < 128 { 1.0 } else { 0.0 }).collect();
+        let other: Vec<f32> = (0..256).map(|i| if i < 96 { 1.0 } else { 0.1 }).collect();
+        let line_embeddings = vec![
+            LineEmbedding {
+                path: "/test/doc1.txt".to_string(),
+                line_number: 0,
+                start_column: None,
+                end_column: None,
+                text_hash: None,
+                text: None,
+                embedding: near.clone(),
+            },
+            LineEmbedding {
+                path: "/test/doc2.txt".to_string(),
+                line_number: 0,
+                start_column: None,
+                end_column: None,
+                text_hash: None,
+                text: None,
+                embedding: near.clone(),
+            },
+            LineEmbedding {
+                path: "/test/doc3.txt".to_string(),
+                line_number: 0,
+                start_column: None,
+                end_column: None,
+                text_hash: None,
+                text: None,
+                embedding: other,
+            },
+        ];
+        store
+            .upsert_line_embeddings(&line_embeddings)
+            .expect("Failed to upsert line embeddings");
+
+        let paths: Vec<String> = line_embeddings.iter().map(|l| l.path.clone()).collect();
+
+        // Plain search returns both duplicates first
+        let plain = store
+            .search_line_embeddings(&near, PathScope::Paths(&paths), None, 2, None)
+            .expect("Should be able to search");
+        assert_eq!(plain.len(), 2);
+        assert!(plain.iter().all(|r| r.path != "/test/doc3.txt"));
+
+        // Diverse search swaps the second duplicate for the distinct line
+        let diverse = store
+            .search_line_embeddings_diverse(
+                &near,
+                PathScope::Paths(&paths),
+                None,
+                Some(2),
+                None,
+                0.3,
+            )
+            .expect("Should be able to search");
+        assert_eq!(diverse.len(), 2);
+        assert!(diverse.iter().any(|r| r.path == "/test/doc3.txt"));
+
+        // With a threshold and no top_k, every hit under it comes back, re-ordered
+        let all = store
+            .search_line_embeddings_diverse(
+                &near,
+                PathScope::Paths(&paths),
+                None,
+                None,
+                Some(2.0),
+                0.3,
+            )
+            .expect("Should be able to search");
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[1].path, "/test/doc3.txt");
+
+        drop(store);
+        drop(_temp_dir);
+    }
+
+    #[test]
+    fn test_search_document_embeddings() {
+        let (store, _temp_dir) = create_test_store();
+        let (mut docs, embeddings) = create_test_docs();
+
+        for (doc, embedding) in docs.iter_mut().zip(embeddings) {
+            doc.embedding = embedding;
+        }
+        docs[1].embedding = (0..256)
+            .map(|i| if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+
+        store
+            .upsert_document_metadata(&docs)
+            .expect("Failed to upsert documents");
+
+        let query: Vec<f32> = (0..256)
+            .map(|i| if i % 2 == 0 { 1.0 } else { -1.0 })
+            .collect();
+        let all_paths: Vec<String> = docs.iter().map(|d| d.path.clone()).collect();
+        let results = store
+            .search_document_embeddings(&query, PathScope::Paths(&all_paths), 3, None)
+            .expect("Should be able to search document embeddings");
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].path, "/test/doc2.txt");
+        assert!(results[0].distance < 0.01);
+        assert!(results[0].best_line.is_none());
+
+        // The whole workspace gives the same ranking without a path filter
+        let everything = store
+            .search_document_embeddings(&query, PathScope::All, 3, None)
+            .expect("Should be able to search document embeddings");
+        let paths = |results: &[RankedDocument]| -> Vec<String> {
+            results.iter().map(|r| r.path.clone()).collect()
+        };
+        assert_eq!(paths(&everything), paths(&results));
+
+        // Restricting the subset only returns documents within it
+        let results = store
+            .search_document_embeddings(
+                &query,
+                PathScope::Paths(&["/test/doc1.txt".to_string()]),
+                3,
+                None,
+            )
+            .expect("Should be able to search document embeddings");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, "/test/doc1.txt");
+
+        drop(store);
+        drop(_temp_dir);
+    }
+
+    #[test]
+    fn test_store_open_writes_layout_manifest() {
+        let (store, temp_dir) = create_test_store();
+
+        let manifest_path = temp_dir.path().join(STORE_MANIFEST_FILE);
+        let manifest: StoreManifest =
+            serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        assert_eq!(manifest.layout_version, STORE_LAYOUT_VERSION);
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    fn test_index_settings() {
+        assert_eq!(Quantization::parse("Scalar").unwrap(), Quantization::Scalar);
+        assert!(Quantization::parse("pq").is_err());
+        assert_eq!(VectorStorage::parse("ram").unwrap(), VectorStorage::Memory);
+
+        let default = IndexSettings::default();
+        assert!(default.search_params(3).is_none());
+        let tuned_search = IndexSettings {
+            hnsw_ef: Some(128),
+            ..default
+        };
+        assert!(!default.needs_rebuild(&tuned_search));
+        assert_eq!(tuned_search.search_params(3).unwrap().hnsw_ef, Some(128));
+
+        let quantized = IndexSettings {
+            quantization: Quantization::Binary,
+            ..default
+        };
+        assert!(default.needs_rebuild(&quantized));
+        let params = quantized.search_params(4).unwrap();
+        let quantization = params.quantization.unwrap();
+        assert_eq!(quantization.oversampling, Some(4.0));
+        assert_eq!(quantization.rescore, Some(true));
+
+        assert!(default.validate().is_ok());
+        for invalid in [
+            IndexSettings {
+                hnsw_m: 1,
+                ..default
+            },
+            IndexSettings {
+                hnsw_ef_construct: 0,
+                ..default
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_configure_rebuilds_line_embeddings() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().to_str().unwrap();
+        let mut store = Store::open(dir).unwrap();
+        let lines: Vec<LineEmbedding> = (0..2500).map(|i| line("/test/doc1.txt", i)).collect();
+        store.upsert_line_embeddings(&lines).unwrap();
+
+        let settings = IndexSettings {
+            quantization: Quantization::Scalar,
+            storage: VectorStorage::Memory,
+            hnsw_m: 8,
+            ..Default::default()
+        };
+        store.configure(&settings, 2).unwrap();
+        assert_eq!(store.index_settings(), settings);
+        assert_eq!(store.count_line_embeddings().unwrap(), 2500);
+        let hits = store
+            .search_line_embeddings(&[0.1; 256], PathScope::All, None, 3, None)
+            .unwrap();
+        assert_eq!(hits.len(), 3);
+
+        // The settings the shard was built with are kept, and the old shard is gone
+        drop(store);
+        let store = Store::open_shared(dir).unwrap();
+        assert_eq!(store.index_settings(), settings);
+        let (rebuilt_path, replaced_path) =
+            rebuild_paths(&temp_dir.path().join(LINE_EMBEDDINGS_SHARD_DIR));
+        assert!(!rebuilt_path.exists() && !replaced_path.exists());
+    }
+
     #[test]
     fn test_get_all_document_paths() {
         let (store, _temp_dir) = create_test_store();
@@ -956,6 +2441,8 @@ mod tests {
             size_bytes: 100,
             mtime: 1000,
             _version: CURRENT_EMBEDDING_VERSION,
+            content_hash: None,
+            embedding: vec![],
         };
         let _initial_embedding = [vec![1.0, 2.0, 3.0, 4.0]];
 
@@ -975,6 +2462,8 @@ mod tests {
             size_bytes: 200,
             mtime: 2000,
             _version: CURRENT_EMBEDDING_VERSION,
+            content_hash: None,
+            embedding: vec![],
         };
         let _updated_embedding = [vec![5.0, 6.0, 7.0, 8.0]];
 
@@ -998,6 +2487,343 @@ mod tests {
         drop(_temp_dir);
     }
 
+    fn line(path: &str, line_number: i32) -> LineEmbedding {
+        LineEmbedding {
+            path: path.to_string(),
+            line_number,
+            start_column: None,
+            end_column: None,
+            text_hash: None,
+            text: None,
+            embedding: vec![0.1; 256],
+        }
+    }
+
+    #[test]
+    fn test_replace_line_embeddings_drops_stale_lines() {
+        let (store, _temp_dir) = create_test_store();
+
+        let before: Vec<LineEmbedding> = (0..5).map(|i| line("/test/doc1.txt", i)).collect();
+        store.upsert_line_embeddings(&before).unwrap();
+        store
+            .upsert_line_embeddings(&[line("/test/doc2.txt", 0)])
+            .unwrap();
+
+        // The document shrank to two lines
+        let after: Vec<LineEmbedding> = (0..2).map(|i| line("/test/doc1.txt", i)).collect();
+        store
+            .replace_line_embeddings(&["/test/doc1.txt".to_string()], &after)
+            .unwrap();
+
+        let mut lines: Vec<(String, i32)> = store
+            .scroll_line_embeddings(None)
+            .unwrap()
+            .into_iter()
+            .map(|(_, l)| (l.path, l.line_number))
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                ("/test/doc1.txt".to_string(), 0),
+                ("/test/doc1.txt".to_string(), 1),
+                ("/test/doc2.txt".to_string(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_line_embeddings_keeps_unchanged_points() {
+        let (store, _temp_dir) = create_test_store();
+        let paths = vec!["/test/doc1.txt".to_string()];
+        let fingerprinted = |texts: &[&str]| -> Vec<LineEmbedding> {
+            texts
+                .iter()
+                .enumerate()
+                .map(|(i, text)| LineEmbedding {
+                    text_hash: Some(line_text_hash(text)),
+                    ..line("/test/doc1.txt", i as i32)
+                })
+                .collect()
+        };
+
+        let lines = fingerprinted(&["first", "second", "third"]);
+        assert_eq!(store.replace_line_embeddings(&paths, &lines).unwrap(), 3);
+        assert_eq!(store.replace_line_embeddings(&paths, &lines).unwrap(), 0);
+
+        // Editing the last line only rewrites that line
+        let lines = fingerprinted(&["first", "second", "3rd"]);
+        assert_eq!(store.replace_line_embeddings(&paths, &lines).unwrap(), 1);
+
+        let vectors = store.get_line_vectors(&paths).unwrap();
+        let stored = &vectors["/test/doc1.txt"];
+        assert_eq!(stored.len(), 3);
+        assert!(stored.contains_key(&line_text_hash("3rd")));
+        assert!(!stored.contains_key(&line_text_hash("third")));
+        assert_eq!(stored[&line_text_hash("first")].len(), 256);
+    }
+
+    #[test]
+    fn test_get_line_snapshot() {
+        let (store, _temp_dir) = create_test_store();
+        let with_text =
+            |line_number: i32, columns: Option<(usize, usize)>, text: &str| LineEmbedding {
+                start_column: columns.map(|c| c.0),
+                end_column: columns.map(|c| c.1),
+                text: Some(text.to_string()),
+                ..line("/test/doc1.txt", line_number)
+            };
+        store
+            .upsert_line_embeddings(&[
+                with_text(0, None, "first"),
+                with_text(1, Some((0, 5)), "long "),
+                with_text(1, Some((5, 9)), "line"),
+                with_text(2, None, "last"),
+            ])
+            .unwrap();
+
+        // Spans are joined, and the range stops at the end of the document
+        let snapshot = store.get_line_snapshot("/test/doc1.txt", 1, 5).unwrap();
+        assert_eq!(
+            snapshot,
+            Some(LineSnapshot {
+                start: 1,
+                lines: vec!["long line".to_string(), "last".to_string()],
+            })
+        );
+
+        // Lines indexed without their text have no snapshot
+        store
+            .upsert_line_embeddings(&[line("/test/doc2.txt", 0)])
+            .unwrap();
+        assert_eq!(
+            store.get_line_snapshot("/test/doc2.txt", 0, 1).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parallel_writers_and_readers_keep_shards_consistent() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().to_str().unwrap().to_string();
+        drop(Store::open(&dir).unwrap());
+
+        // Each writer indexes documents of ten lines, one at a time, like
+        // separate `search --workspace` processes syncing their files
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    for doc in 0..3 {
+                        let store = Store::open(&dir).unwrap();
+                        let path = format!("/test/writer{writer}/doc{doc}.txt");
+                        let lines: Vec<LineEmbedding> = (0..10).map(|i| line(&path, i)).collect();
+                        store
+                            .replace_line_embeddings(std::slice::from_ref(&path), &lines)
+                            .unwrap();
+                        store
+                            .upsert_document_metadata(&[DocMeta {
+                                path,
+                                size_bytes: 10,
+                                mtime: 0,
+                                _version: CURRENT_EMBEDDING_VERSION,
+                                content_hash: None,
+                                embedding: vec![],
+                            }])
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        // Readers never see a document without its lines, or lines without their document
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..5 {
+                        let store = Store::open_shared(&dir).unwrap();
+                        let documents = store.count_documents().unwrap();
+                        let lines = store.scroll_line_embeddings(None).unwrap().len();
+                        assert_eq!(lines, documents * 10);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+
+        let store = Store::open_shared(&dir).unwrap();
+        assert_eq!(store.count_documents().unwrap(), 12);
+        assert_eq!(store.scroll_line_embeddings(None).unwrap().len(), 120);
+    }
+
+    #[test]
+    fn test_open_fails_for_a_workspace_deleted_while_waiting() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("notes");
+        drop(Store::open(dir.to_str().unwrap()).unwrap());
+
+        let deleting = WorkspaceLock::acquire(&dir, LockMode::Exclusive, Duration::ZERO).unwrap();
+        let waiting = std::thread::spawn({
+            let dir = dir.to_str().unwrap().to_string();
+            move || Store::open(&dir).map(drop)
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        std::fs::remove_dir_all(&dir).unwrap();
+        drop(deleting);
+
+        let error = waiting.join().unwrap().unwrap_err();
+        assert!(error.to_string().contains("deleted or renamed"));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_try_open_shared_neither_waits_nor_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().to_str().unwrap();
+
+        // A workspace without shards is left as it is
+        assert!(Store::try_open_shared(dir).unwrap().is_none());
+        assert!(!temp_dir.path().join(DOCUMENTS_SHARD_DIR).exists());
+        assert!(!temp_dir.path().join(LINE_EMBEDDINGS_SHARD_DIR).exists());
+
+        let (docs, _embeddings) = create_test_docs();
+        let writer = Store::open(dir).unwrap();
+        writer.upsert_document_metadata(&docs).unwrap();
+        assert!(Store::try_open_shared(dir).unwrap().is_none());
+        drop(writer);
+
+        let reader = Store::try_open_shared(dir).unwrap().unwrap();
+        assert_eq!(reader.count_documents().unwrap(), docs.len());
+        assert!(Store::try_open_shared(dir).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_interrupted_update_marks_documents_for_embedding() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().to_str().unwrap();
+        let (docs, _embeddings) = create_test_docs();
+        let store = Store::open(dir).unwrap();
+        store.upsert_document_metadata(&docs).unwrap();
+        store
+            .upsert_line_embeddings(&[line("/test/doc1.txt", 0), line("/test/doc2.txt", 0)])
+            .unwrap();
+        let line_paths = |store: &Store| -> Vec<String> {
+            let mut paths: Vec<String> = store
+                .scroll_line_embeddings(None)
+                .unwrap()
+                .into_iter()
+                .map(|(_, l)| l.path)
+                .collect();
+            paths.sort();
+            paths.dedup();
+            paths
+        };
+        let marked = |store: &Store| -> Vec<String> {
+            let mut paths: Vec<String> = store
+                .get_existing_docs(&store.get_all_document_paths().unwrap())
+                .unwrap()
+                .into_values()
+                .filter(|meta| meta._version != CURRENT_EMBEDDING_VERSION)
+                .inspect(|meta| assert!(meta.content_hash.is_none()))
+                .map(|meta| meta.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        // An update that fails after writing lines leaves its documents in the
+        // workspace, marked to be embedded again
+        let doc2 = vec!["/test/doc2.txt".to_string()];
+        let result = store.update_documents(&doc2, |store| {
+            store.upsert_line_embeddings(&[line("/test/doc2.txt", 1)])?;
+            Err::<(), _>(anyhow!("embedding failed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(store.count_documents().unwrap(), 3);
+        assert_eq!(marked(&store), doc2);
+        assert_eq!(line_paths(&store), vec!["/test/doc1.txt", "/test/doc2.txt"]);
+        assert!(!temp_dir.path().join(JOURNAL_FILE).exists());
+
+        // A process that died during an update leaves its journal for the next
+        // open. Lines of a document that didn't get its metadata yet are dropped.
+        store
+            .upsert_line_embeddings(&[line("/test/new.txt", 0)])
+            .unwrap();
+        drop(store);
+        std::fs::write(
+            temp_dir.path().join(JOURNAL_FILE),
+            r#"{"paths": ["/test/doc1.txt", "/test/new.txt"]}"#,
+        )
+        .unwrap();
+        let store = Store::open_shared(dir).unwrap();
+        assert_eq!(line_paths(&store), vec!["/test/doc1.txt", "/test/doc2.txt"]);
+        assert_eq!(store.count_documents().unwrap(), 3);
+        assert_eq!(marked(&store), vec!["/test/doc1.txt", "/test/doc2.txt"]);
+        assert!(!temp_dir.path().join(JOURNAL_FILE).exists());
+
+        // Marked documents can still be deleted
+        drop(store);
+        let store = Store::open(dir).unwrap();
+        store.delete_documents(&doc2).unwrap();
+        assert_eq!(store.count_documents().unwrap(), 2);
+        assert_eq!(line_paths(&store), vec!["/test/doc1.txt"]);
+
+        // Finished updates don't leave a journal behind
+        store
+            .update_documents(&doc2, |store| {
+                store.upsert_line_embeddings(&[line("/test/doc2.txt", 0)])
+            })
+            .unwrap();
+        assert!(!temp_dir.path().join(JOURNAL_FILE).exists());
+    }
+
+    #[test]
+    fn test_remove_orphaned_line_embeddings() {
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let file_paths = create_test_files(&temp_dir);
+        let store = Store::open(temp_dir.path().to_str().unwrap()).unwrap();
+
+        // test1.txt has two lines, but was stored with five, as if it had shrunk
+        let states = store
+            .analyze_document_states(&file_paths[..1], None)
+            .unwrap();
+        let DocumentState::New(doc_info) = &states[0] else {
+            panic!("Expected New document state");
+        };
+        store
+            .upsert_document_metadata(&[doc_info.meta.clone()])
+            .unwrap();
+        let stored: Vec<LineEmbedding> = (0..5).map(|i| line(&file_paths[0], i)).collect();
+        store.upsert_line_embeddings(&stored).unwrap();
+
+        // test2.txt has line embeddings but no metadata
+        store
+            .upsert_line_embeddings(&[line(&file_paths[1], 0)])
+            .unwrap();
+
+        assert_eq!(store.remove_orphaned_line_embeddings().unwrap(), 4);
+        let remaining: Vec<i32> = store
+            .scroll_line_embeddings(None)
+            .unwrap()
+            .into_iter()
+            .map(|(_, l)| l.line_number)
+            .collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.iter().all(|&line_number| line_number < 2));
+
+        // Nothing is left to remove
+        assert_eq!(store.remove_orphaned_line_embeddings().unwrap(), 0);
+
+        drop(store);
+        drop(temp_dir);
+    }
+
     #[test]
     fn test_doc_meta_id_generation() {
         let doc1 = DocMeta {
@@ -1005,12 +2831,16 @@ mod tests {
             size_bytes: 100,
             mtime: 1000,
             _version: CURRENT_EMBEDDING_VERSION,
+            content_hash: None,
+            embedding: vec![],
         };
         let doc2 = DocMeta {
             path: "test2.txt".to_string(),
             size_bytes: 100,
             mtime: 1000,
             _version: CURRENT_EMBEDDING_VERSION,
+            content_hash: None,
+            embedding: vec![],
         };
 
         let id1 = doc1.id();
@@ -1020,6 +2850,24 @@ mod tests {
         assert_ne!(id1, id2);
     }
 
+    #[test]
+    fn test_line_embedding_span_ids() {
+        let span = |start_column: Option<usize>| LineEmbedding {
+            path: "long.json".to_string(),
+            line_number: 4,
+            start_column,
+            end_column: start_column.map(|start| start + 512),
+            text_hash: None,
+            text: None,
+            embedding: vec![],
+        };
+
+        // The first span replaces the whole-line point, later spans get their own
+        assert_eq!(span(None).id(), span(Some(0)).id());
+        assert_ne!(span(Some(0)).id(), span(Some(512)).id());
+        assert_ne!(span(Some(512)).id(), span(Some(1024)).id());
+    }
+
     // Helper to create test files for analyze_document_states tests
     fn create_test_files(temp_dir: &tempfile::TempDir) -> Vec<String> {
         use std::fs;
@@ -1049,7 +2897,7 @@ mod tests {
         // Create empty store
         let store = Store::open(temp_dir.path().to_str().unwrap()).unwrap();
 
-        let states = store.analyze_document_states(&file_paths).unwrap();
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
 
         assert_eq!(states.len(), 3);
 
@@ -1095,13 +2943,15 @@ mod tests {
                     .unwrap()
                     .as_secs() as i64,
                 _version: CURRENT_EMBEDDING_VERSION,
+                content_hash: None,
+                embedding: vec![],
             };
             docs.push(doc_meta);
         }
         store.upsert_document_metadata(&docs).unwrap();
 
         // Analyze states - should all be unchanged
-        let states = store.analyze_document_states(&file_paths).unwrap();
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
 
         assert_eq!(states.len(), 3);
 
@@ -1134,13 +2984,15 @@ mod tests {
                 size_bytes: 10, // Different from actual size
                 mtime: 1000,    // Old timestamp
                 _version: 1,    // simulate old version
+                content_hash: None,
+                embedding: vec![],
             };
             docs.push(doc_meta);
         }
         store.upsert_document_metadata(&docs).unwrap();
 
         // Analyze states - should all be changed
-        let states = store.analyze_document_states(&file_paths).unwrap();
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
 
         assert_eq!(states.len(), 3);
 
@@ -1180,11 +3032,13 @@ mod tests {
                 .unwrap()
                 .as_secs() as i64,
             _version: CURRENT_EMBEDDING_VERSION,
+            content_hash: None,
+            embedding: vec![],
         };
         store.upsert_document_metadata(&[doc_meta]).unwrap();
 
         // Analyze states
-        let states = store.analyze_document_states(&file_paths).unwrap();
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
 
         assert_eq!(states.len(), 3);
 
@@ -1213,6 +3067,78 @@ mod tests {
         drop(temp_dir);
     }
 
+    #[test]
+    fn test_analyze_document_states_touched() {
+        use std::fs;
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let file_paths = create_test_files(&temp_dir);
+        let store = Store::open(temp_dir.path().to_str().unwrap()).unwrap();
+
+        // Store every file as it is, but with an older modification time
+        let metas: Vec<DocMeta> = store
+            .analyze_document_states(&file_paths, None)
+            .unwrap()
+            .into_iter()
+            .map(|state| match state {
+                DocumentState::New(doc_info) => DocMeta {
+                    mtime: 1000,
+                    ..doc_info.meta
+                },
+                _ => panic!("Expected New document state"),
+            })
+            .collect();
+        assert!(metas.iter().all(|meta| meta.content_hash.is_some()));
+        store.upsert_document_metadata(&metas).unwrap();
+
+        // Same size, different content
+        fs::write(&file_paths[2], "This is test file 3\nWith more CONTENT").unwrap();
+
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
+        assert!(matches!(&states[0], DocumentState::Touched(meta) if meta.mtime != 1000));
+        assert!(matches!(&states[1], DocumentState::Touched(_)));
+        assert!(matches!(&states[2], DocumentState::Changed(_)));
+
+        drop(store);
+        drop(temp_dir);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            content_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_find_documents_by_content_hash() {
+        let (store, _temp_dir) = create_test_store();
+        let (docs, _) = create_test_docs();
+
+        let hashes = [
+            content_hash("same"),
+            content_hash("same"),
+            content_hash("other"),
+        ];
+        let docs: Vec<DocMeta> = docs
+            .into_iter()
+            .zip(hashes)
+            .map(|(doc, hash)| DocMeta {
+                content_hash: Some(hash),
+                ..doc
+            })
+            .collect();
+        store.upsert_document_metadata(&docs).unwrap();
+
+        let found = store
+            .find_documents_by_content_hash(&[content_hash("same"), content_hash("missing")])
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[&content_hash("same")], "/test/doc1.txt");
+    }
+
     #[test]
     fn test_analyze_document_states_version_mismatch() {
         use std::fs;
@@ -1237,13 +3163,15 @@ mod tests {
                     .duration_since(UNIX_EPOCH)
                     .unwrap()
                     .as_secs() as i64,
-                _version: 1, // older version than CURRENT_EMBEDDING_VERSION (2)
+                _version: 1, // older version than CURRENT_EMBEDDING_VERSION (3)
+                content_hash: None,
+                embedding: vec![],
             };
             old_docs.push(doc_meta);
         }
         store.upsert_document_metadata(&old_docs).unwrap();
 
-        let states = store.analyze_document_states(&file_paths).unwrap();
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
         assert_eq!(states.len(), 3);
         for state in &states {
             match state {
@@ -1270,7 +3198,7 @@ mod tests {
 
         let store = Store::open(temp_dir.path().to_str().unwrap()).unwrap();
 
-        let states = store.analyze_document_states(&file_paths).unwrap();
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
 
         // Should only have states for existing files
         assert_eq!(states.len(), 3);
@@ -1292,6 +3220,8 @@ mod tests {
             size_bytes: 1200_u64,
             mtime: 1234567890,
             _version: CURRENT_EMBEDDING_VERSION,
+            content_hash: None,
+            embedding: vec![],
         };
         let doc_meta_json =
             serde_json::to_value(doc_meta).expect("Should be able to conver DocMeta to JSON Value");
@@ -1316,6 +3246,10 @@ mod tests {
         let line_embedding = LineEmbedding {
             path: "hello.txt".to_string(),
             line_number: 12,
+            start_column: None,
+            end_column: None,
+            text_hash: None,
+            text: None,
             embedding: vec![0.1, 0.3, 0.4, 0.5],
         };
         let doc_meta_json = serde_json::to_value(line_embedding)
@@ -1371,4 +3305,36 @@ mod tests {
         assert_eq!(line_embedding.line_number, 12_i32);
         assert!(line_embedding.embedding.is_empty());
     }
+
+    #[test]
+    fn test_analyze_document_states_skips_binary_and_large_files() {
+        use tempfile::TempDir;
+
+        let temp_dir = TempDir::new().unwrap();
+        let mut file_paths = create_test_files(&temp_dir);
+
+        let binary_path = temp_dir.path().join("image.bin");
+        std::fs::write(&binary_path, [0x89, b'P', b'N', b'G', 0x00, 0x00]).unwrap();
+        file_paths.push(binary_path.to_string_lossy().to_string());
+
+        let store = Store::open(temp_dir.path().to_str().unwrap()).unwrap();
+
+        let states = store.analyze_document_states(&file_paths, None).unwrap();
+        assert_eq!(states.len(), 4);
+        assert!(matches!(
+            &states[3],
+            DocumentState::Skipped(skipped) if skipped.path == file_paths[3]
+        ));
+
+        // With a 1 byte limit every file is too large
+        let states = store.analyze_document_states(&file_paths, Some(1)).unwrap();
+        assert!(
+            states
+                .iter()
+                .all(|state| matches!(state, DocumentState::Skipped(_)))
+        );
+
+        drop(store);
+        drop(temp_dir);
+    }
 }